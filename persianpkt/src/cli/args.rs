@@ -15,6 +15,15 @@ pub struct Args {
 
     #[clap(long, global = true, help = "Use a specific config file")]
     pub config: Option<String>,
+
+    #[clap(long, global = true, help = "Operate on an alternate filesystem root")]
+    pub root: Option<String>,
+
+    #[clap(long, global = true, conflicts_with = "root", help = "Install for the current user only, below ~/.local/share/persianpkt")]
+    pub user: bool,
+
+    #[clap(long, global = true, help = "Seconds to wait for another pkt process to finish")]
+    pub lock_timeout: Option<u64>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -26,20 +35,71 @@ pub enum Commands {
 
         #[clap(short, long, help = "Don't ask for confirmation")]
         yes: bool,
+
+        #[clap(long, help = "Replace modified config files with the package version, keeping yours as .pkt-old")]
+        confnew: bool,
+
+        #[clap(long, help = "Keep other versions installed and link the active one into the shared prefix")]
+        side_by_side: bool,
+
+        #[clap(long, help = "Don't install the packages the new packages recommend")]
+        no_install_recommends: bool,
+
+        #[clap(long, help = "Also install the packages the new packages suggest")]
+        install_suggests: bool,
     },
 
     #[clap(about = "Remove packages")]
     Remove {
-        #[clap(help = "Packages to remove")]
+        #[clap(help = "Packages to remove, name@version for a side-by-side version")]
         packages: Vec<String>,
 
         #[clap(short, long, help = "Don't ask for confirmation")]
         yes: bool,
 
-        #[clap(long, help = "Remove dependencies that are no longer needed")]
+        #[clap(long, help = "Also remove config files and dependencies that are no longer needed")]
         purge: bool,
     },
 
+    #[clap(about = "Remove automatically installed packages that are no longer needed")]
+    Autoremove {
+        #[clap(short, long, help = "Don't ask for confirmation")]
+        yes: bool,
+
+        #[clap(long, help = "Also remove their config files")]
+        purge: bool,
+    },
+
+    #[clap(about = "Mark installed packages as automatically or manually installed")]
+    Mark {
+        #[clap(subcommand)]
+        command: MarkCommands,
+    },
+
+    #[clap(name = "showmanual", about = "List manually installed packages")]
+    ShowManual,
+
+    #[clap(about = "Show which installed packages need a package")]
+    Why {
+        #[clap(help = "Package name")]
+        package: String,
+    },
+
+    #[clap(name = "why-not", about = "Show what keeps a package from being installed")]
+    WhyNot {
+        #[clap(help = "Package name, optionally as name@version")]
+        package: String,
+    },
+
+    #[clap(about = "Choose the active side-by-side version of a package")]
+    Switch {
+        #[clap(help = "Package name")]
+        package: String,
+
+        #[clap(help = "Version to activate")]
+        version: String,
+    },
+
     #[clap(about = "Update package lists")]
     Update,
 
@@ -47,6 +107,9 @@ pub enum Commands {
     Upgrade {
         #[clap(short, long, help = "Don't ask for confirmation")]
         yes: bool,
+
+        #[clap(long, help = "Allow installing and removing packages to satisfy changed dependencies")]
+        full: bool,
     },
 
     #[clap(about = "Search for packages")]
@@ -64,6 +127,33 @@ pub enum Commands {
     #[clap(about = "List installed packages")]
     List,
 
+    #[clap(about = "Show the configuration files of an installed package")]
+    Conffiles {
+        #[clap(help = "Package name")]
+        package: String,
+
+        #[clap(long, help = "Show differences to pending .pkt-new and .pkt-old files")]
+        diff: bool,
+    },
+
+    #[clap(about = "Check installed files against the package database")]
+    Verify {
+        #[clap(help = "Packages to verify, all installed packages if none are given")]
+        packages: Vec<String>,
+    },
+
+    #[clap(about = "Install files of packages to another path")]
+    Divert {
+        #[clap(subcommand)]
+        command: DivertCommands,
+    },
+
+    #[clap(about = "Choose between packages providing the same command")]
+    Alternatives {
+        #[clap(subcommand)]
+        command: AlternativesCommands,
+    },
+
     #[clap(about = "Manage repositories")]
     Repo {
         #[clap(subcommand)]
@@ -77,6 +167,21 @@ pub enum Commands {
     },
 }
 
+#[derive(Debug, Subcommand)]
+pub enum MarkCommands {
+    #[clap(about = "Let autoremove remove the packages once nothing needs them")]
+    Auto {
+        #[clap(required = true, help = "Package names")]
+        packages: Vec<String>,
+    },
+
+    #[clap(about = "Keep the packages until they are removed explicitly")]
+    Manual {
+        #[clap(required = true, help = "Package names")]
+        packages: Vec<String>,
+    },
+}
+
 #[derive(Debug, Subcommand)]
 pub enum RepoCommands {
     #[clap(about = "Add a repository")]
@@ -110,6 +215,98 @@ pub enum RepoCommands {
     },
 }
 
+#[derive(Debug, Subcommand)]
+pub enum DivertCommands {
+    #[clap(about = "Add a diversion")]
+    Add {
+        #[clap(help = "Path to divert")]
+        path: String,
+
+        #[clap(long, help = "Where packages install the file instead, defaults to <path>.distrib")]
+        to: Option<String>,
+
+        #[clap(long, help = "Package that still installs the original path, the diversion applies to every package if not given")]
+        package: Option<String>,
+
+        #[clap(long, help = "Also move an existing file to the diverted location")]
+        rename: bool,
+    },
+
+    #[clap(about = "Remove a diversion")]
+    Remove {
+        #[clap(help = "Diverted path")]
+        path: String,
+
+        #[clap(long, help = "Also move the diverted file back to its original path")]
+        rename: bool,
+    },
+
+    #[clap(about = "List all diversions")]
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AlternativesCommands {
+    #[clap(about = "List alternatives and their providers")]
+    List {
+        #[clap(help = "Alternative name, all alternatives if not given")]
+        name: Option<String>,
+    },
+
+    #[clap(about = "Select a provider manually")]
+    Set {
+        #[clap(help = "Alternative name")]
+        name: String,
+
+        #[clap(help = "Path of the provider")]
+        path: String,
+    },
+
+    #[clap(about = "Let the highest priority provider be used")]
+    Auto {
+        #[clap(help = "Alternative name")]
+        name: String,
+    },
+
+    #[clap(about = "Register a provider, e.g. from a maintainer script")]
+    Install {
+        #[clap(help = "Public link, e.g. /usr/bin/editor")]
+        link: String,
+
+        #[clap(help = "Alternative name")]
+        name: String,
+
+        #[clap(help = "Path of the provider")]
+        path: String,
+
+        #[clap(help = "Priority, the highest wins in auto mode")]
+        priority: i32,
+
+        #[clap(long, num_args = 3, value_names = ["LINK", "NAME", "PATH"], help = "Slave link that follows the provider")]
+        slave: Vec<String>,
+    },
+
+    #[clap(about = "Unregister a provider")]
+    Remove {
+        #[clap(help = "Alternative name")]
+        name: String,
+
+        #[clap(help = "Path of the provider")]
+        path: String,
+    },
+}
+
 pub fn parse_args() -> Args {
     Args::parse()
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_mode_conflicts_with_a_root() {
+        assert!(Args::try_parse_from(["pkt", "--user", "--root", "/srv/root", "list"]).is_err());
+        assert!(Args::try_parse_from(["pkt", "list", "--root", "/srv/root", "--user"]).is_err());
+        assert!(Args::try_parse_from(["pkt", "--user", "list"]).unwrap().user);
+    }
+}
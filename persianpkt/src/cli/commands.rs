@@ -1,44 +1,110 @@
-use crate::cli::args::{Args, Commands, RepoCommands};
-use crate::repository::{Repository, RepositoryManager};
-use crate::package::PackageManager;
+use crate::cli::args::{AlternativesCommands, Args, Commands, DivertCommands, MarkCommands, RepoCommands};
+use crate::core::{Cause, ProviderChoice};
+use crate::repository::{PackageIndex, Repository, RepositoryManager};
+use crate::package::{
+    plan_upgrade, AlternativeMode, AlternativeSpec, Conffile, ConffileStatus, Diversion, InstallPlan, InstallRequest,
+    PackageDependency, PackageManager, PackageState, WeakKind,
+};
 use crate::config::{Config, ConfigPaths};
+use crate::utils::{LockMode, OperationLock, LOCKED_ENV};
 use anyhow::{Result, anyhow};
 use colored::Colorize;
 use std::io::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
 pub fn execute_command(args: Args) -> Result<()> {
     if args.verbose {
         println!("Verbose mode enabled");
     }
+    
+    let mut config_paths = ConfigPaths::new();
+    let config = Config::load(&config_paths.config_file())?;
+    
+    // Rebase installed state, cache and extraction target onto an alternate
+    // root. --user conflicts with --root, and the root_dir of the config file
+    // only applies to system-wide installs.
+    if args.user {
+        config_paths = config_paths.with_user_mode();
+        config_paths.ensure_dirs_exist()?;
+    } else if let Some(root_dir) = args.root.map(PathBuf::from).or(config.root_dir) {
+        fs::create_dir_all(&root_dir)?;
+        config_paths = config_paths.with_root_dir(root_dir.canonicalize()?);
+        config_paths.ensure_dirs_exist()?;
+    }
+    
+    // Held until the command returns, so concurrent runs cannot interleave changes
+    let lock_timeout = Duration::from_secs(args.lock_timeout.unwrap_or(config.lock_timeout));
+    // Maintainer scripts managing alternatives run while their pkt holds the lock
+    let nested = std::env::var_os(LOCKED_ENV).is_some() && matches!(args.command, Commands::Alternatives { .. });
+    let _lock = if nested {
+        None
+    } else {
+        Some(OperationLock::acquire(&config_paths.lock_file(), lock_mode(&args.command), lock_timeout)?)
+    };
 
     match args.command {
-        Commands::Install { packages, yes } => install_packages(packages, yes),
-        Commands::Remove { packages, yes, purge } => remove_packages(packages, yes, purge),
-        Commands::Update => update_package_lists(),
-        Commands::Upgrade { yes } => upgrade_packages(yes),
-        Commands::Search { query } => search_packages(query),
-        Commands::Show { package } => show_package_info(package),
-        Commands::List => list_installed_packages(),
+        Commands::Install { packages, yes, confnew, side_by_side, no_install_recommends, install_suggests } => {
+            install_packages(&config_paths, packages, yes, confnew, side_by_side, !no_install_recommends, install_suggests)
+        }
+        Commands::Remove { packages, yes, purge } => remove_packages(&config_paths, packages, yes, purge),
+        Commands::Autoremove { yes, purge } => autoremove_packages(&config_paths, yes, purge),
+        Commands::Mark { command } => mark_packages(&config_paths, command),
+        Commands::ShowManual => show_manual_packages(&config_paths),
+        Commands::Why { package } => explain_why(&config_paths, package),
+        Commands::WhyNot { package } => explain_why_not(&config_paths, package),
+        Commands::Update => update_package_lists(&config_paths),
+        Commands::Upgrade { yes, full } => upgrade_packages(&config_paths, yes, full),
+        Commands::Search { query } => search_packages(&config_paths, query),
+        Commands::Show { package } => show_package_info(&config_paths, package),
+        Commands::Switch { package, version } => switch_version(&config_paths, package, version),
+        Commands::List => list_installed_packages(&config_paths),
+        Commands::Conffiles { package, diff } => show_conffiles(&config_paths, package, diff),
+        Commands::Verify { packages } => verify_packages(&config_paths, packages),
+        Commands::Divert { command } => handle_divert_command(&config_paths, command),
+        Commands::Alternatives { command } => handle_alternatives_command(&config_paths, command),
         Commands::Repo { command } => handle_repo_command(command),
-        Commands::Clean { all } => clean_cache(all),
+        Commands::Clean { all } => clean_cache(&config_paths, all),
     }
 }
 
-fn install_packages(packages: Vec<String>, yes: bool) -> Result<()> {
+/// Read-only commands may run alongside each other, anything that changes
+/// repositories, the cache or installed packages needs the lock to itself.
+fn lock_mode(command: &Commands) -> LockMode {
+    match command {
+        Commands::Search { .. }
+        | Commands::Show { .. }
+        | Commands::List
+        | Commands::ShowManual
+        | Commands::Why { .. }
+        | Commands::WhyNot { .. }
+        | Commands::Conffiles { .. }
+        | Commands::Verify { .. }
+        | Commands::Divert { command: DivertCommands::List }
+        | Commands::Alternatives { command: AlternativesCommands::List { .. } }
+        | Commands::Repo { command: RepoCommands::List } => LockMode::Shared,
+        _ => LockMode::Exclusive,
+    }
+}
+
+fn install_packages(
+    config_paths: &ConfigPaths,
+    packages: Vec<String>,
+    yes: bool,
+    confnew: bool,
+    side_by_side: bool,
+    install_recommends: bool,
+    install_suggests: bool,
+) -> Result<()> {
     if packages.is_empty() {
         return Err(anyhow!("No packages specified for installation"));
     }
     
     println!("{} Installing packages: {}", "==>".green().bold(), packages.join(", "));
     
-    if !yes && !confirm_action() {
-        println!("Operation cancelled");
-        return Ok(());
-    }
-    
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
     let config_file = config_paths.config_file();
     let config = Config::load(&config_file)?;
     
@@ -47,46 +113,239 @@ fn install_packages(packages: Vec<String>, yes: bool) -> Result<()> {
     repo_manager.load_repositories()?;
     let repositories = repo_manager.list_repositories();
     
-    if repositories.is_empty() {
+    // Package names, name@version, package file paths or URLs
+    let requests: Vec<InstallRequest> = packages.iter()
+        .map(|package| InstallRequest::parse(package))
+        .collect();
+    
+    // Package files can be installed without a repository as long as their dependencies are met
+    let from_repository = requests.iter().any(|r| matches!(r, InstallRequest::Repository { .. }));
+    if repositories.is_empty() && from_repository {
         return Err(anyhow!("No repositories configured. Add a repository with 'pkt repo add'"));
     }
     
     // Create package manager
-    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories);
+    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories.to_vec())
+        .with_index(PackageIndex::load(&config_paths.lists_dir(), repositories)?)
+        .with_root_dir(config_paths.root_dir())
+        .with_triggers_dir(config_paths.triggers_dir())
+        .with_prefix_dir(config_paths.prefix_dir())
+        .with_held_packages(config.held_packages.clone())
+        .with_preferred_providers(config.preferred_providers.clone())
+        .with_ask_providers(!yes)
+        .with_install_recommends(config.install_recommends && install_recommends)
+        .with_install_suggests(config.install_suggests || install_suggests)
+        .with_replace_conffiles(confnew);
+    let mut package_manager = for_user(package_manager, config_paths);
     
     // Create async runtime
     let rt = Runtime::new()?;
     
-    // Install each package
-    for package_name in packages {
-        println!("Installing package: {}", package_name);
+    if side_by_side {
+        let plan = rt.block_on(package_manager.plan_side_by_side(&requests))?;
         
-        // Parse version if specified (package_name@version)
-        let (name, version) = if package_name.contains('@') {
-            let parts: Vec<&str> = package_name.split('@').collect();
-            (parts[0].to_string(), Some(parts[1].to_string()))
-        } else {
-            (package_name, None)
-        };
+        println!("The following packages will be installed side by side:");
+        for package in &plan.solution.installs {
+            println!("  {} (v{})", package.name.bold(), package.version);
+        }
+        
+        if !yes && !confirm_action() {
+            println!("Operation cancelled");
+            return Ok(());
+        }
         
-        match rt.block_on(package_manager.install_package(&name, version.as_deref())) {
-            Ok(_) => println!("{} Successfully installed {}", "✓".green().bold(), name),
-            Err(e) => eprintln!("{} Failed to install {}: {}", "✗".red().bold(), name, e),
+        for package in rt.block_on(package_manager.install_side_by_side(&plan))? {
+            println!("{} Installed {} ({}) side by side", "✓".green().bold(), package.name, package.version);
         }
+        
+        let versions = package_manager.versions()?;
+        println!("Active versions are linked into {}", versions.prefix_dir().display());
+        return Ok(());
     }
     
+    // Plan the install, asking which provider to use for a virtual package
+    // when there is a choice
+    let plan = loop {
+        match rt.block_on(package_manager.plan_install(&requests)) {
+            Err(e) if e.is::<ProviderChoice>() => {
+                let choice = e.downcast::<ProviderChoice>()?;
+                let provider = choose_provider(&choice)?;
+                package_manager.prefer_provider(choice.virtual_name, provider);
+            }
+            result => break result?,
+        }
+    };
+    
+    show_install_plan(&plan);
+    
+    if !yes && !confirm_action() {
+        println!("Operation cancelled");
+        return Ok(());
+    }
+    
+    // Install all packages in one transaction
+    let installed = rt.block_on(package_manager.install_packages(&plan))
+        .map_err(|e| anyhow!("Installation failed and was rolled back: {}", e))?;
+        
+    for package in installed {
+        println!("{} Successfully installed {} ({})", "✓".green().bold(), package.name, package.version);
+    }
+    
+    show_user_path_hint(config_paths);
     Ok(())
 }
 
-fn remove_packages(packages: Vec<String>, yes: bool, purge: bool) -> Result<()> {
+/// Prints what an install changes besides installing the requested packages.
+fn show_install_plan(plan: &InstallPlan) {
+    let solution = &plan.solution;
+    
+    for (virtual_name, provider) in &plan.provided {
+        println!("{} is already provided by {}", virtual_name, provider);
+    }
+    for (virtual_name, provider) in &plan.providers {
+        println!("Installing {} to provide {}", provider, virtual_name);
+    }
+    
+    // New packages other than the requested ones, by what pulls them in
+    let pulled_in = |kind: Option<WeakKind>| solution.installs.iter()
+        .filter(|p| !plan.requests.contains_key(&p.name) && !plan.installed.contains_key(&p.name))
+        .filter(|p| match solution.causes.get(&p.name) {
+            Some(Cause::Weak { kind: weak, .. }) => Some(*weak) == kind,
+            _ => kind.is_none(),
+        })
+        .map(|p| format!("{} ({})", p.name, p.version))
+        .collect::<Vec<_>>();
+    for (label, kind) in [("dependencies", None), ("recommended packages", Some(WeakKind::Recommends)), ("suggested packages", Some(WeakKind::Suggests))] {
+        let packages = pulled_in(kind);
+        if !packages.is_empty() {
+            println!("Also installing {}: {}", label, packages.join(", "));
+        }
+    }
+    if !solution.recommended.is_empty() {
+        println!("Recommended packages: {}", solution.recommended.join(", "));
+    }
+    if !solution.suggested.is_empty() {
+        println!("Suggested packages: {}", solution.suggested.join(", "));
+    }
+    
+    let reason = |name: &str| solution.reasons.get(name).map(|r| r.as_str()).unwrap_or_default();
+    
+    let changes: Vec<String> = solution.installs.iter()
+        .filter(|p| !plan.requests.contains_key(&p.name))
+        .filter_map(|p| plan.installed.get(&p.name).map(|i| format!("{} ({} -> {}): {}", p.name, i.version, p.version, reason(&p.name))))
+        .collect();
+    if !changes.is_empty() {
+        println!("Also changing installed packages:\n  {}", changes.join("\n  "));
+    }
+    
+    if !solution.removals.is_empty() {
+        let removals: Vec<String> = solution.removals.iter()
+            .map(|p| format!("{} ({}): {}", p.name, p.version, reason(&p.name)))
+            .collect();
+        println!("Removing replaced packages:\n  {}", removals.join("\n  "));
+    }
+}
+
+fn remove_packages(config_paths: &ConfigPaths, packages: Vec<String>, yes: bool, purge: bool) -> Result<()> {
     if packages.is_empty() {
         return Err(anyhow!("No packages specified for removal"));
     }
     
     println!("{} Removing packages: {}", "==>".red().bold(), packages.join(", "));
     
+    let config = Config::load(&config_paths.config_file())?;
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir())
+        .with_triggers_dir(config_paths.triggers_dir())
+        .with_prefix_dir(config_paths.prefix_dir())
+        .with_held_packages(config.held_packages.clone());
+    let package_manager = for_user(package_manager, config_paths);
+    
+    // name@version naming a side-by-side version removes just that version
+    let mut versions = package_manager.versions()?;
+    let mut side_by_side = Vec::new();
+    let mut installed = Vec::new();
+    for package in packages {
+        match package.split_once('@') {
+            Some((name, version)) if versions.get(name).map(|p| p.versions.contains_key(version)).unwrap_or(false) => {
+                side_by_side.push((name.to_string(), version.to_string()));
+            }
+            _ => installed.push(package),
+        }
+    }
+    let packages = installed;
+    
+    if !packages.is_empty() {
+        let plan = package_manager.plan_removal(&packages, purge)?;
+        let unneeded: Vec<&str> = plan.iter()
+            .filter(|p| !packages.contains(&p.name))
+            .map(|p| p.name.as_str())
+            .collect();
+        
+        if purge {
+            println!("Configuration files will also be removed");
+        }
+        
+        if !unneeded.is_empty() {
+            println!("Unused dependencies will also be removed: {}", unneeded.join(", "));
+        }
+    }
+    
+    if !yes && !confirm_action() {
+        println!("Operation cancelled");
+        return Ok(());
+    }
+    
+    for (name, version) in side_by_side {
+        versions.remove(&name, &version)?;
+        println!("{} Removed side-by-side {} ({})", "✓".green().bold(), name, version);
+    }
+    
+    if packages.is_empty() {
+        return Ok(());
+    }
+    
+    let removed = package_manager.remove_packages(&packages, purge)?;
+    
+    for package in removed {
+        if purge {
+            println!("{} Purged {} ({})", "✓".green().bold(), package.name, package.version);
+        } else {
+            println!("{} Removed {} ({})", "✓".green().bold(), package.name, package.version);
+        }
+    }
+    
+    let unneeded = package_manager.unneeded_packages()?;
+    if !unneeded.is_empty() {
+        println!("{} automatically installed package(s) are no longer needed, use 'pkt autoremove' to remove them", unneeded.len());
+    }
+    
+    Ok(())
+}
+
+fn autoremove_packages(config_paths: &ConfigPaths, yes: bool, purge: bool) -> Result<()> {
+    println!("{} Removing packages that are no longer needed", "==>".red().bold());
+    
+    let config = Config::load(&config_paths.config_file())?;
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir())
+        .with_triggers_dir(config_paths.triggers_dir())
+        .with_prefix_dir(config_paths.prefix_dir())
+        .with_held_packages(config.held_packages.clone());
+    let package_manager = for_user(package_manager, config_paths);
+    
+    let unneeded = package_manager.unneeded_packages()?;
+    if unneeded.is_empty() {
+        println!("No packages to remove");
+        return Ok(());
+    }
+    
+    println!("The following packages will be {}:", "REMOVED".red().bold());
+    for package in &unneeded {
+        println!("  {} (v{})", package.name.bold(), package.version);
+    }
     if purge {
-        println!("Unused dependencies will also be removed");
+        println!("Configuration files will also be removed");
     }
     
     if !yes && !confirm_action() {
@@ -94,16 +353,146 @@ fn remove_packages(packages: Vec<String>, yes: bool, purge: bool) -> Result<()>
         return Ok(());
     }
     
-    println!("Package removal not yet implemented");
+    for package in package_manager.autoremove(purge)? {
+        if purge {
+            println!("{} Purged {} ({})", "✓".green().bold(), package.name, package.version);
+        } else {
+            println!("{} Removed {} ({})", "✓".green().bold(), package.name, package.version);
+        }
+    }
     
     Ok(())
 }
 
-fn update_package_lists() -> Result<()> {
-    println!("{} Updating package lists", "==>".blue().bold());
+fn mark_packages(config_paths: &ConfigPaths, command: MarkCommands) -> Result<()> {
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new());
+    
+    let (packages, auto_installed) = match command {
+        MarkCommands::Auto { packages } => (packages, true),
+        MarkCommands::Manual { packages } => (packages, false),
+    };
+    package_manager.mark_packages(&packages, auto_installed)?;
+    
+    for package in &packages {
+        let mark = if auto_installed { "automatically" } else { "manually" };
+        println!("{} {} set to {} installed", "✓".green().bold(), package, mark);
+    }
     
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
+    Ok(())
+}
+
+fn show_manual_packages(config_paths: &ConfigPaths) -> Result<()> {
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new());
+    
+    for package in package_manager.installed_packages()? {
+        if package.is_present() && !package.auto_installed {
+            println!("{}", package.name);
+        }
+    }
+    
+    Ok(())
+}
+
+fn explain_why(config_paths: &ConfigPaths, package: String) -> Result<()> {
+    println!("{} Why {} is installed", "==>".blue().bold(), package);
+    
+    let config = Config::load(&config_paths.config_file())?;
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_held_packages(config.held_packages.clone());
+    
+    let installed = package_manager.installed_packages()?;
+    let target = installed.iter()
+        .find(|p| p.name == package && p.is_present())
+        .ok_or_else(|| anyhow!("Package '{}' is not installed", package))?;
+    let reasons = package_manager.install_reasons()?;
+    
+    // Walk up from the package to the manually installed or held one that needs it
+    let mut chain = Vec::new();
+    let mut current = target.name.clone();
+    while let Some(Some((parent, reason))) = reasons.get(&current) {
+        chain.push(reason.clone());
+        current = parent.clone();
+    }
+    
+    let root = installed.iter().find(|p| p.name == current).unwrap_or(target);
+    if !reasons.contains_key(&root.name) {
+        println!("{} ({}) was installed automatically and nothing needs it anymore", root.name.bold(), root.version);
+        println!("Use 'pkt autoremove' to remove it");
+        return Ok(());
+    }
+    
+    let mark = if root.auto_installed { "installed automatically" } else { "manually installed" };
+    if config.held_packages.contains(&root.name) {
+        println!("{} ({}) is {} and held", root.name.bold(), root.version, mark);
+    } else {
+        println!("{} ({}) is {}", root.name.bold(), root.version, mark);
+    }
+    for reason in chain.iter().rev() {
+        println!("  {}", reason);
+    }
+    
+    // Other installed packages that need it directly
+    let parent = reasons.get(&target.name).cloned().flatten().map(|(name, _)| name);
+    let dependents: Vec<&str> = installed.iter()
+        .filter(|p| p.is_present() && p.name != target.name && Some(&p.name) != parent.as_ref())
+        .filter(|p| p.pre_depends.iter().chain(&p.dependencies).chain(&p.recommends)
+            .any(|relation| relation.choices().any(|choice| target.satisfies(choice))))
+        .map(|p| p.name.as_str())
+        .collect();
+    if !dependents.is_empty() {
+        println!("Also needed by: {}", dependents.join(", "));
+    }
+    
+    Ok(())
+}
+
+fn explain_why_not(config_paths: &ConfigPaths, package: String) -> Result<()> {
+    println!("{} Why {} cannot be installed", "==>".blue().bold(), package);
+    
+    // Load repositories
+    let mut repo_manager = RepositoryManager::new(config_paths.repositories_file());
+    repo_manager.load_repositories()?;
+    let repositories = repo_manager.list_repositories();
+    
+    let index = PackageIndex::load(&config_paths.lists_dir(), repositories)?;
+    if index.is_empty() {
+        return Err(anyhow!("No package lists available. Run 'pkt update' first"));
+    }
+    
+    let config = Config::load(&config_paths.config_file())?;
+    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories.to_vec())
+        .with_index(index)
+        .with_held_packages(config.held_packages.clone())
+        .with_preferred_providers(config.preferred_providers.clone());
+    
+    let dependency = match package.split_once('@') {
+        Some((name, version)) => PackageDependency::with_version_req(name.to_string(), format!("= {}", version)),
+        None => {
+            if let Some(installed) = package_manager.installed_package(&package)?.filter(|p| p.is_present()) {
+                println!("{} ({}) is already installed", installed.name.bold(), installed.version);
+                return Ok(());
+            }
+            PackageDependency::new(package.clone())
+        }
+    };
+    
+    match package_manager.installation_blockers(&dependency)? {
+        Some(failures) => {
+            for failure in failures {
+                println!("  {}, but:", failure.requirement);
+                for rejection in &failure.rejections {
+                    println!("    {}", rejection.to_string().yellow());
+                }
+            }
+        }
+        None => println!("Nothing keeps {} from being installed", package),
+    }
+    
+    Ok(())
+}
+
+fn update_package_lists(config_paths: &ConfigPaths) -> Result<()> {
+    println!("{} Updating package lists", "==>".blue().bold());
     
     // Load repositories
     let mut repo_manager = RepositoryManager::new(config_paths.repositories_file());
@@ -120,7 +509,7 @@ fn update_package_lists() -> Result<()> {
     // Update each repository
     for repo in repositories {
         println!("Updating repository '{}'...", repo.name);
-        match rt.block_on(repo.update()) {
+        match rt.block_on(repo.update(&config_paths.lists_dir())) {
             Ok(_) => println!("{} Repository '{}' updated successfully", "✓".green().bold(), repo.name),
             Err(e) => eprintln!("{} Failed to update repository '{}': {}", "✗".red().bold(), repo.name, e),
         }
@@ -129,29 +518,114 @@ fn update_package_lists() -> Result<()> {
     Ok(())
 }
 
-fn upgrade_packages(yes: bool) -> Result<()> {
+fn upgrade_packages(config_paths: &ConfigPaths, yes: bool, full: bool) -> Result<()> {
     println!("{} Upgrading packages", "==>".blue().bold());
     
+    // Load repositories
+    let mut repo_manager = RepositoryManager::new(config_paths.repositories_file());
+    repo_manager.load_repositories()?;
+    let repositories = repo_manager.list_repositories();
+    
+    let index = PackageIndex::load(&config_paths.lists_dir(), repositories)?;
+    if index.is_empty() {
+        return Err(anyhow!("No package lists available. Run 'pkt update' first"));
+    }
+    
+    // Create package manager
+    let config = Config::load(&config_paths.config_file())?;
+    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories.to_vec())
+        .with_index(index)
+        .with_root_dir(config_paths.root_dir())
+        .with_triggers_dir(config_paths.triggers_dir())
+        .with_prefix_dir(config_paths.prefix_dir())
+        .with_held_packages(config.held_packages.clone())
+        .with_preferred_providers(config.preferred_providers.clone());
+    let package_manager = for_user(package_manager, config_paths);
+    
+    let plan = plan_upgrade(
+        &package_manager.database()?,
+        package_manager.index(),
+        full,
+        &config.held_packages,
+        &config.preferred_providers,
+        config.install_recommends,
+        config.install_suggests,
+    )?;
+    
+    if !plan.held_back.is_empty() {
+        println!("The following packages have been kept back:");
+        for (package, reason) in &plan.held_back {
+            println!("  {} (v{}): {}", package.name.bold(), package.version, reason.yellow());
+        }
+    }
+    
+    if plan.is_empty() {
+        if plan.held_back.is_empty() {
+            println!("All packages are up to date");
+        } else if full {
+            println!("No packages can be upgraded");
+        } else {
+            println!("No packages can be upgraded without --full");
+        }
+        return Ok(());
+    }
+    
+    if !plan.upgrades.is_empty() {
+        println!("The following packages will be upgraded:");
+        for (installed, candidate) in &plan.upgrades {
+            println!("  {} (v{} -> v{})", installed.name.bold(), installed.version, candidate.version);
+        }
+    }
+    
+    if !plan.installs.is_empty() {
+        println!("The following NEW packages will be installed:");
+        for package in &plan.installs {
+            println!("  {} (v{})", package.name.bold(), package.version);
+        }
+    }
+    
+    if !plan.removals.is_empty() {
+        println!("The following packages will be {}:", "REMOVED".red().bold());
+        for (package, reason) in &plan.removals {
+            println!("  {} (v{}): {}", package.name.bold(), package.version, reason.yellow());
+        }
+    }
+    
+    if !plan.recommended.is_empty() {
+        println!("Recommended packages: {}", plan.recommended.join(", "));
+    }
+    
+    if !plan.suggested.is_empty() {
+        println!("Suggested packages: {}", plan.suggested.join(", "));
+    }
+    
+    println!("{} upgraded, {} newly installed, {} to remove, {} kept back",
+        plan.upgrades.len(), plan.installs.len(), plan.removals.len(), plan.held_back.len());
+    println!("Need to download {} bytes", plan.download_size);
+    
     if !yes && !confirm_action() {
         println!("Operation cancelled");
         return Ok(());
     }
     
-    println!("Package upgrade not yet implemented");
+    let rt = Runtime::new()?;
+    let installed = rt.block_on(package_manager.upgrade_packages(&plan))
+        .map_err(|e| anyhow!("Upgrade failed and was rolled back: {}", e))?;
+        
+    for package in installed {
+        println!("{} Successfully installed {} ({})", "✓".green().bold(), package.name, package.version);
+    }
     
     Ok(())
 }
 
-fn search_packages(query: String) -> Result<()> {
+fn search_packages(config_paths: &ConfigPaths, query: String) -> Result<()> {
     if query.is_empty() {
         return Err(anyhow!("Search query cannot be empty"));
     }
     
     println!("{} Searching for packages matching: {}", "==>".blue().bold(), query);
     
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
-    
     // Load repositories
     let mut repo_manager = RepositoryManager::new(config_paths.repositories_file());
     repo_manager.load_repositories()?;
@@ -176,9 +650,9 @@ fn search_packages(query: String) -> Result<()> {
                     
                     for package in packages {
                         println!("  {} (v{}) - {}", 
-                            package.info.name.bold(), 
-                            package.info.version,
-                            package.info.description);
+                            package.name.bold(), 
+                            package.version,
+                            package.description);
                     }
                 }
             },
@@ -193,16 +667,13 @@ fn search_packages(query: String) -> Result<()> {
     Ok(())
 }
 
-fn show_package_info(package: String) -> Result<()> {
+fn show_package_info(config_paths: &ConfigPaths, package: String) -> Result<()> {
     if package.is_empty() {
         return Err(anyhow!("Package name cannot be empty"));
     }
     
     println!("{} Package information for: {}", "==>".blue().bold(), package);
     
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
-    
     // Load repositories
     let mut repo_manager = RepositoryManager::new(config_paths.repositories_file());
     repo_manager.load_repositories()?;
@@ -213,7 +684,8 @@ fn show_package_info(package: String) -> Result<()> {
     }
     
     // Create package manager
-    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories);
+    let package_manager = PackageManager::new(config_paths.packages_dir(), repositories.to_vec());
+    let installed = package_manager.installed_package(&package)?;
     
     // Create async runtime
     let rt = Runtime::new()?;
@@ -223,6 +695,11 @@ fn show_package_info(package: String) -> Result<()> {
         Ok(pkg) => {
             println!("Name: {}", pkg.name.bold());
             println!("Version: {}", pkg.version);
+            
+            match &installed {
+                Some(installed) => println!("Status: {} (v{})", installed.state, installed.version),
+                None => println!("Status: not installed"),
+            }
             println!("Architecture: {}", pkg.architecture);
             println!("Description: {}", pkg.description);
             println!("Maintainer: {}", pkg.maintainer);
@@ -239,7 +716,21 @@ fn show_package_info(package: String) -> Result<()> {
             if !pkg.dependencies.is_empty() {
                 println!("\nDependencies:");
                 for dep in &pkg.dependencies {
-                    println!("  {}", dep.name);
+                    println!("  {}", dep);
+                }
+            }
+            
+            if !pkg.recommends.is_empty() {
+                println!("\nRecommends:");
+                for relation in &pkg.recommends {
+                    println!("  {}", relation);
+                }
+            }
+            
+            if !pkg.suggests.is_empty() {
+                println!("\nSuggests:");
+                for relation in &pkg.suggests {
+                    println!("  {}", relation);
                 }
             }
             
@@ -249,6 +740,20 @@ fn show_package_info(package: String) -> Result<()> {
                     println!("  {}", conflict);
                 }
             }
+            
+            if !pkg.breaks.is_empty() {
+                println!("\nBreaks:");
+                for relation in &pkg.breaks {
+                    println!("  {}", relation);
+                }
+            }
+            
+            if !pkg.replaces.is_empty() {
+                println!("\nReplaces:");
+                for relation in &pkg.replaces {
+                    println!("  {}", relation);
+                }
+            }
         },
         Err(e) => {
             return Err(anyhow!("Package '{}' not found: {}", package, e));
@@ -258,53 +763,258 @@ fn show_package_info(package: String) -> Result<()> {
     Ok(())
 }
 
-fn list_installed_packages() -> Result<()> {
+fn list_installed_packages(config_paths: &ConfigPaths) -> Result<()> {
     println!("{} Installed packages:", "==>".blue().bold());
     
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_prefix_dir(config_paths.prefix_dir());
+    let packages = package_manager.installed_packages()?;
+    let versions = package_manager.versions()?;
     
-    // Check if packages directory exists
-    let packages_dir = config_paths.packages_dir();
-    if !packages_dir.exists() {
+    if packages.is_empty() && versions.packages().next().is_none() {
         println!("No packages installed");
         return Ok(());
     }
     
-    // List installed packages
-    let entries = std::fs::read_dir(packages_dir)?;
-    let mut found = false;
+    for package in packages {
+        if package.state == PackageState::Installed {
+            println!("  {} (v{})", package.name.bold(), package.version);
+        } else {
+            println!("  {} (v{}) [{}]", package.name.bold(), package.version, package.state.to_string().yellow());
+        }
+    }
     
-    for entry in entries {
-        if let Ok(entry) = entry {
-            if entry.file_type()?.is_dir() {
-                found = true;
-                let package_name = entry.file_name();
-                let package_dir = entry.path();
-                
-                // List versions
-                if let Ok(versions) = std::fs::read_dir(package_dir) {
-                    for version in versions {
-                        if let Ok(version) = version {
-                            if version.file_type()?.is_dir() {
-                                println!("  {} (v{})", 
-                                    package_name.to_string_lossy().bold(),
-                                    version.file_name().to_string_lossy());
-                            }
-                        }
-                    }
-                }
+    for package in versions.packages() {
+        for version in package.versions.keys() {
+            if package.active.as_ref() == Some(version) {
+                println!("  {} (v{}) [{}]", package.name.bold(), version, "side-by-side, active".green());
+            } else {
+                println!("  {} (v{}) [side-by-side]", package.name.bold(), version);
             }
         }
     }
     
-    if !found {
-        println!("No packages installed");
+    Ok(())
+}
+
+fn switch_version(config_paths: &ConfigPaths, package: String, version: String) -> Result<()> {
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_prefix_dir(config_paths.prefix_dir());
+    
+    let mut versions = package_manager.versions()?;
+    versions.switch(&package, &version)?;
+    
+    println!("{} {} now uses version {}", "✓".green().bold(), package, version);
+    Ok(())
+}
+
+fn show_conffiles(config_paths: &ConfigPaths, package: String, diff: bool) -> Result<()> {
+    println!("{} Configuration files of: {}", "==>".blue().bold(), package);
+    
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir())
+        .with_triggers_dir(config_paths.triggers_dir());
+    
+    let installed = package_manager.installed_package(&package)?
+        .ok_or_else(|| anyhow!("Package '{}' is not installed", package))?;
+        
+    if installed.conffiles.is_empty() {
+        println!("Package {} has no configuration files", package);
+        return Ok(());
+    }
+    
+    let diversions = package_manager.diversions()?;
+    
+    for conffile in &installed.conffiles {
+        let on_disk = package_manager.root_path(&diversions.destination(&conffile.path, &installed.name));
+        let new_path = Conffile::new_path(&on_disk);
+        let old_path = Conffile::old_path(&on_disk);
+        
+        let status = match conffile.status(&on_disk)? {
+            ConffileStatus::Unmodified => "unmodified".green(),
+            ConffileStatus::Modified => "modified".yellow(),
+            ConffileStatus::Missing => "missing".red(),
+        };
+        
+        println!("  {} [{}]", conffile.path.display(), status);
+        
+        if new_path.exists() {
+            println!("    pending: {}", new_path.display());
+            if diff {
+                show_diff(&on_disk, &new_path)?;
+            }
+        }
+        
+        if old_path.exists() {
+            println!("    previous: {}", old_path.display());
+            if diff {
+                show_diff(&old_path, &on_disk)?;
+            }
+        }
     }
     
     Ok(())
 }
 
+fn show_diff(from: &Path, to: &Path) -> Result<()> {
+    Command::new("diff")
+        .arg("-u")
+        .arg(from)
+        .arg(to)
+        .status()
+        .map_err(|e| anyhow!("Failed to run diff: {}", e))?;
+        
+    Ok(())
+}
+
+fn verify_packages(config_paths: &ConfigPaths, packages: Vec<String>) -> Result<()> {
+    println!("{} Verifying installed packages", "==>".blue().bold());
+    
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir());
+    
+    let database = package_manager.database()?;
+    let diversions = package_manager.diversions()?;
+    
+    let installed = if packages.is_empty() {
+        database.packages().filter(|p| p.is_present()).cloned().collect()
+    } else {
+        packages.iter()
+            .map(|name| database.get(name).cloned().ok_or_else(|| anyhow!("Package '{}' is not installed", name)))
+            .collect::<Result<Vec<_>>>()?
+    };
+    
+    let mut problem_count = 0;
+    for package in &installed {
+        for (path, problem) in package_manager.verify_package(package, &diversions)? {
+            println!("  {} {}: {}", package.name.bold(), path.display(), problem.yellow());
+            problem_count += 1;
+        }
+    }
+    
+    if problem_count > 0 {
+        return Err(anyhow!("Found {} problem(s) in {} package(s)", problem_count, installed.len()));
+    }
+    
+    println!("{} All files of {} package(s) are in place", "✓".green().bold(), installed.len());
+    Ok(())
+}
+
+fn handle_divert_command(config_paths: &ConfigPaths, command: DivertCommands) -> Result<()> {
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir());
+    
+    match command {
+        DivertCommands::Add { path, to, package, rename } => {
+            let divert_to = to.unwrap_or_else(|| format!("{}.distrib", path));
+            let diversion = Diversion {
+                path: PathBuf::from(path),
+                divert_to: PathBuf::from(divert_to),
+                package,
+            };
+            
+            package_manager.add_diversion(diversion.clone(), rename)?;
+            println!("{} Diverted {} to {}", "✓".green().bold(), diversion.path.display(), diversion.divert_to.display());
+            Ok(())
+        }
+        DivertCommands::Remove { path, rename } => {
+            let diversion = package_manager.remove_diversion(Path::new(&path), rename)?;
+            println!("{} Removed diversion of {} to {}", "✓".green().bold(), diversion.path.display(), diversion.divert_to.display());
+            Ok(())
+        }
+        DivertCommands::List => {
+            let diversions = package_manager.diversions()?;
+            
+            if diversions.iter().next().is_none() {
+                println!("No diversions");
+                return Ok(());
+            }
+            
+            for diversion in diversions.iter() {
+                let holder = match &diversion.package {
+                    Some(package) => format!("except for {}", package),
+                    None => "local".to_string(),
+                };
+                println!("  {} -> {} ({})", diversion.path.display(), diversion.divert_to.display(), holder);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn handle_alternatives_command(config_paths: &ConfigPaths, command: AlternativesCommands) -> Result<()> {
+    let package_manager = PackageManager::new(config_paths.packages_dir(), Vec::new())
+        .with_root_dir(config_paths.root_dir());
+    
+    match command {
+        AlternativesCommands::List { name } => {
+            let alternatives = package_manager.alternatives()?;
+            let groups: Vec<_> = alternatives.groups()
+                .filter(|g| name.as_ref().map(|n| g.name == *n).unwrap_or(true))
+                .collect();
+            
+            if groups.is_empty() {
+                match name {
+                    Some(name) => return Err(anyhow!("No alternative named {}", name)),
+                    None => println!("No alternatives"),
+                }
+                return Ok(());
+            }
+            
+            for group in groups {
+                let mode = match group.mode {
+                    AlternativeMode::Auto => "auto".green(),
+                    AlternativeMode::Manual => "manual".yellow(),
+                };
+                println!("{} - {} [{}]", group.name.bold(), group.link.display(), mode);
+                
+                let current = group.current().map(|p| p.path.clone());
+                for provider in &group.providers {
+                    let marker = if Some(&provider.path) == current.as_ref() { "*" } else { " " };
+                    let package = provider.package.as_deref().unwrap_or("local");
+                    println!("  {} {} (priority {}, {})", marker, provider.path.display(), provider.priority, package);
+                }
+            }
+            Ok(())
+        }
+        AlternativesCommands::Set { name, path } => {
+            package_manager.change_alternatives(|alternatives| alternatives.set(&name, Path::new(&path)))?;
+            println!("{} Using {} for {} (manual mode)", "✓".green().bold(), path, name);
+            Ok(())
+        }
+        AlternativesCommands::Auto { name } => {
+            package_manager.change_alternatives(|alternatives| alternatives.auto(&name))?;
+            let alternatives = package_manager.alternatives()?;
+            if let Some(current) = alternatives.get(&name).and_then(|g| g.current()) {
+                println!("{} Using {} for {} (auto mode)", "✓".green().bold(), current.path.display(), name);
+            }
+            Ok(())
+        }
+        AlternativesCommands::Install { link, name, path, priority, slave } => {
+            let spec = AlternativeSpec {
+                link: PathBuf::from(link),
+                name: name.clone(),
+                path: PathBuf::from(&path),
+                priority,
+                slaves: slave.chunks(3)
+                    .map(|s| (PathBuf::from(&s[0]), s[1].clone(), PathBuf::from(&s[2])))
+                    .collect(),
+            };
+            
+            // Providers registered from a maintainer script go away with its package
+            let package = std::env::var("DPKG_MAINTSCRIPT_PACKAGE").ok();
+            package_manager.change_alternatives(|alternatives| alternatives.install(&spec, package.as_deref()))?;
+            println!("{} Registered {} as an alternative for {}", "✓".green().bold(), path, name);
+            Ok(())
+        }
+        AlternativesCommands::Remove { name, path } => {
+            package_manager.change_alternatives(|alternatives| alternatives.remove(&name, Path::new(&path)))?;
+            println!("{} Removed {} as an alternative for {}", "✓".green().bold(), path, name);
+            Ok(())
+        }
+    }
+}
+
 fn handle_repo_command(command: RepoCommands) -> Result<()> {
     match command {
         RepoCommands::Add { url, name } => add_repository(url, name),
@@ -454,11 +1164,9 @@ fn disable_repository(name: String) -> Result<()> {
     }
 }
 
-fn clean_cache(all: bool) -> Result<()> {
+fn clean_cache(config_paths: &ConfigPaths, all: bool) -> Result<()> {
     println!("{} Cleaning package cache", "==>".blue().bold());
     
-    // Set up configuration
-    let config_paths = ConfigPaths::new();
     let temp_dir = config_paths.packages_dir().join("temp");
     
     if all {
@@ -476,11 +1184,15 @@ fn clean_cache(all: bool) -> Result<()> {
             let entries = std::fs::read_dir(&temp_dir)?;
             let mut count = 0;
             
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    std::fs::remove_file(entry.path())?;
-                    count += 1;
+            // Transactions stage packages in directories of their own
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    std::fs::remove_dir_all(&path)?;
+                } else {
+                    std::fs::remove_file(&path)?;
                 }
+                count += 1;
             }
             
             println!("{} Removed {} cached packages", "✓".green().bold(), count);
@@ -492,6 +1204,59 @@ fn clean_cache(all: bool) -> Result<()> {
     Ok(())
 }
 
+/// Puts the package manager into user mode when pkt runs with `--user`.
+fn for_user(package_manager: PackageManager, config_paths: &ConfigPaths) -> PackageManager {
+    if config_paths.user_mode() {
+        package_manager.with_user_mode(config_paths.user_bin_dir())
+    } else {
+        package_manager
+    }
+}
+
+/// Tells the user how to run the commands of user installed packages, unless
+/// their bin directory is already in `PATH`.
+fn show_user_path_hint(config_paths: &ConfigPaths) {
+    if !config_paths.user_mode() {
+        return;
+    }
+    
+    let bin_dir = config_paths.user_bin_dir();
+    let in_path = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir == bin_dir))
+        .unwrap_or(false);
+    
+    if !in_path {
+        println!();
+        println!("{} is not in your PATH. Add it by putting this in your shell profile:", bin_dir.display());
+        println!("  export PATH=\"{}:$PATH\"", bin_dir.display());
+    }
+}
+
+/// Asks which package should provide a virtual package, the first one
+/// being the default.
+fn choose_provider(choice: &ProviderChoice) -> Result<String> {
+    println!("{} is a virtual package provided by:", choice.virtual_name);
+    for (number, provider) in choice.providers.iter().enumerate() {
+        println!("  {}) {}", number + 1, provider);
+    }
+    print!("Which one do you want to install? [1] ");
+    std::io::stdout().flush().unwrap();
+    
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Err(anyhow!("No provider chosen for {}", choice.virtual_name));
+    }
+    
+    let input = input.trim();
+    let provider = match input.parse::<usize>() {
+        _ if input.is_empty() => choice.providers.first(),
+        Ok(number) => number.checked_sub(1).and_then(|index| choice.providers.get(index)),
+        Err(_) => choice.providers.iter().find(|p| *p == input),
+    };
+    
+    provider.cloned().ok_or_else(|| anyhow!("{} is not one of the providers of {}", input, choice.virtual_name))
+}
+
 fn confirm_action() -> bool {
     print!("Do you want to continue? [Y/n] ");
     std::io::stdout().flush().unwrap();
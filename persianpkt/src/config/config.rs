@@ -1,5 +1,6 @@
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::fs;
 
@@ -18,6 +19,32 @@ pub struct Config {
     pub auto_clean: bool,
     pub max_cache_size: u64,
     pub default_yes: bool,
+    #[serde(default)]
+    pub root_dir: Option<PathBuf>,
+    /// Seconds to wait for another pkt process to release the lock
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: u64,
+    /// Packages that installs and upgrades keep at their installed version
+    #[serde(default)]
+    pub held_packages: Vec<String>,
+    /// The package to install for a virtual package with several
+    /// providers, e.g. `mail-transport-agent = "postfix"`
+    #[serde(default)]
+    pub preferred_providers: BTreeMap<String, String>,
+    /// Install the packages new packages recommend
+    #[serde(default = "default_install_recommends")]
+    pub install_recommends: bool,
+    /// Install the packages new packages suggest
+    #[serde(default)]
+    pub install_suggests: bool,
+}
+
+fn default_lock_timeout() -> u64 {
+    60
+}
+
+fn default_install_recommends() -> bool {
+    true
 }
 
 impl Default for Config {
@@ -40,6 +67,12 @@ impl Default for Config {
             auto_clean: true,
             max_cache_size: 1024 * 1024 * 1024, // 1 GB
             default_yes: false,
+            root_dir: None,
+            lock_timeout: default_lock_timeout(),
+            held_packages: Vec::new(),
+            preferred_providers: BTreeMap::new(),
+            install_recommends: default_install_recommends(),
+            install_suggests: false,
         }
     }
 }
@@ -3,6 +3,8 @@ use dirs;
 
 pub struct ConfigPaths {
     base_dir: PathBuf,
+    root_dir: Option<PathBuf>,
+    user_mode: bool,
 }
 
 impl ConfigPaths {
@@ -13,11 +15,52 @@ impl ConfigPaths {
             PathBuf::from("/etc/persianpkt")
         };
         
-        Self { base_dir }
+        Self { base_dir, root_dir: None, user_mode: false }
     }
     
     pub fn with_base_dir(base_dir: PathBuf) -> Self {
-        Self { base_dir }
+        Self { base_dir, root_dir: None, user_mode: false }
+    }
+    
+    /// Installs into a private root below the user's data directory, with
+    /// commands linked into `user_bin_dir`.
+    pub fn with_user_mode(mut self) -> Self {
+        self.root_dir = Some(self.user_dir().join("root"));
+        self.user_mode = true;
+        self
+    }
+    
+    pub fn user_mode(&self) -> bool {
+        self.user_mode
+    }
+    
+    /// Per-user installation directory, `~/.local/share/persianpkt` on Linux.
+    pub fn user_dir(&self) -> PathBuf {
+        dirs::data_dir()
+            .map(|data_dir| data_dir.join("persianpkt"))
+            .unwrap_or_else(|| self.base_dir.join("user"))
+    }
+    
+    /// Directory of links to the commands of user installed packages, which
+    /// users add to their `PATH`.
+    pub fn user_bin_dir(&self) -> PathBuf {
+        self.user_dir().join("bin")
+    }
+    
+    /// Rebases installed state, cache, keys and the extraction target onto
+    /// `root_dir`, using the system locations inside it. Configuration and
+    /// repositories are still read from the host.
+    pub fn with_root_dir(mut self, root_dir: PathBuf) -> Self {
+        self.root_dir = Some(root_dir);
+        self
+    }
+    
+    pub fn root_dir(&self) -> PathBuf {
+        self.root_dir.clone().unwrap_or_else(|| PathBuf::from("/"))
+    }
+    
+    fn rooted(&self, system_path: &str) -> Option<PathBuf> {
+        self.root_dir.as_ref().map(|root| root.join(system_path))
     }
     
     pub fn base_dir(&self) -> PathBuf {
@@ -37,7 +80,9 @@ impl ConfigPaths {
     }
     
     pub fn cache_dir(&self) -> PathBuf {
-        if let Some(cache_dir) = dirs::cache_dir() {
+        if let Some(cache_dir) = self.rooted("var/cache/persianpkt") {
+            cache_dir
+        } else if let Some(cache_dir) = dirs::cache_dir() {
             cache_dir.join("persianpkt")
         } else {
             PathBuf::from("/var/cache/persianpkt")
@@ -45,19 +90,64 @@ impl ConfigPaths {
     }
     
     pub fn packages_dir(&self) -> PathBuf {
-        if let Some(data_dir) = dirs::data_dir() {
+        if let Some(packages_dir) = self.rooted("var/lib/persianpkt/packages") {
+            packages_dir
+        } else if let Some(data_dir) = dirs::data_dir() {
             data_dir.join("persianpkt").join("packages")
         } else {
             PathBuf::from("/var/lib/persianpkt/packages")
         }
     }
     
+    /// Where `pkt update` stores the package lists of each repository.
+    pub fn lists_dir(&self) -> PathBuf {
+        if let Some(lists_dir) = self.rooted("var/lib/persianpkt/lists") {
+            lists_dir
+        } else if let Some(data_dir) = dirs::data_dir() {
+            data_dir.join("persianpkt").join("lists")
+        } else {
+            PathBuf::from("/var/lib/persianpkt/lists")
+        }
+    }
+    
+    /// The lock file serializing pkt runs that share this data directory.
+    pub fn lock_file(&self) -> PathBuf {
+        if let Some(lock_file) = self.rooted("var/lib/persianpkt/lock") {
+            lock_file
+        } else if let Some(data_dir) = dirs::data_dir() {
+            data_dir.join("persianpkt").join("lock")
+        } else {
+            PathBuf::from("/var/lib/persianpkt/lock")
+        }
+    }
+    
+    /// Shared prefix holding links to the active side-by-side versions, whose
+    /// `bin` directory users add to their `PATH`.
+    pub fn prefix_dir(&self) -> PathBuf {
+        if let Some(prefix_dir) = self.rooted("opt/persianpkt") {
+            prefix_dir
+        } else if let Some(data_dir) = dirs::data_dir() {
+            data_dir.join("persianpkt").join("prefix")
+        } else {
+            PathBuf::from("/opt/persianpkt")
+        }
+    }
+    
+    /// Trigger definitions, one TOML file per trigger.
+    pub fn triggers_dir(&self) -> PathBuf {
+        self.rooted("etc/persianpkt/triggers.d")
+            .unwrap_or_else(|| self.base_dir.join("triggers.d"))
+    }
+    
     pub fn keys_dir(&self) -> PathBuf {
-        self.base_dir.join("keys")
+        self.rooted("etc/persianpkt/keys")
+            .unwrap_or_else(|| self.base_dir.join("keys"))
     }
     
     pub fn logs_dir(&self) -> PathBuf {
-        if let Some(data_dir) = dirs::data_dir() {
+        if let Some(logs_dir) = self.rooted("var/log/persianpkt") {
+            logs_dir
+        } else if let Some(data_dir) = dirs::data_dir() {
             data_dir.join("persianpkt").join("logs")
         } else {
             PathBuf::from("/var/log/persianpkt")
@@ -65,7 +155,9 @@ impl ConfigPaths {
     }
     
     pub fn temp_dir(&self) -> PathBuf {
-        if let Some(cache_dir) = dirs::cache_dir() {
+        if let Some(temp_dir) = self.rooted("tmp/persianpkt") {
+            temp_dir
+        } else if let Some(cache_dir) = dirs::cache_dir() {
             cache_dir.join("persianpkt").join("temp")
         } else {
             PathBuf::from("/tmp/persianpkt")
@@ -77,6 +169,7 @@ impl ConfigPaths {
             self.base_dir(),
             self.cache_dir(),
             self.packages_dir(),
+            self.lists_dir(),
             self.keys_dir(),
             self.logs_dir(),
             self.temp_dir(),
@@ -88,4 +181,44 @@ impl ConfigPaths {
         
         Ok(())
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn rebases_state_onto_the_root() {
+        let root = PathBuf::from("/srv/rootfs");
+        let paths = ConfigPaths::with_base_dir(PathBuf::from("/etc/persianpkt")).with_root_dir(root.clone());
+        
+        assert_eq!(paths.root_dir(), root);
+        for dir in [
+            paths.packages_dir(),
+            paths.lists_dir(),
+            paths.cache_dir(),
+            paths.keys_dir(),
+            paths.lock_file(),
+            paths.triggers_dir(),
+            paths.prefix_dir(),
+            paths.logs_dir(),
+            paths.temp_dir(),
+        ] {
+            assert!(dir.starts_with(&root), "{} is outside of the root", dir.display());
+        }
+        assert_eq!(paths.packages_dir(), root.join("var/lib/persianpkt/packages"));
+        assert_eq!(paths.lock_file(), root.join("var/lib/persianpkt/lock"));
+        
+        // Configuration still comes from the host
+        assert_eq!(paths.config_file(), Path::new("/etc/persianpkt/config.toml"));
+        assert_eq!(paths.repositories_file(), Path::new("/etc/persianpkt/repositories.json"));
+    }
+    
+    #[test]
+    fn uses_the_host_without_a_root() {
+        let paths = ConfigPaths::with_base_dir(PathBuf::from("/etc/persianpkt"));
+        
+        assert_eq!(paths.root_dir(), Path::new("/"));
+        assert_eq!(paths.keys_dir(), Path::new("/etc/persianpkt/keys"));
+        assert_eq!(paths.triggers_dir(), Path::new("/etc/persianpkt/triggers.d"));
+    }
+}
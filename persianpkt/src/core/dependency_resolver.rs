@@ -1,57 +1,133 @@
 use anyhow::Result;
-use std::collections::{HashMap, HashSet};
-use crate::package::{Package, PackageDependency};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::core::{Preferences, Solution, Solver, MAX_STEPS};
+use crate::package::{InstalledDatabase, Package, PackageDependency};
+use crate::repository::PackageIndex;
 
-pub struct DependencyResolver {
+/// Plans changes to the installed packages from the repository index: which
+/// packages to install, upgrade or remove, and the order to install them in.
+pub struct DependencyResolver<'a> {
+    index: &'a PackageIndex,
     installed_packages: HashMap<String, Package>,
+    held_packages: HashSet<String>,
+    preferred_providers: BTreeMap<String, String>,
+    preferences: Preferences,
+    max_steps: usize,
 }
 
-impl DependencyResolver {
-    pub fn new() -> Self {
+impl<'a> DependencyResolver<'a> {
+    pub fn new(index: &'a PackageIndex) -> Self {
         Self {
+            index,
             installed_packages: HashMap::new(),
+            held_packages: HashSet::new(),
+            preferred_providers: BTreeMap::new(),
+            preferences: Preferences::default(),
+            max_steps: MAX_STEPS,
         }
     }
 
-    pub fn load_installed_packages(&mut self) -> Result<()> {
-        Ok(())
+    pub fn with_preferences(mut self, preferences: Preferences) -> Self {
+        self.preferences = preferences;
+        self
     }
 
-    pub fn resolve_dependencies(&self, packages: &[String]) -> Result<Vec<Package>> {
-        let mut to_install = Vec::new();
-        let mut visited = HashSet::new();
-        
-        for package_name in packages {
-            self.resolve_package_dependencies(package_name, &mut to_install, &mut visited)?;
-        }
-        
-        Ok(to_install)
-    }
-
-    fn resolve_package_dependencies(
-        &self,
-        package_name: &str,
-        to_install: &mut Vec<Package>,
-        visited: &mut HashSet<String>,
-    ) -> Result<()> {
-        if visited.contains(package_name) {
-            return Ok(());
-        }
-        
-        visited.insert(package_name.to_string());
-        
+    /// Limits how many assignments the solver tries before giving up.
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Keeps these installed packages at their installed version.
+    pub fn with_held_packages(mut self, package_names: &[String]) -> Self {
+        self.held_packages = package_names.iter().cloned().collect();
+        self
+    }
+
+    /// Satisfies each virtual package with the provider named for it.
+    pub fn with_preferred_providers(mut self, providers: BTreeMap<String, String>) -> Self {
+        self.preferred_providers = providers;
+        self
+    }
+
+    pub fn load_installed_packages(&mut self, database: &InstalledDatabase) -> Result<()> {
+        self.installed_packages = database.packages()
+            .filter(|package| package.is_present())
+            .map(|package| (package.name.clone(), package.clone()))
+            .collect();
         Ok(())
     }
 
+    /// Plans installing the requested packages and a provider of each
+    /// requested virtual package: the installs are these together with the
+    /// dependencies they are missing and the installed packages that have to
+    /// change version for them, each after the packages it depends on. The
+    /// only removals are of installed packages that a new package conflicts
+    /// with and replaces.
+    pub fn resolve_dependencies(&self, packages: &[Package], virtual_names: &[String]) -> Result<Solution> {
+        let install: Vec<PackageDependency> = packages.iter()
+            .map(|p| PackageDependency::with_version_req(p.name.clone(), format!("= {}", p.version)))
+            .chain(virtual_names.iter().map(|name| PackageDependency::new(name.clone())))
+            .collect();
+
+        self.solver(packages).solve(&install, &[])
+    }
+
+    /// Finds the changes that install `install`, remove `remove` and keep
+    /// the other installed packages consistent, following the preferences.
+    pub fn solve(&self, install: &[PackageDependency], remove: &[String]) -> Result<Solution> {
+        self.solver(&[]).solve(install, remove)
+    }
+
+    fn solver<'s>(&'s self, local: &'s [Package]) -> Solver<'s> {
+        Solver::new(self.index, &self.installed_packages, &self.held_packages, self.preferences)
+            .with_local_packages(local)
+            .with_preferred_providers(&self.preferred_providers)
+            .with_max_steps(self.max_steps)
+    }
+
+    /// Returns a message for every `Conflicts` or `Breaks` between the
+    /// packages to install, and between them and the installed packages they
+    /// leave in place.
     pub fn check_conflicts(&self, packages: &[Package]) -> Result<Vec<String>> {
         let mut conflicts = Vec::new();
-        
+        let planned: HashSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        let remaining: Vec<&Package> = self.installed_packages.values()
+            .filter(|p| !planned.contains(p.name.as_str()))
+            .collect();
+
+        for package in packages {
+            for other in packages {
+                if let Some((kind, relation)) = package.rejects(other) {
+                    conflicts.push(format!("{} {} {} {}, and both are to be installed",
+                        package.name, package.version, kind, other.describe_match(&relation)));
+                }
+            }
+
+            for other in &remaining {
+                if let Some((kind, relation)) = package.rejects(other) {
+                    conflicts.push(format!("{} {} {} {}, which is installed",
+                        package.name, package.version, kind, other.describe_match(&relation)));
+                }
+                if let Some((kind, relation)) = other.rejects(package) {
+                    conflicts.push(format!("{} {} is installed and {} {}",
+                        other.name, other.version, kind, package.describe_match(&relation)));
+                }
+            }
+        }
+
+        conflicts.sort();
         Ok(conflicts)
     }
 
+    /// Returns the installed packages that depend on `package_name`.
     pub fn find_reverse_dependencies(&self, package_name: &str) -> Result<Vec<Package>> {
-        let mut reverse_deps = Vec::new();
-        
+        let mut reverse_deps: Vec<Package> = self.installed_packages.values()
+            .filter(|package| package.depends_on(package_name))
+            .cloned()
+            .collect();
+
+        reverse_deps.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(reverse_deps)
     }
-} 
\ No newline at end of file
+}
@@ -1,11 +1,13 @@
 mod package_manager;
 mod dependency_resolver;
+mod solver;
 mod cache;
 mod mirror;
 mod security;
  
 pub use package_manager::*;
 pub use dependency_resolver::*;
+pub use solver::*;
 pub use cache::*;
 pub use mirror::*;
 pub use security::*; 
\ No newline at end of file
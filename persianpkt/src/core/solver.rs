@@ -0,0 +1,1401 @@
+use anyhow::{Result, anyhow};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+use crate::package::{compare_versions, ConflictKind, Package, PackageDependency, WeakKind};
+use crate::repository::PackageIndex;
+
+/// A search that has tried this many assignments gives up, by default.
+pub const MAX_STEPS: usize = 200_000;
+
+/// Most reasons listed when no solution exists.
+const MAX_REASONS: usize = 8;
+
+/// Most failures a decision keeps from the decisions below it.
+const MAX_FAILURES: usize = 32;
+
+/// Why a package is in a solution, or why it has the version it has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cause {
+    Requested,
+    Installed,
+    Held,
+    /// An installed package removed because keeping it was impossible
+    Removed,
+    /// An installed package removed for a package that conflicts with and
+    /// replaces it
+    Replaced { package: String, version: String },
+    Dependency { package: String, version: String, relation: String },
+    /// Installed along with a package that recommends or suggests it
+    Weak { package: String, version: String, kind: WeakKind, relation: String },
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Requested => write!(f, "it was requested"),
+            Self::Installed => write!(f, "it is installed"),
+            Self::Held => write!(f, "it is installed and held"),
+            Self::Removed => write!(f, "it cannot be kept"),
+            Self::Replaced { package, version } => write!(f, "{} {} replaces it", package, version),
+            Self::Dependency { package, version, relation } => write!(f, "{} {} needs {}", package, version, relation),
+            Self::Weak { package, version, kind, relation } => write!(f, "{} {} {} {}", package, version, kind, relation),
+        }
+    }
+}
+
+/// What the solver tries first when several solutions exist.
+#[derive(Debug, Clone, Copy)]
+pub struct Preferences {
+    /// Prefer installed versions over newer candidates
+    pub keep_installed: bool,
+    /// Try every other version of an installed package before removing it
+    pub minimize_removals: bool,
+    pub allow_removals: bool,
+    /// Whether packages that are not installed may be pulled in
+    pub allow_installs: bool,
+    /// Stop with a `ProviderChoice` at a virtual package with several
+    /// providers and none preferred, instead of picking one
+    pub ask_providers: bool,
+    /// Also install what new packages recommend, where possible
+    pub install_recommends: bool,
+    /// Also install what new packages suggest, where possible
+    pub install_suggests: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            keep_installed: true,
+            minimize_removals: true,
+            allow_removals: false,
+            allow_installs: true,
+            ask_providers: false,
+            install_recommends: true,
+            install_suggests: false,
+        }
+    }
+}
+
+/// A consistent set of changes to the installed packages.
+#[derive(Debug, Default)]
+pub struct Solution {
+    /// Packages to install, including new versions of installed ones, each
+    /// after the packages it depends on
+    pub installs: Vec<Package>,
+    pub removals: Vec<Package>,
+    /// Why each package of the resulting system is there
+    pub causes: BTreeMap<String, Cause>,
+    /// Why each installed package that changes version or is removed, other
+    /// than the requested ones, has to
+    pub reasons: BTreeMap<String, String>,
+    /// Recommends and Suggests of the packages to install that the solution
+    /// leaves out
+    pub recommended: Vec<String>,
+    pub suggested: Vec<String>,
+}
+
+/// A virtual package that has to be provided but has several providers,
+/// none of them installed or preferred.
+#[derive(Debug, Clone)]
+pub struct ProviderChoice {
+    pub virtual_name: String,
+    pub providers: Vec<String>,
+}
+
+impl fmt::Display for ProviderChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is a virtual package provided by {}; set a preferred provider for it in preferred_providers",
+            self.virtual_name, self.providers.join(", "))
+    }
+}
+
+impl std::error::Error for ProviderChoice {}
+
+/// The error when no solution exists: the requirements that cannot be met
+/// together.
+#[derive(Debug, Clone)]
+pub struct Unsatisfiable {
+    pub failures: Vec<Failure>,
+    /// How many more failures were found than are listed
+    pub more: usize,
+}
+
+impl fmt::Display for Unsatisfiable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to satisfy dependencies:")?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+        if self.more > 0 {
+            write!(f, "\n  and {} more", self.more)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Unsatisfiable {}
+
+/// A requirement that cannot be met, and why each way of meeting it was
+/// ruled out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub requirement: Requirement,
+    pub rejections: Vec<Rejection>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rejections: Vec<String> = self.rejections.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}, but {}", self.requirement, rejections.join("; "))
+    }
+}
+
+/// Something a solution has to meet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    Requested { relation: String },
+    Dependency { package: String, version: String, installed: bool, held: bool, relation: String },
+    /// An installed package has to be kept, changed or removed
+    Installed { package: String, version: String },
+    Weak { package: String, version: String, kind: WeakKind, relation: String },
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Requested { relation } => write!(f, "{} was requested", relation),
+            Self::Dependency { package, version, installed, held, relation } => {
+                let label = match (installed, held) {
+                    (true, true) => " (installed, held)",
+                    (true, false) => " (installed)",
+                    _ => "",
+                };
+                write!(f, "{} {}{} needs {}", package, version, label, relation)
+            }
+            Self::Installed { package, version } => write!(f, "{} {} is installed", package, version),
+            Self::Weak { package, version, kind, relation } => write!(f, "{} {} {} {}", package, version, kind, relation),
+        }
+    }
+}
+
+/// Why one way of meeting a requirement was ruled out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The package is not installed and new packages are not allowed
+    NotInstalled { package: String },
+    Unavailable { package: String },
+    /// The package is held at a version that does not fit
+    Held { package: String, version: String },
+    /// The package does not provide a fitting version of a virtual package
+    NotProvided { package: String, relation: String },
+    /// Only versions that do not fit are available
+    OtherVersions { package: String, versions: Vec<String> },
+    /// The versions that fit fail whatever else is selected
+    Unusable { package: String, versions: Vec<String> },
+    /// The package is already selected in another version, or removed
+    Decided { package: String, version: Option<String>, cause: Cause },
+    /// `package` conflicts with or breaks `other`, as `target` describes,
+    /// and `selected`, one of the two, was selected first
+    Conflict { package: String, version: String, kind: ConflictKind, target: String, other: String, selected: String, cause: Cause },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInstalled { package } => write!(f, "{} is not installed and new packages are not allowed", package),
+            Self::Unavailable { package } => write!(f, "{} is not available", package),
+            Self::Held { package, version } => write!(f, "{} is held at {}", package, version),
+            Self::NotProvided { package, relation } => write!(f, "{} does not provide {}", package, relation),
+            Self::OtherVersions { package, versions } => write!(f, "{} is only available in version {}", package, versions.join(", ")),
+            Self::Unusable { package, versions } => write!(f, "{} {} cannot be selected", package, versions.join(", ")),
+            Self::Decided { package, version: Some(version), cause } => write!(f, "{} {} is selected because {}", package, version, cause),
+            Self::Decided { package, version: None, cause } => write!(f, "{} is to be removed because {}", package, cause),
+            Self::Conflict { package, version, kind, target, selected, cause, .. } if selected == package => {
+                write!(f, "{} {} {} {}, and {} {} is selected because {}", package, version, kind, target, package, version, cause)
+            }
+            Self::Conflict { package, version, kind, target, cause, .. } => {
+                write!(f, "{} {} {} {}, which is selected because {}", package, version, kind, target, cause)
+            }
+        }
+    }
+}
+
+/// A complete dependency solver. It searches for versions of the requested
+/// and installed packages such that every dependency holds and no two
+/// selected packages conflict, backtracking over the alternatives, versions
+/// and removals it may choose from. When no such set exists, the error lists
+/// the requirements that cannot be met together.
+pub struct Solver<'a> {
+    index: &'a PackageIndex,
+    installed: &'a HashMap<String, Package>,
+    held: &'a HashSet<String>,
+    local: &'a [Package],
+    preferences: Preferences,
+    /// The package to use for a virtual package, by virtual name
+    preferred_providers: Option<&'a BTreeMap<String, String>>,
+    max_steps: usize,
+}
+
+impl<'a> Solver<'a> {
+    pub fn new(
+        index: &'a PackageIndex,
+        installed: &'a HashMap<String, Package>,
+        held: &'a HashSet<String>,
+        preferences: Preferences,
+    ) -> Self {
+        Self {
+            index,
+            installed,
+            held,
+            local: &[],
+            preferences,
+            preferred_providers: None,
+            max_steps: MAX_STEPS,
+        }
+    }
+
+    /// Also consider `packages` as candidates, e.g. package files that are
+    /// not in any repository. They are preferred over the index.
+    pub fn with_local_packages(mut self, packages: &'a [Package]) -> Self {
+        self.local = packages;
+        self
+    }
+
+    /// Satisfies a virtual package with the provider named for it, if any.
+    pub fn with_preferred_providers(mut self, providers: &'a BTreeMap<String, String>) -> Self {
+        self.preferred_providers = Some(providers);
+        self
+    }
+
+    /// Gives up after trying `max_steps` assignments.
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Finds a solution that installs something satisfying each of
+    /// `install`, removes the packages named in `remove` and keeps the
+    /// remaining installed packages where possible. Recommends and Suggests
+    /// are installed as the preferences ask, but a solution without them is
+    /// returned rather than none.
+    pub fn solve(&self, install: &[PackageDependency], remove: &[String]) -> Result<Solution> {
+        let weak = self.preferences.install_recommends || self.preferences.install_suggests;
+        match self.search(install, remove, weak) {
+            Err(_) if weak => self.search(install, remove, false),
+            result => result,
+        }
+    }
+
+    fn search(&self, install: &[PackageDependency], remove: &[String], weak: bool) -> Result<Solution> {
+        let mut search = Search::new(self, install, weak);
+
+        for name in remove {
+            search.assignments.insert(name.clone(), Assignment { package: None, cause: Cause::Requested });
+        }
+
+        match search.run() {
+            Ok(()) => Ok(search.solution()),
+            Err(_) if search.undecided.is_some() => Err(anyhow::Error::new(search.undecided.take().expect("provider choice"))),
+            Err(_) if search.gave_up => Err(anyhow!("Unable to satisfy dependencies: gave up after trying {} assignments", self.max_steps)),
+            Err(mut failures) => {
+                let mut unique = Vec::new();
+                failures.retain(|failure| if unique.contains(failure) { false } else { unique.push(failure.clone()); true });
+                let more = failures.len().saturating_sub(MAX_REASONS);
+                failures.truncate(MAX_REASONS);
+                Err(anyhow::Error::new(Unsatisfiable { failures, more }))
+            }
+        }
+    }
+
+    /// Every version of a package that may be selected, the preferred first.
+    fn candidates(&self, package_name: &str) -> Vec<Package> {
+        let installed = self.installed.get(package_name);
+
+        if let Some(installed) = installed.filter(|_| self.held.contains(package_name)) {
+            return vec![installed.clone()];
+        }
+
+        let mut entries: Vec<_> = self.index.candidates(package_name).iter().collect();
+        entries.sort_by(|a, b| b.priority.cmp(&a.priority)
+            .then_with(|| compare_versions(&b.info.version, &a.info.version)));
+
+        let mut candidates: Vec<Package> = self.local.iter().filter(|p| p.name == package_name).cloned().collect();
+        for entry in entries {
+            if !candidates.iter().any(|p| p.version == entry.info.version) {
+                candidates.push(entry.to_package());
+            }
+        }
+
+        if let Some(installed) = installed {
+            candidates.retain(|p| p.version != installed.version);
+
+            // Newer versions come first unless installed versions are preferred
+            let position = if self.preferences.keep_installed {
+                0
+            } else {
+                candidates.iter()
+                    .position(|p| compare_versions(&p.version, &installed.version) != Ordering::Greater)
+                    .unwrap_or(candidates.len())
+            };
+            candidates.insert(position, installed.clone());
+        }
+
+        candidates
+    }
+
+    /// The packages that may provide `virtual_name`, in name order.
+    fn providers(&self, virtual_name: &str) -> Vec<String> {
+        let provides = |package: &Package| package.name != virtual_name
+            && package.provided().iter().any(|p| p.name == virtual_name);
+
+        let providers: BTreeSet<String> = self.index.providers(virtual_name).iter()
+            .map(|entry| entry.info.name.clone())
+            .filter(|name| name != virtual_name)
+            .chain(self.local.iter().filter(|p| provides(p)).map(|p| p.name.clone()))
+            .chain(self.installed.values().filter(|p| provides(p)).map(|p| p.name.clone()))
+            .collect();
+
+        providers.into_iter().collect()
+    }
+}
+
+struct Assignment {
+    /// `None` when the package is removed or left uninstalled
+    package: Option<Package>,
+    cause: Cause,
+}
+
+/// Something the selected packages still need.
+enum Obligation {
+    Request(usize),
+    Dependency { requirer: String, dependency: PackageDependency },
+    /// An installed package needs a decision: keep, change or remove it
+    Keep(String),
+    /// A `Recommends` or `Suggests`, which may also be left unmet
+    Weak { requirer: String, kind: WeakKind, dependency: PackageDependency },
+}
+
+type Choice = (String, Option<Package>, Cause);
+
+/// The ways to meet an obligation, and why there are no others.
+struct Options {
+    choices: Vec<Choice>,
+    reasons: Vec<Rejection>,
+    failures: Vec<Failure>,
+    conflict: HashSet<String>,
+}
+
+/// A decision with the options still left to try.
+struct ChoicePoint {
+    obligation: Obligation,
+    options: Vec<Choice>,
+    next: usize,
+    /// The package the option being tried assigns
+    current: Option<String>,
+    trail_len: usize,
+    cursor: usize,
+    keep_cursor: usize,
+    weak_cursor: (usize, usize),
+    /// Why options were ruled out here, and why the choices below failed
+    reasons: Vec<Rejection>,
+    failures: Vec<Failure>,
+    /// Packages whose assignment the failures below depend on, for all
+    /// options and for the current one
+    conflict: HashSet<String>,
+    option_conflict: HashSet<String>,
+    /// Where the failures of the current option start
+    option_failures: usize,
+}
+
+struct Search<'s, 'a> {
+    solver: &'s Solver<'a>,
+    install: &'s [PackageDependency],
+    /// Whether weak dependencies are met at all
+    weak: bool,
+    keep: Vec<String>,
+    assignments: HashMap<String, Assignment>,
+    /// Assigned packages in order, so decisions can be undone
+    trail: Vec<String>,
+    /// The `Conflicts` and `Breaks` of the selected packages, by the name
+    /// they are against
+    conflicts: HashMap<String, Vec<(String, ConflictKind, PackageDependency)>>,
+    /// The selected packages by the virtual packages they provide
+    provided: HashMap<String, Vec<String>>,
+    candidates: HashMap<String, Vec<Package>>,
+    providers: HashMap<String, Vec<String>>,
+    /// Versions found to fail whatever else is selected, with the reasons
+    dead: HashMap<(String, String), Vec<Failure>>,
+    steps: usize,
+    /// Whether the search stopped after `max_steps`
+    gave_up: bool,
+    /// The provider choice that stopped the search, see `ask_providers`
+    undecided: Option<ProviderChoice>,
+}
+
+impl<'s, 'a> Search<'s, 'a> {
+    fn new(solver: &'s Solver<'a>, install: &'s [PackageDependency], weak: bool) -> Self {
+        let mut keep: Vec<String> = solver.installed.keys().cloned().collect();
+        keep.sort();
+
+        Self {
+            solver,
+            install,
+            weak,
+            keep,
+            assignments: HashMap::new(),
+            trail: Vec::new(),
+            conflicts: HashMap::new(),
+            provided: HashMap::new(),
+            candidates: HashMap::new(),
+            providers: HashMap::new(),
+            dead: HashMap::new(),
+            steps: 0,
+            gave_up: false,
+            undecided: None,
+        }
+    }
+
+    /// Runs a depth-first search with conflict-directed backjumping: when
+    /// a decision runs out of options, the search goes back to the latest
+    /// decision its failure depends on instead of simply the previous one.
+    fn run(&mut self) -> std::result::Result<(), Vec<Failure>> {
+        let mut stack: Vec<ChoicePoint> = Vec::new();
+        let (mut cursor, mut keep_cursor, mut weak_cursor) = (0, 0, (0, 0));
+
+        loop {
+            let obligation = match self.next_obligation(&mut cursor, &mut keep_cursor, &mut weak_cursor) {
+                Some(obligation) => obligation,
+                None => return Ok(()),
+            };
+
+            let options = self.options(&obligation);
+            if self.undecided.is_some() {
+                return Err(Vec::new());
+            }
+            stack.push(ChoicePoint {
+                obligation,
+                options: options.choices,
+                next: 0,
+                current: None,
+                trail_len: self.trail.len(),
+                cursor,
+                keep_cursor,
+                weak_cursor,
+                reasons: options.reasons,
+                failures: options.failures,
+                conflict: options.conflict,
+                option_conflict: HashSet::new(),
+                option_failures: 0,
+            });
+
+            loop {
+                if self.steps > self.solver.max_steps {
+                    self.gave_up = true;
+                    return Err(Vec::new());
+                }
+
+                let point = stack.last_mut().expect("choice point");
+                self.undo(point.trail_len);
+                cursor = point.cursor;
+                keep_cursor = point.keep_cursor;
+                weak_cursor = point.weak_cursor;
+
+                if self.try_next(point) {
+                    break;
+                }
+
+                let point = stack.pop().expect("choice point");
+                let (conflict, failures) = self.explain(point);
+
+                let target = stack.iter().rposition(|p| p.current.as_ref().map(|name| conflict.contains(name)).unwrap_or(false));
+                match target {
+                    Some(target) => {
+                        stack.truncate(target + 1);
+                        let point = &mut stack[target];
+                        point.option_conflict.extend(conflict.iter().cloned());
+                        point.conflict.extend(conflict);
+                        add_failures(&mut point.failures, &failures);
+                    }
+                    None => return Err(failures),
+                }
+            }
+        }
+    }
+
+    /// Returns the first requirement the current assignment leaves open:
+    /// requests first, then dependencies of selected packages in the order
+    /// they were selected, then installed packages not decided yet, then
+    /// weak dependencies of the selected packages.
+    fn next_obligation(&self, cursor: &mut usize, keep_cursor: &mut usize, weak_cursor: &mut (usize, usize)) -> Option<Obligation> {
+        if let Some(index) = self.install.iter().position(|d| !self.satisfied(d)) {
+            return Some(Obligation::Request(index));
+        }
+
+        // A satisfied dependency stays satisfied as the assignment only grows
+        while *cursor < self.trail.len() {
+            let name = &self.trail[*cursor];
+            if let Some(package) = &self.assignments[name].package {
+                let open = package.dependencies.iter()
+                    .filter(|d| !d.is_optional)
+                    .find(|d| !self.satisfied(d));
+                if let Some(dependency) = open {
+                    return Some(Obligation::Dependency { requirer: name.clone(), dependency: dependency.clone() });
+                }
+            }
+            *cursor += 1;
+        }
+
+        while *keep_cursor < self.keep.len() {
+            let name = &self.keep[*keep_cursor];
+            if !self.assignments.contains_key(name) {
+                return Some(Obligation::Keep(name.clone()));
+            }
+            *keep_cursor += 1;
+        }
+
+        // The cursor moves past a weak dependency once it is decided, as
+        // leaving it unmet is one of its options
+        while weak_cursor.0 < self.trail.len() {
+            let name = &self.trail[weak_cursor.0];
+            if let Some(package) = &self.assignments[name].package {
+                let relations = self.weak_relations(package);
+                while weak_cursor.1 < relations.len() {
+                    let (kind, dependency) = &relations[weak_cursor.1];
+                    weak_cursor.1 += 1;
+                    if !self.satisfied(dependency) {
+                        return Some(Obligation::Weak { requirer: name.clone(), kind: *kind, dependency: dependency.clone() });
+                    }
+                }
+            }
+            *weak_cursor = (weak_cursor.0 + 1, 0);
+        }
+
+        None
+    }
+
+    /// The weak dependencies to meet for a selected package: those its
+    /// installed version did not have, so that ones the user removed stay
+    /// removed.
+    fn weak_relations(&self, package: &Package) -> Vec<(WeakKind, PackageDependency)> {
+        if !self.weak {
+            return Vec::new();
+        }
+        let preferences = self.solver.preferences;
+
+        let mut relations: Vec<(WeakKind, &PackageDependency)> = Vec::new();
+        if preferences.install_recommends {
+            relations.extend(package.recommends.iter().map(|r| (WeakKind::Recommends, r)));
+        }
+        if preferences.install_suggests {
+            relations.extend(package.suggests.iter().map(|r| (WeakKind::Suggests, r)));
+        }
+
+        let installed = self.solver.installed.get(&package.name);
+        let known = |relation: &PackageDependency| installed
+            .map(|i| i.recommends.iter().chain(&i.suggests).any(|r| r.to_string() == relation.to_string()))
+            .unwrap_or(false);
+
+        relations.into_iter()
+            .filter(|(_, relation)| !known(relation))
+            .map(|(kind, relation)| (kind, relation.clone()))
+            .collect()
+    }
+
+    fn satisfied(&self, dependency: &PackageDependency) -> bool {
+        dependency.choices().any(|choice| self.selected_for(choice).is_some())
+    }
+
+    /// Returns the selected package that meets `choice`, the package of that
+    /// name first, then one providing it.
+    fn selected_for<'c>(&'c self, choice: &'c PackageDependency) -> Option<&'c String> {
+        let selected = |name: &String| match self.assignments.get(name) {
+            Some(Assignment { package: Some(package), .. }) => package.satisfies(choice),
+            _ => false,
+        };
+
+        Some(&choice.name).filter(|name| selected(name))
+            .or_else(|| self.provided.get(&choice.name)?.iter().find(|name| selected(name)))
+    }
+
+    fn candidates(&mut self, package_name: &str) -> &[Package] {
+        if !self.candidates.contains_key(package_name) {
+            let candidates = self.solver.candidates(package_name);
+            self.candidates.insert(package_name.to_string(), candidates);
+        }
+        &self.candidates[package_name]
+    }
+
+    /// Lists the ways to meet an obligation, with the reasons for each
+    /// choice that offers none and the packages those reasons depend on.
+    fn options(&mut self, obligation: &Obligation) -> Options {
+        let reasons = Vec::new();
+        let failures = Vec::new();
+        let mut conflict = HashSet::new();
+
+        let (dependency, cause) = match obligation {
+            Obligation::Request(index) => (self.install[*index].clone(), Cause::Requested),
+            Obligation::Dependency { requirer, dependency } | Obligation::Weak { requirer, dependency, .. } => {
+                let version = self.assignments[requirer].package.as_ref().map(|p| p.version.clone()).unwrap_or_default();
+                conflict.insert(requirer.clone());
+                let (package, relation) = (requirer.clone(), dependency.to_string());
+                (dependency.clone(), match obligation {
+                    Obligation::Weak { kind, .. } => Cause::Weak { package, version, kind: *kind, relation },
+                    _ => Cause::Dependency { package, version, relation },
+                })
+            }
+            Obligation::Keep(name) => {
+                let held = self.solver.held.contains(name);
+                let cause = if held { Cause::Held } else { Cause::Installed };
+                let preferences = self.solver.preferences;
+
+                let mut options: Vec<Choice> = self.candidates(name).iter()
+                    .map(|p| (name.clone(), Some(p.clone()), cause.clone()))
+                    .collect();
+
+                // A package replacing this one may take its place even when removals are not allowed
+                let removal = match self.replacer(name) {
+                    Some(replacer) if !held => Some(replacer),
+                    _ if preferences.allow_removals && !held => Some(Cause::Removed),
+                    _ => None,
+                };
+                if let Some(cause) = removal {
+                    let position = if preferences.minimize_removals { options.len() } else { 1.min(options.len()) };
+                    options.insert(position, (name.clone(), None, cause));
+                }
+
+                return Options { choices: options, reasons, failures, conflict };
+            }
+        };
+
+        let requested = matches!(obligation, Obligation::Request(_));
+        let weak = matches!(obligation, Obligation::Weak { .. });
+
+        let mut result = Options { choices: Vec::new(), reasons, failures, conflict };
+        for choice in dependency.choices() {
+            let providers = self.providers(choice, !weak);
+            let real = self.solver.installed.contains_key(&choice.name) || !self.candidates(&choice.name).is_empty();
+
+            if real || providers.is_empty() {
+                self.add_options(choice, &choice.name, requested, &cause, &mut result);
+            }
+            for provider in &providers {
+                self.add_options(choice, provider, requested, &cause, &mut result);
+            }
+        }
+
+        result
+    }
+
+    /// Adds the versions of `target` that meet `choice`, or the reason there
+    /// are none.
+    fn add_options(&mut self, choice: &PackageDependency, target: &str, requested: bool, cause: &Cause, result: &mut Options) {
+        let installed = self.solver.installed.get(target);
+        if installed.is_none() && !requested && !self.solver.preferences.allow_installs {
+            result.reasons.push(Rejection::NotInstalled { package: target.to_string() });
+            return;
+        }
+
+        let held = self.solver.held.contains(target);
+        let candidates = self.candidates(target).to_vec();
+        let matching: Vec<&Package> = candidates.iter().filter(|p| p.satisfies(choice)).collect();
+
+        if matching.is_empty() {
+            let package = target.to_string();
+            result.reasons.push(match (candidates.is_empty(), held, installed) {
+                (true, _, _) => Rejection::Unavailable { package },
+                (false, true, Some(installed)) => Rejection::Held { package, version: installed.version.clone() },
+                _ if target != choice.name => Rejection::NotProvided { package, relation: describe_choice(choice) },
+                _ => Rejection::OtherVersions { package, versions: candidates.iter().map(|p| p.version.clone()).collect() },
+            });
+            return;
+        }
+
+        // Versions known to fail rule the choice out whatever is selected
+        let (dead, matching): (Vec<&Package>, Vec<&Package>) = matching.into_iter()
+            .partition(|p| self.dead.contains_key(&(target.to_string(), p.version.clone())));
+        if matching.is_empty() {
+            result.reasons.push(Rejection::Unusable {
+                package: target.to_string(),
+                versions: dead.iter().map(|p| p.version.clone()).collect(),
+            });
+            for package in dead {
+                add_failures(&mut result.failures, &self.dead[&(target.to_string(), package.version.clone())]);
+            }
+            return;
+        }
+
+        if let Some(assignment) = self.assignments.get(target) {
+            result.reasons.push(Rejection::Decided {
+                package: target.to_string(),
+                version: assignment.package.as_ref().map(|p| p.version.clone()),
+                cause: assignment.cause.clone(),
+            });
+            result.conflict.insert(target.to_string());
+            return;
+        }
+
+        for package in matching {
+            if !result.choices.iter().any(|(name, p, _)| *name == package.name && p.as_ref().map(|p| &p.version) == Some(&package.version)) {
+                result.choices.push((target.to_string(), Some(package.clone()), cause.clone()));
+            }
+        }
+    }
+
+    /// Returns the packages other than `choice.name` that may provide it, an
+    /// installed one first, then the preferred one. With `ask_providers` and
+    /// `ask`, a choice between several providers and no package of the name
+    /// itself stops the search so the user can pick one.
+    fn providers(&mut self, choice: &PackageDependency, ask: bool) -> Vec<String> {
+        if !self.providers.contains_key(&choice.name) {
+            let providers = self.solver.providers(&choice.name);
+            self.providers.insert(choice.name.clone(), providers);
+        }
+        let mut providers = self.providers[&choice.name].clone();
+        if providers.is_empty() {
+            return providers;
+        }
+
+        let preferred = self.solver.preferred_providers
+            .and_then(|preferred| preferred.get(&choice.name))
+            .filter(|name| providers.contains(name))
+            .cloned();
+        if let Some(preferred) = &preferred {
+            providers.retain(|name| name != preferred);
+            providers.insert(0, preferred.clone());
+        }
+
+        // An installed provider is kept even over a preferred one
+        providers.sort_by_key(|name| !self.solver.installed.contains_key(name));
+        if preferred.is_some() {
+            return providers;
+        }
+
+        let fitting: Vec<String> = providers.iter()
+            .filter(|name| self.candidates(name).iter().any(|p| p.provides_choice(choice)))
+            .cloned()
+            .collect();
+        let real = self.solver.installed.contains_key(&choice.name) || !self.candidates(&choice.name).is_empty();
+        let installed = fitting.iter().any(|name| self.solver.installed.contains_key(name));
+
+        if ask && self.solver.preferences.ask_providers && !real && !installed && fitting.len() > 1 {
+            self.undecided = Some(ProviderChoice { virtual_name: choice.name.clone(), providers: fitting });
+        }
+
+        providers
+    }
+
+    /// Assigns the next option of a choice point that does not conflict with
+    /// the packages selected so far.
+    fn try_next(&mut self, point: &mut ChoicePoint) -> bool {
+        // A version whose failure depended on no other decision never fits
+        if let Some(name) = point.current.take() {
+            let conflict = std::mem::take(&mut point.option_conflict);
+            if let Some(package) = &point.options[point.next - 1].1 {
+                if conflict.iter().all(|culprit| *culprit == name) {
+                    let failures = point.failures[point.option_failures..].to_vec();
+                    self.dead.insert((name, package.version.clone()), failures);
+                }
+            }
+        }
+
+        while point.next < point.options.len() {
+            let (name, package, cause) = point.options[point.next].clone();
+            point.next += 1;
+            self.steps += 1;
+
+            if let Some(package) = &package {
+                if let Some(failures) = self.dead.get(&(name.clone(), package.version.clone())) {
+                    add_failures(&mut point.failures, failures);
+                    continue;
+                }
+                if let Some((reason, culprit)) = self.find_conflict(package) {
+                    point.reasons.push(reason);
+                    point.conflict.insert(culprit);
+                    continue;
+                }
+            }
+
+            self.assign(name.clone(), package, cause);
+            point.current = Some(name);
+            point.option_failures = point.failures.len();
+            return true;
+        }
+
+        point.current = None;
+
+        // A weak dependency is left unmet once nothing else fits
+        if matches!(point.obligation, Obligation::Weak { .. }) && point.next == point.options.len() {
+            point.next += 1;
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns why `package` cannot be selected next to the packages already
+    /// selected, and the selected package it conflicts with.
+    fn find_conflict(&self, package: &Package) -> Option<(Rejection, String)> {
+        let names = std::iter::once(package.name.clone()).chain(package.provided().into_iter().map(|p| p.name));
+        for name in names {
+            for (owner, kind, relation) in self.conflicts.get(&name).into_iter().flatten() {
+                if *owner != package.name && package.satisfies(relation) {
+                    let selected = &self.assignments[owner];
+                    let version = selected.package.as_ref().map(|p| p.version.as_str()).unwrap_or_default();
+                    return Some((Rejection::Conflict {
+                        package: owner.clone(),
+                        version: version.to_string(),
+                        kind: *kind,
+                        target: package.describe_match(relation),
+                        other: package.name.clone(),
+                        selected: owner.clone(),
+                        cause: selected.cause.clone(),
+                    }, owner.clone()));
+                }
+            }
+        }
+
+        for (kind, relation) in package.conflict_relations() {
+            let targets = std::iter::once(&relation.name).chain(self.provided.get(&relation.name).into_iter().flatten());
+            for target in targets.filter(|name| **name != package.name) {
+                if let Some(Assignment { package: Some(other), cause }) = self.assignments.get(target) {
+                    if other.satisfies(&relation) {
+                        return Some((Rejection::Conflict {
+                            package: package.name.clone(),
+                            version: package.version.clone(),
+                            kind,
+                            target: other.describe_match(&relation),
+                            other: other.name.clone(),
+                            selected: other.name.clone(),
+                            cause: cause.clone(),
+                        }, other.name.clone()));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Returns the removal cause when a selected package conflicts with and
+    /// replaces the installed version of `name`.
+    fn replacer(&self, name: &str) -> Option<Cause> {
+        let installed = self.solver.installed.get(name)?;
+        let names = std::iter::once(installed.name.clone()).chain(installed.provided().into_iter().map(|p| p.name));
+
+        for name in names {
+            for (owner, _, relation) in self.conflicts.get(&name).into_iter().flatten() {
+                let replacer = match &self.assignments[owner].package {
+                    Some(package) => package,
+                    None => continue,
+                };
+                if installed.satisfies(relation) && replacer.replaces_package(installed) {
+                    return Some(Cause::Replaced { package: replacer.name.clone(), version: replacer.version.clone() });
+                }
+            }
+        }
+
+        None
+    }
+
+    fn assign(&mut self, name: String, package: Option<Package>, cause: Cause) {
+        if let Some(package) = &package {
+            for (kind, relation) in package.conflict_relations() {
+                self.conflicts.entry(relation.name.clone()).or_default().push((name.clone(), kind, relation));
+            }
+            for provided in package.provided() {
+                self.provided.entry(provided.name).or_default().push(name.clone());
+            }
+        }
+
+        self.assignments.insert(name.clone(), Assignment { package, cause });
+        self.trail.push(name);
+    }
+
+    /// Undoes the assignments made after the trail was `len` long.
+    fn undo(&mut self, len: usize) {
+        while self.trail.len() > len {
+            let name = self.trail.pop().expect("trail entry");
+            if let Some(Assignment { package: Some(package), .. }) = self.assignments.remove(&name) {
+                for (_, relation) in package.conflict_relations() {
+                    if let Some(owners) = self.conflicts.get_mut(&relation.name) {
+                        owners.pop();
+                    }
+                }
+                for provided in package.provided() {
+                    if let Some(owners) = self.provided.get_mut(&provided.name) {
+                        owners.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Turns an exhausted choice point into the failure it passes up: the
+    /// reasons it found, and the decisions above it they depend on.
+    fn explain(&self, point: ChoicePoint) -> (HashSet<String>, Vec<Failure>) {
+        let mut conflict = point.conflict;
+        for (name, _, _) in &point.options {
+            conflict.remove(name);
+        }
+        if let Obligation::Dependency { requirer, .. } | Obligation::Weak { requirer, .. } = &point.obligation {
+            conflict.insert(requirer.clone());
+        }
+
+        let mut failures = point.failures;
+        if !point.reasons.is_empty() {
+            failures.push(Failure { requirement: self.requirement(&point.obligation), rejections: point.reasons });
+        }
+
+        (conflict, failures)
+    }
+
+    fn requirement(&self, obligation: &Obligation) -> Requirement {
+        let selected_version = |name: &String| self.assignments[name].package.as_ref()
+            .map(|p| p.version.clone())
+            .unwrap_or_default();
+
+        match obligation {
+            Obligation::Request(index) => Requirement::Requested { relation: self.install[*index].to_string() },
+            Obligation::Dependency { requirer, dependency } => {
+                let version = selected_version(requirer);
+                let installed = self.solver.installed.get(requirer).map(|p| p.version == version).unwrap_or(false);
+                Requirement::Dependency {
+                    package: requirer.clone(),
+                    held: installed && self.solver.held.contains(requirer),
+                    installed,
+                    version,
+                    relation: dependency.to_string(),
+                }
+            }
+            Obligation::Keep(name) => Requirement::Installed {
+                package: name.clone(),
+                version: self.solver.installed.get(name).map(|p| p.version.clone()).unwrap_or_default(),
+            },
+            Obligation::Weak { requirer, kind, dependency } => Requirement::Weak {
+                package: requirer.clone(),
+                version: selected_version(requirer),
+                kind: *kind,
+                relation: dependency.to_string(),
+            },
+        }
+    }
+
+    /// Collects the changes the final assignment makes to the installed
+    /// packages.
+    fn solution(&self) -> Solution {
+        let mut solution = Solution::default();
+
+        // Requested packages are installed again even in the same version
+        let changed: Vec<&String> = self.trail.iter()
+            .filter(|name| {
+                let assignment = &self.assignments[*name];
+                let installed = self.solver.installed.get(*name).map(|p| p.version.as_str());
+                match &assignment.package {
+                    Some(package) => matches!(assignment.cause, Cause::Requested) || installed != Some(package.version.as_str()),
+                    None => false,
+                }
+            })
+            .collect();
+
+        let changed_set: HashSet<&String> = changed.iter().copied().collect();
+        let mut visited = HashSet::new();
+        for name in changed {
+            self.order(name, &changed_set, &mut visited, &mut solution.installs);
+        }
+
+        for (name, assignment) in &self.assignments {
+            match &assignment.package {
+                Some(_) => {
+                    solution.causes.insert(name.clone(), assignment.cause.clone());
+                }
+                None => {
+                    if let Some(installed) = self.solver.installed.get(name) {
+                        solution.removals.push(installed.clone());
+                    }
+                }
+            }
+        }
+        solution.removals.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for (name, installed) in self.solver.installed {
+            let assignment = match self.assignments.get(name) {
+                Some(assignment) if !matches!(assignment.cause, Cause::Requested) => assignment,
+                _ => continue,
+            };
+            if assignment.package.as_ref().map(|p| p.version == installed.version).unwrap_or(false) {
+                continue;
+            }
+            solution.reasons.insert(name.clone(), self.change_reason(installed, assignment));
+        }
+
+        for package in &solution.installs {
+            solution.recommended.extend(package.recommends.iter().filter(|r| !self.satisfied(r)).map(|r| r.to_string()));
+            solution.suggested.extend(package.suggests.iter().filter(|r| !self.satisfied(r)).map(|r| r.to_string()));
+        }
+        for relations in [&mut solution.recommended, &mut solution.suggested] {
+            relations.sort();
+            relations.dedup();
+        }
+
+        solution
+    }
+
+    /// Tells which relation keeps the installed version of a package that
+    /// the solution changes or removes.
+    fn change_reason(&self, installed: &Package, assignment: &Assignment) -> String {
+        let selected = || self.trail.iter()
+            .filter(|name| **name != installed.name)
+            .filter_map(|name| self.assignments[name].package.as_ref());
+
+        if let Cause::Replaced { .. } = assignment.cause {
+            if let Some((package, (kind, relation))) = selected()
+                .filter(|p| p.replaces_package(installed))
+                .find_map(|p| p.rejects(installed).map(|rejection| (p, rejection))) {
+                return format!("{} {} {} {} and replaces it", package.name, package.version, kind, installed.describe_match(&relation));
+            }
+        }
+
+        if let Some((package, (kind, relation))) = selected().find_map(|p| p.rejects(installed).map(|rejection| (p, rejection))) {
+            return format!("{} {} {} {}", package.name, package.version, kind, installed.describe_match(&relation));
+        }
+
+        if let Some((other, (kind, relation))) = selected().find_map(|p| installed.rejects(p).map(|rejection| (p, rejection))) {
+            return format!("{} {} {} {}", installed.name, installed.version, kind, other.describe_match(&relation));
+        }
+
+        if let Cause::Dependency { .. } = assignment.cause {
+            return assignment.cause.to_string();
+        }
+
+        let broken = installed.dependencies.iter()
+            .filter(|d| !d.is_optional)
+            .find(|d| !self.satisfied(d));
+        match broken {
+            Some(dependency) => format!("{} {} needs {}", installed.name, installed.version, dependency),
+            None => assignment.cause.to_string(),
+        }
+    }
+
+    /// Appends a package to `order` after the changed packages it depends
+    /// on. A package reached again through a cycle is left where it is.
+    fn order(&self, name: &String, changed: &HashSet<&String>, visited: &mut HashSet<String>, order: &mut Vec<Package>) {
+        if !visited.insert(name.clone()) {
+            return;
+        }
+
+        let package = match &self.assignments[name].package {
+            Some(package) => package,
+            None => return,
+        };
+
+        for dependency in package.dependencies.iter().filter(|d| !d.is_optional) {
+            let selected = dependency.choices().find_map(|choice| self.selected_for(choice));
+            if let Some(name) = selected.filter(|name| changed.contains(name)) {
+                self.order(name, changed, visited, order);
+            }
+        }
+
+        order.push(package.clone());
+    }
+}
+
+/// One alternative of a relation, without the others.
+fn describe_choice(choice: &PackageDependency) -> String {
+    match &choice.version_req {
+        Some(version_req) => format!("{} ({})", choice.name, version_req),
+        None => choice.name.clone(),
+    }
+}
+
+/// Adds the failures not listed yet, up to `MAX_FAILURES`.
+fn add_failures(failures: &mut Vec<Failure>, more: &[Failure]) {
+    for failure in more {
+        if failures.len() >= MAX_FAILURES {
+            break;
+        }
+        if !failures.contains(failure) {
+            failures.push(failure.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{IndexEntry, RepositorySource};
+
+    /// An index of the packages described in `Packages` file syntax.
+    fn index(packages: &str) -> PackageIndex {
+        let mut index = PackageIndex::default();
+        for info in RepositorySource::parse_packages_file(packages).unwrap() {
+            index.insert(IndexEntry {
+                repository: "test".to_string(),
+                priority: 500,
+                base_url: url::Url::parse("http://localhost/debian/").unwrap(),
+                info,
+            });
+        }
+        index
+    }
+
+    fn installed(index: &PackageIndex, packages: &[(&str, &str)]) -> HashMap<String, Package> {
+        packages.iter()
+            .map(|(name, version)| (name.to_string(), index.find(name, version).unwrap().to_package()))
+            .collect()
+    }
+
+    fn request(relation: &str) -> Vec<PackageDependency> {
+        vec![PackageDependency::parse(relation).unwrap()]
+    }
+
+    fn versions(solution: &Solution) -> Vec<(String, String)> {
+        solution.installs.iter().map(|p| (p.name.clone(), p.version.clone())).collect()
+    }
+
+    fn pairs(packages: &[(&str, &str)]) -> Vec<(String, String)> {
+        packages.iter().map(|(name, version)| (name.to_string(), version.to_string())).collect()
+    }
+
+    fn unsatisfiable(result: Result<Solution>) -> Unsatisfiable {
+        result.unwrap_err().downcast::<Unsatisfiable>().unwrap()
+    }
+
+    const ALTERNATIVES: &str = "\
+Package: app
+Version: 1
+Architecture: all
+Depends: missing | libb (>= 2) | libc
+
+Package: libb
+Version: 1
+Architecture: all
+
+Package: libb
+Version: 2
+Architecture: all
+
+Package: libc
+Version: 1
+Architecture: all
+";
+
+    #[test]
+    fn picks_the_first_alternative_that_can_be_met() {
+        let index = index(ALTERNATIVES);
+        let (installed, held) = (HashMap::new(), HashSet::new());
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let solution = solver.solve(&request("app"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("libb", "2"), ("app", "1")]));
+        assert!(solution.removals.is_empty());
+        assert_eq!(solution.causes["libb"], Cause::Dependency {
+            package: "app".to_string(),
+            version: "1".to_string(),
+            relation: "missing | libb (>= 2) | libc".to_string(),
+        });
+    }
+
+    #[test]
+    fn falls_back_to_a_later_alternative() {
+        let index = index(ALTERNATIVES);
+        let installed = installed(&index, &[("libb", "1")]);
+        let held = HashSet::from(["libb".to_string()]);
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let solution = solver.solve(&request("app"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("libc", "1"), ("app", "1")]));
+    }
+
+    #[test]
+    fn meets_every_bound_of_a_versioned_range() {
+        let index = index("\
+Package: app
+Version: 1
+Architecture: all
+Depends: lib (>= 2), lib (<< 3)
+
+Package: lib
+Version: 1
+Architecture: all
+
+Package: lib
+Version: 2.5
+Architecture: all
+
+Package: lib
+Version: 3
+Architecture: all
+");
+        let (installed, held) = (HashMap::new(), HashSet::new());
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let solution = solver.solve(&request("app"), &[]).unwrap();
+        assert_eq!(versions(&solution), pairs(&[("lib", "2.5"), ("app", "1")]));
+
+        let solution = solver.solve(&request("lib (>> 2.5)"), &[]).unwrap();
+        assert_eq!(versions(&solution), pairs(&[("lib", "3")]));
+    }
+
+    const HELD: &str = "\
+Package: app
+Version: 1
+Architecture: all
+Depends: lib (>= 2)
+
+Package: lib
+Version: 1
+Architecture: all
+
+Package: lib
+Version: 2
+Architecture: all
+";
+
+    #[test]
+    fn upgrades_an_installed_dependency_that_is_too_old() {
+        let index = index(HELD);
+        let installed = installed(&index, &[("lib", "1")]);
+        let held = HashSet::new();
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let solution = solver.solve(&request("app"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("lib", "2"), ("app", "1")]));
+        assert_eq!(solution.reasons["lib"], "app 1 needs lib (>= 2)");
+    }
+
+    #[test]
+    fn held_packages_block_a_solution() {
+        let index = index(HELD);
+        let installed = installed(&index, &[("lib", "1")]);
+        let held = HashSet::from(["lib".to_string()]);
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let error = unsatisfiable(solver.solve(&request("app"), &[]));
+
+        let failure = error.failures.iter()
+            .find(|f| matches!(&f.requirement, Requirement::Dependency { package, .. } if package == "app"))
+            .expect("app's dependency fails");
+        assert!(failure.rejections.contains(&Rejection::Held { package: "lib".to_string(), version: "1".to_string() }));
+    }
+
+    const PROVIDERS: &str = "\
+Package: mailer
+Version: 1
+Architecture: all
+Depends: mail-transport-agent
+
+Package: exim4
+Version: 4.96
+Architecture: all
+Provides: mail-transport-agent
+
+Package: postfix
+Version: 3.7
+Architecture: all
+Provides: mail-transport-agent
+";
+
+    #[test]
+    fn uses_the_preferred_provider() {
+        let index = index(PROVIDERS);
+        let (installed, held) = (HashMap::new(), HashSet::new());
+        let preferred = BTreeMap::from([("mail-transport-agent".to_string(), "postfix".to_string())]);
+        let solver = Solver::new(&index, &installed, &held, Preferences::default())
+            .with_preferred_providers(&preferred);
+
+        let solution = solver.solve(&request("mailer"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("postfix", "3.7"), ("mailer", "1")]));
+    }
+
+    #[test]
+    fn keeps_an_installed_provider() {
+        let index = index(PROVIDERS);
+        let installed = installed(&index, &[("postfix", "3.7")]);
+        let held = HashSet::new();
+        let preferences = Preferences { ask_providers: true, ..Preferences::default() };
+        let solver = Solver::new(&index, &installed, &held, preferences);
+
+        let solution = solver.solve(&request("mailer"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("mailer", "1")]));
+    }
+
+    #[test]
+    fn asks_which_provider_to_use() {
+        let index = index(PROVIDERS);
+        let (installed, held) = (HashMap::new(), HashSet::new());
+        let preferences = Preferences { ask_providers: true, ..Preferences::default() };
+        let solver = Solver::new(&index, &installed, &held, preferences);
+
+        let error = solver.solve(&request("mailer"), &[]).unwrap_err();
+        let choice = error.downcast_ref::<ProviderChoice>().expect("a provider choice");
+
+        assert_eq!(choice.virtual_name, "mail-transport-agent");
+        assert_eq!(choice.providers, ["exim4", "postfix"]);
+    }
+
+    #[test]
+    fn removes_conflicting_packages_only_when_allowed() {
+        let index = index("\
+Package: new
+Version: 1
+Architecture: all
+Conflicts: old
+
+Package: old
+Version: 1
+Architecture: all
+");
+        let installed = installed(&index, &[("old", "1")]);
+        let held = HashSet::new();
+
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+        assert!(solver.solve(&request("new"), &[]).is_err());
+
+        let preferences = Preferences { allow_removals: true, ..Preferences::default() };
+        let solver = Solver::new(&index, &installed, &held, preferences);
+        let solution = solver.solve(&request("new"), &[]).unwrap();
+
+        assert_eq!(versions(&solution), pairs(&[("new", "1")]));
+        assert_eq!(solution.removals.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["old"]);
+    }
+
+    #[test]
+    fn explains_why_no_solution_exists() {
+        let index = index("\
+Package: app
+Version: 1
+Architecture: all
+Depends: missing (>= 1) | lib (>= 2)
+
+Package: lib
+Version: 1
+Architecture: all
+");
+        let (installed, held) = (HashMap::new(), HashSet::new());
+        let solver = Solver::new(&index, &installed, &held, Preferences::default());
+
+        let error = solver.solve(&request("app"), &[]).unwrap_err();
+
+        assert_eq!(error.to_string(), "Unable to satisfy dependencies:\n  \
+            app 1 needs missing (>= 1) | lib (>= 2), but missing is not available; lib is only available in version 1");
+    }
+
+    #[test]
+    fn gives_up_after_the_step_limit() {
+        let index = index(ALTERNATIVES);
+        let installed = installed(&index, &[("libb", "1")]);
+        let held = HashSet::from(["libb".to_string()]);
+        let solver = Solver::new(&index, &installed, &held, Preferences::default()).with_max_steps(1);
+
+        let error = solver.solve(&request("app"), &[]).unwrap_err();
+
+        assert!(error.to_string().contains("gave up after trying 1 assignments"));
+    }
+}
@@ -0,0 +1,524 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::utils::with_suffix;
+
+/// Directory holding the `name -> provider` symlinks the public links point to.
+pub const ALTERNATIVES_DIR: &str = "/etc/alternatives";
+
+/// Whether a group follows the highest priority provider or a chosen one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlternativeMode {
+    #[default]
+    Auto,
+    Manual,
+}
+
+/// One path that can be selected for an alternative, e.g. `/usr/bin/vim.basic`
+/// for `editor`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlternativeProvider {
+    pub path: PathBuf,
+    pub priority: i32,
+    /// Package that registered the provider, `None` if it was added by hand
+    #[serde(default)]
+    pub package: Option<String>,
+    /// Targets of the group's slave links, by slave name
+    #[serde(default)]
+    pub slaves: BTreeMap<String, PathBuf>,
+}
+
+/// A public link such as `/usr/bin/editor`, its slave links and the
+/// providers it can point to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlternativeGroup {
+    pub name: String,
+    pub link: PathBuf,
+    /// Slave links by name, e.g. `editor.1.gz` for the manual page
+    #[serde(default)]
+    pub slaves: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    pub mode: AlternativeMode,
+    /// The provider selected in manual mode
+    #[serde(default)]
+    pub selected: Option<PathBuf>,
+    pub providers: Vec<AlternativeProvider>,
+}
+
+impl AlternativeGroup {
+    /// Returns the provider the links should point to.
+    pub fn current(&self) -> Option<&AlternativeProvider> {
+        let selected = self.selected.as_ref()
+            .filter(|_| self.mode == AlternativeMode::Manual)
+            .and_then(|path| self.providers.iter().find(|p| p.path == *path));
+
+        selected.or_else(|| self.best())
+    }
+
+    /// Returns the provider with the highest priority.
+    pub fn best(&self) -> Option<&AlternativeProvider> {
+        self.providers.iter().max_by_key(|p| p.priority)
+    }
+}
+
+/// An alternative a package registers from its `alternatives` control file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlternativeSpec {
+    pub link: PathBuf,
+    pub name: String,
+    pub path: PathBuf,
+    pub priority: i32,
+    /// `(link, name, path)` of each slave link
+    #[serde(default)]
+    pub slaves: Vec<(PathBuf, String, PathBuf)>,
+}
+
+/// Parses an `alternatives` control file. Each alternative is a line
+/// `<link> <name> <path> <priority>`, followed by its slave links as
+/// `slave <link> <name> <path>` lines.
+pub fn parse_alternatives(content: &str) -> Result<Vec<AlternativeSpec>> {
+    let mut specs: Vec<AlternativeSpec> = Vec::new();
+
+    for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        match fields.as_slice() {
+            ["slave", link, name, path] => {
+                let spec = specs.last_mut()
+                    .ok_or_else(|| anyhow!("Slave link {} comes before any alternative", name))?;
+                spec.slaves.push((PathBuf::from(link), name.to_string(), PathBuf::from(path)));
+            }
+            [link, name, path, priority] => {
+                let priority = priority.parse()
+                    .map_err(|_| anyhow!("Invalid priority {} for alternative {}", priority, name))?;
+                specs.push(AlternativeSpec {
+                    link: PathBuf::from(link),
+                    name: name.to_string(),
+                    path: PathBuf::from(path),
+                    priority,
+                    slaves: Vec::new(),
+                });
+            }
+            _ => return Err(anyhow!("Invalid alternatives line: {}", line)),
+        }
+    }
+
+    Ok(specs)
+}
+
+/// The alternatives registry, kept in `alternatives.json` next to the
+/// installed package database. Changes only touch the registry; call
+/// `update_links` to bring the symlinks in line with it.
+pub struct Alternatives {
+    path: PathBuf,
+    groups: BTreeMap<String, AlternativeGroup>,
+}
+
+impl Alternatives {
+    pub const FILE_NAME: &'static str = "alternatives.json";
+
+    pub fn open(packages_dir: &Path) -> Result<Self> {
+        let path = packages_dir.join(Self::FILE_NAME);
+
+        let groups = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<AlternativeGroup> = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?;
+            list.into_iter().map(|g| (g.name.clone(), g)).collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self { path, groups })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let list: Vec<&AlternativeGroup> = self.groups.values().collect();
+        let content = serde_json::to_string_pretty(&list)?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to save alternatives: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AlternativeGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &AlternativeGroup> {
+        self.groups.values()
+    }
+
+    /// Adds a provider to a group, creating the group on first use. A
+    /// provider registered again replaces its earlier registration.
+    pub fn install(&mut self, spec: &AlternativeSpec, package: Option<&str>) -> Result<()> {
+        if let Some(other) = self.groups.values().find(|g| g.name != spec.name && g.link == spec.link) {
+            return Err(anyhow!("{} is already the link of alternative {}", spec.link.display(), other.name));
+        }
+
+        let group = self.groups.entry(spec.name.clone()).or_insert_with(|| AlternativeGroup {
+            name: spec.name.clone(),
+            link: spec.link.clone(),
+            slaves: BTreeMap::new(),
+            mode: AlternativeMode::Auto,
+            selected: None,
+            providers: Vec::new(),
+        });
+
+        if group.link != spec.link {
+            log::info!("Moving link of alternative {} from {} to {}", group.name, group.link.display(), spec.link.display());
+            group.link = spec.link.clone();
+        }
+
+        for (link, name, _) in &spec.slaves {
+            group.slaves.insert(name.clone(), link.clone());
+        }
+
+        group.providers.retain(|p| p.path != spec.path);
+        group.providers.push(AlternativeProvider {
+            path: spec.path.clone(),
+            priority: spec.priority,
+            package: package.map(|p| p.to_string()),
+            slaves: spec.slaves.iter().map(|(_, name, path)| (name.clone(), path.clone())).collect(),
+        });
+
+        Ok(())
+    }
+
+    /// Removes a provider. A manually selected provider that goes away puts
+    /// the group back into auto mode; a group without providers is dropped.
+    pub fn remove(&mut self, name: &str, path: &Path) -> Result<()> {
+        let group = self.groups.get_mut(name)
+            .ok_or_else(|| anyhow!("No alternative named {}", name))?;
+
+        let before = group.providers.len();
+        group.providers.retain(|p| p.path != path);
+        if group.providers.len() == before {
+            return Err(anyhow!("{} is not an alternative for {}", path.display(), name));
+        }
+
+        self.tidy(name);
+        Ok(())
+    }
+
+    /// Removes every provider registered by `package`.
+    pub fn remove_package(&mut self, package: &str) {
+        self.retain_package(package, |_| false);
+    }
+
+    /// Removes the providers registered by `package` that `keep` rejects.
+    pub fn retain_package<F>(&mut self, package: &str, keep: F)
+    where
+        F: Fn(&AlternativeProvider) -> bool,
+    {
+        let names: Vec<String> = self.groups.keys().cloned().collect();
+
+        for name in names {
+            if let Some(group) = self.groups.get_mut(&name) {
+                group.providers.retain(|p| p.package.as_deref() != Some(package) || keep(p));
+            }
+            self.tidy(&name);
+        }
+    }
+
+    fn tidy(&mut self, name: &str) {
+        let Some(group) = self.groups.get_mut(name) else {
+            return;
+        };
+
+        if group.mode == AlternativeMode::Manual
+            && !group.providers.iter().any(|p| Some(&p.path) == group.selected.as_ref()) {
+            log::info!("Selected provider of {} was removed, switching to auto mode", group.name);
+            group.mode = AlternativeMode::Auto;
+            group.selected = None;
+        }
+
+        // Slave links no provider offers anymore are dropped with their link
+        let offered: Vec<String> = group.providers.iter().flat_map(|p| p.slaves.keys().cloned()).collect();
+        group.slaves.retain(|slave, _| offered.contains(slave));
+    }
+
+    /// Selects a provider manually.
+    pub fn set(&mut self, name: &str, path: &Path) -> Result<()> {
+        let group = self.groups.get_mut(name)
+            .ok_or_else(|| anyhow!("No alternative named {}", name))?;
+
+        if !group.providers.iter().any(|p| p.path == path) {
+            return Err(anyhow!("{} is not an alternative for {}", path.display(), name));
+        }
+
+        group.mode = AlternativeMode::Manual;
+        group.selected = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Puts a group back into auto mode.
+    pub fn auto(&mut self, name: &str) -> Result<()> {
+        let group = self.groups.get_mut(name)
+            .ok_or_else(|| anyhow!("No alternative named {}", name))?;
+
+        group.mode = AlternativeMode::Auto;
+        group.selected = None;
+        Ok(())
+    }
+
+    /// Points every link below `root_dir` at the current provider of its
+    /// group, removes the links of groups and slaves that are gone, and drops
+    /// empty groups from the registry.
+    pub fn update_links(&mut self, root_dir: &Path, previous_links: &[(String, PathBuf)]) -> Result<()> {
+        let alternatives_dir = rooted(root_dir, Path::new(ALTERNATIVES_DIR));
+        fs::create_dir_all(&alternatives_dir)?;
+
+        for (name, link) in previous_links {
+            let still_linked = self.groups.values().any(|g| {
+                !g.providers.is_empty() && ((g.name == *name && g.link == *link) || g.slaves.get(name) == Some(link))
+            });
+            if !still_linked {
+                remove_link(&rooted(root_dir, link))?;
+                remove_link(&alternatives_dir.join(name))?;
+            }
+        }
+
+        self.groups.retain(|_, g| !g.providers.is_empty());
+
+        for group in self.groups.values() {
+            let Some(current) = group.current() else {
+                continue;
+            };
+
+            set_link(root_dir, &alternatives_dir, &group.name, &group.link, &current.path)?;
+
+            for (slave, link) in &group.slaves {
+                match current.slaves.get(slave) {
+                    Some(target) => set_link(root_dir, &alternatives_dir, slave, link, target)?,
+                    None => {
+                        remove_link(&rooted(root_dir, link))?;
+                        remove_link(&alternatives_dir.join(slave))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns every `(name, link)` pair currently managed, so links that
+    /// disappear can be cleaned up by `update_links`.
+    pub fn links(&self) -> Vec<(String, PathBuf)> {
+        self.groups.values()
+            .flat_map(|g| std::iter::once((g.name.clone(), g.link.clone()))
+                .chain(g.slaves.iter().map(|(name, link)| (name.clone(), link.clone()))))
+            .collect()
+    }
+}
+
+fn rooted(root_dir: &Path, path: &Path) -> PathBuf {
+    root_dir.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Makes `link -> /etc/alternatives/<name> -> target`, leaving real files alone.
+fn set_link(root_dir: &Path, alternatives_dir: &Path, name: &str, link: &Path, target: &Path) -> Result<()> {
+    replace_symlink(&alternatives_dir.join(name), target)?;
+
+    let public = rooted(root_dir, link);
+    match fs::symlink_metadata(&public) {
+        Ok(metadata) if !metadata.file_type().is_symlink() => {
+            log::warn!("Not replacing {} with a link, it is not a symlink", public.display());
+            Ok(())
+        }
+        _ => replace_symlink(&public, &Path::new(ALTERNATIVES_DIR).join(name)),
+    }
+}
+
+fn replace_symlink(path: &Path, target: &Path) -> Result<()> {
+    if fs::read_link(path).map(|current| current == target).unwrap_or(false) {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Create next to it and rename over, so the link never goes missing
+    let tmp_path = with_suffix(path, ".pkt-tmp");
+    let _ = fs::remove_file(&tmp_path);
+    symlink(target, &tmp_path)
+        .map_err(|e| anyhow!("Failed to create link {}: {}", path.display(), e))?;
+    fs::rename(&tmp_path, path)
+        .map_err(|e| anyhow!("Failed to create link {}: {}", path.display(), e))?;
+
+    Ok(())
+}
+
+fn remove_link(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            fs::remove_file(path).map_err(|e| anyhow!("Failed to remove link {}: {}", path.display(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, path: &str, priority: i32) -> AlternativeSpec {
+        AlternativeSpec {
+            link: PathBuf::from(format!("/usr/bin/{}", name)),
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            priority,
+            slaves: Vec::new(),
+        }
+    }
+
+    fn with_slave(mut spec: AlternativeSpec, name: &str, path: &str) -> AlternativeSpec {
+        spec.slaves.push((PathBuf::from(format!("/usr/share/man/man1/{}", name)), name.to_string(), PathBuf::from(path)));
+        spec
+    }
+
+    fn current(alternatives: &Alternatives, name: &str) -> Option<PathBuf> {
+        alternatives.get(name)?.current().map(|p| p.path.clone())
+    }
+
+    fn registry() -> (tempfile::TempDir, Alternatives) {
+        let dir = tempfile::tempdir().unwrap();
+        let alternatives = Alternatives::open(dir.path()).unwrap();
+        (dir, alternatives)
+    }
+
+    #[test]
+    fn parses_alternatives_with_slaves() {
+        let specs = parse_alternatives("\
+# editors
+/usr/bin/editor editor /usr/bin/vim.basic 50
+slave /usr/share/man/man1/editor.1.gz editor.1.gz /usr/share/man/man1/vim.1.gz
+
+/usr/bin/vi vi /usr/bin/vim.basic 30
+").unwrap();
+
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0], with_slave(
+            AlternativeSpec { link: PathBuf::from("/usr/bin/editor"), ..spec("editor", "/usr/bin/vim.basic", 50) },
+            "editor.1.gz", "/usr/share/man/man1/vim.1.gz",
+        ));
+        assert_eq!(specs[1].priority, 30);
+
+        assert!(parse_alternatives("slave /a a /b").is_err());
+        assert!(parse_alternatives("/usr/bin/vi vi /usr/bin/vim high").is_err());
+        assert!(parse_alternatives("/usr/bin/vi vi").is_err());
+    }
+
+    #[test]
+    fn follows_the_highest_priority_in_auto_mode() {
+        let (_dir, mut alternatives) = registry();
+        alternatives.install(&spec("editor", "/usr/bin/nano", 40), Some("nano")).unwrap();
+        alternatives.install(&spec("editor", "/usr/bin/vim", 50), Some("vim")).unwrap();
+        assert_eq!(current(&alternatives, "editor"), Some(PathBuf::from("/usr/bin/vim")));
+
+        // Registering again replaces the earlier registration
+        alternatives.install(&spec("editor", "/usr/bin/nano", 60), Some("nano")).unwrap();
+        assert_eq!(current(&alternatives, "editor"), Some(PathBuf::from("/usr/bin/nano")));
+        assert_eq!(alternatives.get("editor").unwrap().providers.len(), 2);
+    }
+
+    #[test]
+    fn returns_to_auto_mode_when_the_selection_goes() {
+        let (_dir, mut alternatives) = registry();
+        alternatives.install(&spec("editor", "/usr/bin/nano", 40), Some("nano")).unwrap();
+        alternatives.install(&spec("editor", "/usr/bin/vim", 50), Some("vim")).unwrap();
+
+        alternatives.set("editor", Path::new("/usr/bin/nano")).unwrap();
+        assert_eq!(alternatives.get("editor").unwrap().mode, AlternativeMode::Manual);
+        assert_eq!(current(&alternatives, "editor"), Some(PathBuf::from("/usr/bin/nano")));
+        assert!(alternatives.set("editor", Path::new("/usr/bin/emacs")).is_err());
+
+        alternatives.remove_package("nano");
+        assert_eq!(alternatives.get("editor").unwrap().mode, AlternativeMode::Auto);
+        assert_eq!(current(&alternatives, "editor"), Some(PathBuf::from("/usr/bin/vim")));
+        assert!(alternatives.remove("editor", Path::new("/usr/bin/nano")).is_err());
+    }
+
+    #[test]
+    fn refuses_a_link_owned_by_another_group() {
+        let (_dir, mut alternatives) = registry();
+        alternatives.install(&spec("editor", "/usr/bin/vim", 50), None).unwrap();
+
+        let other = AlternativeSpec { link: PathBuf::from("/usr/bin/editor"), ..spec("vi", "/usr/bin/vim", 50) };
+        assert!(alternatives.install(&other, None).is_err());
+    }
+
+    #[test]
+    fn keeps_the_registry_across_saves() {
+        let (dir, mut alternatives) = registry();
+        alternatives.install(&spec("editor", "/usr/bin/vim", 50), Some("vim")).unwrap();
+        alternatives.set("editor", Path::new("/usr/bin/vim")).unwrap();
+        alternatives.save().unwrap();
+
+        let reopened = Alternatives::open(dir.path()).unwrap();
+        let group = reopened.get("editor").unwrap();
+        assert_eq!(group.mode, AlternativeMode::Manual);
+        assert_eq!(group.providers[0].package.as_deref(), Some("vim"));
+    }
+
+    #[test]
+    fn links_the_current_provider_and_its_slaves() {
+        let (_dir, mut alternatives) = registry();
+        let root = tempfile::tempdir().unwrap();
+        let link = |path: &str| fs::read_link(root.path().join(path)).ok();
+
+        alternatives.install(&with_slave(spec("editor", "/usr/bin/vim", 50), "editor.1.gz", "/vim.1.gz"), Some("vim")).unwrap();
+        alternatives.install(&spec("editor", "/usr/bin/nano", 40), Some("nano")).unwrap();
+        alternatives.update_links(root.path(), &[]).unwrap();
+
+        assert_eq!(link("usr/bin/editor"), Some(PathBuf::from("/etc/alternatives/editor")));
+        assert_eq!(link("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/vim")));
+        assert_eq!(link("etc/alternatives/editor.1.gz"), Some(PathBuf::from("/vim.1.gz")));
+
+        // A provider without the slave drops its link
+        let previous = alternatives.links();
+        alternatives.set("editor", Path::new("/usr/bin/nano")).unwrap();
+        alternatives.update_links(root.path(), &previous).unwrap();
+        assert_eq!(link("etc/alternatives/editor"), Some(PathBuf::from("/usr/bin/nano")));
+        assert_eq!(link("usr/share/man/man1/editor.1.gz"), None);
+
+        // A group without providers loses its links
+        let previous = alternatives.links();
+        alternatives.remove_package("vim");
+        alternatives.remove_package("nano");
+        alternatives.update_links(root.path(), &previous).unwrap();
+        assert_eq!(link("usr/bin/editor"), None);
+        assert!(alternatives.get("editor").is_none());
+    }
+
+    #[test]
+    fn leaves_real_files_in_place() {
+        let (_dir, mut alternatives) = registry();
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("usr/bin")).unwrap();
+        fs::write(root.path().join("usr/bin/editor"), "#!/bin/sh\n").unwrap();
+
+        alternatives.install(&spec("editor", "/usr/bin/vim", 50), None).unwrap();
+        alternatives.update_links(root.path(), &[]).unwrap();
+
+        assert!(!fs::symlink_metadata(root.path().join("usr/bin/editor")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(root.path().join("etc/alternatives/editor")).unwrap(), PathBuf::from("/usr/bin/vim"));
+    }
+}
@@ -0,0 +1,513 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::fs;
+use std::io::Read;
+use tar::{Archive, EntryType};
+
+use crate::package::{host_architecture, parse_conffiles, parse_relations, Package, PackageInfo};
+use crate::utils::{decompress_data, CompressionFormat};
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_LEN: usize = 60;
+
+/// A package file in the Debian binary format: an `ar` archive holding a
+/// `control.tar` member with the package metadata and a `data.tar` member
+/// with the files to install.
+pub struct PackageArchive {
+    path: PathBuf,
+    control_tar: Vec<u8>,
+    data_tar: Vec<u8>,
+}
+
+impl PackageArchive {
+    pub fn open(path: &Path) -> Result<Self> {
+        let content = fs::read(path)
+            .map_err(|e| anyhow!("Failed to read package file {}: {}", path.display(), e))?;
+
+        if !content.starts_with(AR_MAGIC) {
+            return Err(anyhow!("{} is not a valid package file", path.display()));
+        }
+
+        let mut control_tar = None;
+        let mut data_tar = None;
+        let mut offset = AR_MAGIC.len();
+
+        while offset + AR_HEADER_LEN <= content.len() {
+            let header = &content[offset..offset + AR_HEADER_LEN];
+            let name = String::from_utf8_lossy(&header[0..16]).trim().trim_end_matches('/').to_string();
+            let size: usize = String::from_utf8_lossy(&header[48..58]).trim().parse()
+                .map_err(|_| anyhow!("Corrupt member header in {}", path.display()))?;
+
+            let start = offset + AR_HEADER_LEN;
+            let end = start + size;
+            if end > content.len() {
+                return Err(anyhow!("Truncated member {} in {}", name, path.display()));
+            }
+
+            if name.starts_with("control.tar") {
+                control_tar = Some(decompress_member(&name, &content[start..end])?);
+            } else if name.starts_with("data.tar") {
+                data_tar = Some(decompress_member(&name, &content[start..end])?);
+            }
+
+            // Members are padded to an even offset
+            offset = end + (size % 2);
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            control_tar: control_tar
+                .ok_or_else(|| anyhow!("{} has no control archive", path.display()))?,
+            data_tar: data_tar
+                .ok_or_else(|| anyhow!("{} has no data archive", path.display()))?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the content of a file from the control archive, such as
+    /// `control` or `conffiles`, if the package ships it.
+    pub fn control_file(&self, name: &str) -> Result<Option<String>> {
+        let mut archive = Archive::new(self.control_tar.as_slice());
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = normalize_entry_path(&entry.path()?);
+
+            if entry_path == Path::new("/").join(name) {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                return Ok(Some(content));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Parses the `control` file into its fields. Continuation lines are
+    /// joined to the field they belong to.
+    pub fn control_fields(&self) -> Result<HashMap<String, String>> {
+        let content = self.control_file("control")?
+            .ok_or_else(|| anyhow!("{} has no control file", self.path.display()))?;
+
+        let mut fields = HashMap::new();
+        let mut current: Option<String> = None;
+
+        for line in content.lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some(value) = current.as_ref().and_then(|key| fields.get_mut(key)) {
+                    let value: &mut String = value;
+                    value.push('\n');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+
+            if let Some(pos) = line.find(':') {
+                let key = line[..pos].trim().to_string();
+                fields.insert(key.clone(), line[pos + 1..].trim().to_string());
+                current = Some(key);
+            }
+        }
+
+        Ok(fields)
+    }
+
+    /// Builds the package metadata from the `control` file, for packages that
+    /// do not come from a repository index.
+    pub fn package(&self) -> Result<Package> {
+        let fields = self.control_fields()?;
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let list = |name: &str| -> Vec<String> {
+            fields.get(name)
+                .map(|value| value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+                .unwrap_or_default()
+        };
+
+        let name = fields.get("Package")
+            .ok_or_else(|| anyhow!("{} has no Package field", self.path.display()))?;
+        let version = fields.get("Version")
+            .ok_or_else(|| anyhow!("{} has no Version field", self.path.display()))?;
+
+        let mut info = PackageInfo::new(name.clone(), version.clone(), field("Architecture"), field("Description"));
+        info.maintainer = field("Maintainer");
+        info.homepage = fields.get("Homepage").cloned();
+        info.section = field("Section");
+        info.priority = field("Priority");
+        info.size = fs::metadata(&self.path)?.len();
+        // Installed-Size is given in KiB
+        info.installed_size = field("Installed-Size").parse::<u64>().unwrap_or(0) * 1024;
+
+        let mut package = Package::new(info, PathBuf::new());
+        package.pre_depends = parse_relations(&field("Pre-Depends"), host_architecture());
+        package.dependencies = package.pre_depends.iter().cloned()
+            .chain(parse_relations(&field("Depends"), host_architecture()))
+            .collect();
+        package.recommends = parse_relations(&field("Recommends"), host_architecture());
+        package.suggests = parse_relations(&field("Suggests"), host_architecture());
+        package.conflicts = list("Conflicts");
+        package.breaks = list("Breaks");
+        package.provides = list("Provides");
+        package.replaces = list("Replaces");
+
+        Ok(package)
+    }
+
+    /// Returns the conffiles the package declares in its control archive.
+    pub fn conffiles(&self) -> Result<Vec<PathBuf>> {
+        Ok(self.control_file("conffiles")?
+            .map(|content| parse_conffiles(&content))
+            .unwrap_or_default())
+    }
+
+    /// Reads a single regular file from the data archive.
+    pub fn data_file(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        let mut archive = Archive::new(self.data_tar.as_slice());
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() == EntryType::Regular
+                && normalize_entry_path(&entry.path()?) == path {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                return Ok(Some(content));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Lists the paths the package installs, excluding directories, as
+    /// absolute paths relative to the installation root.
+    pub fn data_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut archive = Archive::new(self.data_tar.as_slice());
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type() != EntryType::Directory {
+                paths.push(normalize_entry_path(&entry.path()?));
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Lists the directories the package ships, with their permission bits,
+    /// as absolute paths relative to the installation root.
+    pub fn data_dirs(&self) -> Result<Vec<(PathBuf, u32)>> {
+        let mut dirs = Vec::new();
+        let mut archive = Archive::new(self.data_tar.as_slice());
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let path = normalize_entry_path(&entry.path()?);
+            if entry.header().entry_type() == EntryType::Directory && path != Path::new("/") {
+                dirs.push((path, entry.header().mode()? & 0o7777));
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    /// Lists the files that would be installed setuid or setgid.
+    pub fn setuid_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut archive = Archive::new(self.data_tar.as_slice());
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type() != EntryType::Directory && entry.header().mode()? & 0o6000 != 0 {
+                paths.push(normalize_entry_path(&entry.path()?));
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Unpacks the data archive below `target_dir` and returns the installed
+    /// paths, excluding directories. `destination` maps each archive path to
+    /// the path actually written, or `None` to leave the file on disk alone.
+    pub fn unpack_data<F>(&self, target_dir: &Path, mut destination: F) -> Result<Vec<PathBuf>>
+    where
+        F: FnMut(&Path) -> Option<PathBuf>,
+    {
+        let mut files = Vec::new();
+        let mut archive = Archive::new(self.data_tar.as_slice());
+        archive.set_preserve_permissions(true);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = normalize_entry_path(&entry.path()?);
+
+            if entry.header().entry_type() == EntryType::Directory {
+                check_no_symlinks(target_dir, &entry_path)?;
+                fs::create_dir_all(target_dir.join(entry_path.strip_prefix("/")?))?;
+                continue;
+            }
+
+            files.push(entry_path.clone());
+            let destination = match destination(&entry_path) {
+                Some(path) => {
+                    check_no_symlinks(target_dir, &path)?;
+                    target_dir.join(path.strip_prefix("/")?)
+                }
+                None => continue,
+            };
+
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Replace a link an earlier entry left here instead of writing through it
+            if fs::symlink_metadata(&destination).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+                fs::remove_file(&destination)?;
+            }
+
+            // Hard link targets are archive paths, so resolve them against the target
+            if entry.header().entry_type() == EntryType::Link {
+                let link_name = normalize_entry_path(&entry.link_name()?
+                    .ok_or_else(|| anyhow!("Hard link {} has no target", entry_path.display()))?);
+                check_no_symlinks(target_dir, &link_name)?;
+                let source = target_dir.join(link_name.strip_prefix("/")?);
+                if fs::symlink_metadata(&source).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+                    return Err(anyhow!("Refusing to hard link {} to the symlink {}", entry_path.display(), link_name.display()));
+                }
+                if destination.exists() {
+                    fs::remove_file(&destination)?;
+                }
+                fs::hard_link(&source, &destination)?;
+            } else {
+                entry.unpack(&destination)
+                    .map_err(|e| anyhow!("Failed to unpack {}: {}", entry_path.display(), e))?;
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+fn decompress_member(name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    if name.ends_with(".zst") || name.ends_with(".bz2") {
+        return Err(anyhow!("Unsupported compression for package member {}", name));
+    }
+
+    decompress_data(data, CompressionFormat::from_extension(Path::new(name)))
+}
+
+/// Fails if a directory on the way to `path` below `target_dir` is a
+/// symlink. Such a link can only come from an earlier entry of the archive,
+/// and following it would write outside of `target_dir`.
+fn check_no_symlinks(target_dir: &Path, path: &Path) -> Result<()> {
+    let mut directory = PathBuf::from("/");
+
+    for component in path.parent().map(|p| p.components()).into_iter().flatten() {
+        if let Component::Normal(part) = component {
+            directory.push(part);
+            let on_disk = target_dir.join(directory.strip_prefix("/")?);
+            if fs::symlink_metadata(&on_disk).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+                return Err(anyhow!("Refusing to unpack {}, {} is a symlink", path.display(), directory.display()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns a tar entry path like `./usr/bin/foo` into `/usr/bin/foo`,
+/// dropping any component that would escape the installation root.
+fn normalize_entry_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+
+    for component in path.components() {
+        if let Component::Normal(part) = component {
+            normalized.push(part);
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tar::{Builder, Header};
+
+    /// Mode of a symlink entry for `build_deb`
+    pub(crate) const SYMLINK: u32 = 0o120777;
+
+    /// Writes a package file to `path`. `control` lists the members of the
+    /// control archive, `data` the entries of the data archive as path,
+    /// content and mode, where no content makes a directory and a mode of
+    /// `SYMLINK` a symlink to the content.
+    pub(crate) fn build_deb(path: &Path, control: &[(&str, &str)], data: &[(&str, Option<&str>, u32)]) {
+        let tar = |entries: Vec<(String, Option<&str>, u32)>| {
+            let mut builder = Builder::new(Vec::new());
+            for (path, content, mode) in entries {
+                let mut header = Header::new_gnu();
+                header.set_mode(mode & 0o7777);
+                if mode == SYMLINK {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, path, content.unwrap()).unwrap();
+                    continue;
+                }
+                header.set_entry_type(if content.is_some() { EntryType::Regular } else { EntryType::Directory });
+                header.set_size(content.map(|c| c.len() as u64).unwrap_or(0));
+                builder.append_data(&mut header, path, content.unwrap_or("").as_bytes()).unwrap();
+            }
+            builder.into_inner().unwrap()
+        };
+
+        let control_tar = tar(control.iter().map(|(name, content)| (format!("./{}", name), Some(*content), 0o644)).collect());
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&control_tar).unwrap();
+        let control_tar = encoder.finish().unwrap();
+        let data_tar = tar(data.iter().map(|(path, content, mode)| (format!(".{}", path), *content, *mode)).collect());
+
+        let mut content = AR_MAGIC.to_vec();
+        for (name, member) in [("debian-binary", b"2.0\n".to_vec()), ("control.tar.gz", control_tar), ("data.tar", data_tar)] {
+            content.extend(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", format!("{}/", name), 0, 0, 0, 100644, member.len()).as_bytes());
+            content.extend(&member);
+            if member.len() % 2 == 1 {
+                content.push(b'\n');
+            }
+        }
+
+        fs::write(path, content).unwrap();
+    }
+
+    const CONTROL: &str = "\
+Package: hello
+Version: 2.10-3
+Architecture: amd64
+Installed-Size: 2
+Depends: libc6 (>= 2.34), foo [i386] | bar
+Provides: greeter
+Description: says hello
+ to the world
+";
+
+    fn hello(dir: &Path) -> PackageArchive {
+        let path = dir.join("hello.deb");
+        build_deb(&path, &[("control", CONTROL), ("conffiles", "/etc/hello.conf\n")], &[
+            ("/", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            ("/usr/bin/hello", Some("#!/bin/sh\necho hello\n"), 0o4755),
+            ("/etc/hello.conf", Some("greeting=hello\n"), 0o644),
+            ("/var/lib/hello", None, 0o700),
+        ]);
+        PackageArchive::open(&path).unwrap()
+    }
+
+    #[test]
+    fn reads_the_control_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = hello(dir.path());
+
+        let fields = archive.control_fields().unwrap();
+        assert_eq!(fields["Description"], "says hello\nto the world");
+
+        let package = archive.package().unwrap();
+        assert_eq!((package.name.as_str(), package.version.as_str()), ("hello", "2.10-3"));
+        assert_eq!(package.installed_size, 2048);
+        assert_eq!(package.provides, ["greeter"]);
+        assert_eq!(package.dependencies.len(), 2);
+        assert_eq!(archive.conffiles().unwrap(), [PathBuf::from("/etc/hello.conf")]);
+        assert_eq!(archive.control_file("postinst").unwrap(), None);
+    }
+
+    #[test]
+    fn lists_the_data_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = hello(dir.path());
+
+        assert_eq!(archive.data_paths().unwrap(), [PathBuf::from("/usr/bin/hello"), PathBuf::from("/etc/hello.conf")]);
+        assert_eq!(archive.data_dirs().unwrap(), [(PathBuf::from("/usr/bin"), 0o755), (PathBuf::from("/var/lib/hello"), 0o700)]);
+        assert_eq!(archive.setuid_paths().unwrap(), [PathBuf::from("/usr/bin/hello")]);
+        assert_eq!(archive.data_file(Path::new("/etc/hello.conf")).unwrap().as_deref(), Some(&b"greeting=hello\n"[..]));
+        assert_eq!(archive.data_file(Path::new("/usr/bin")).unwrap(), None);
+    }
+
+    #[test]
+    fn unpacks_to_the_chosen_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = hello(dir.path());
+        let target = dir.path().join("root");
+
+        let files = archive.unpack_data(&target, |path| {
+            (path != Path::new("/etc/hello.conf")).then(|| path.to_path_buf())
+        }).unwrap();
+
+        assert_eq!(files, [PathBuf::from("/usr/bin/hello"), PathBuf::from("/etc/hello.conf")]);
+        assert_eq!(fs::read_to_string(target.join("usr/bin/hello")).unwrap(), "#!/bin/sh\necho hello\n");
+        assert!(!target.join("etc/hello.conf").exists());
+        assert!(target.join("var/lib/hello").is_dir());
+    }
+
+    #[test]
+    fn refuses_to_write_through_symlinks_of_the_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        let outside = outside.to_str().unwrap();
+
+        for data in [
+            &[("/usr/lib", Some(outside), SYMLINK), ("/usr/lib/x", Some("owned"), 0o644)][..],
+            &[("/usr/lib", Some(outside), SYMLINK), ("/usr/lib/sub", None, 0o755)][..],
+            &[("/usr/lib", Some("../../outside"), SYMLINK), ("/usr/lib/x", Some("owned"), 0o644)][..],
+        ] {
+            let path = dir.path().join("evil.deb");
+            build_deb(&path, &[("control", CONTROL)], data);
+            let target = dir.path().join("root");
+
+            let result = PackageArchive::open(&path).unwrap().unpack_data(&target, |path| Some(path.to_path_buf()));
+
+            assert!(result.is_err());
+            assert_eq!(fs::read_dir(dir.path().join("outside")).unwrap().count(), 0);
+            fs::remove_dir_all(&target).unwrap();
+        }
+    }
+
+    #[test]
+    fn replaces_symlinks_of_the_package_with_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("passwd"), "root").unwrap();
+        let path = dir.path().join("links.deb");
+        build_deb(&path, &[("control", CONTROL)], &[
+            ("/usr/bin/hello", Some(dir.path().join("passwd").to_str().unwrap()), SYMLINK),
+            ("/usr/bin/hello", Some("hello"), 0o755),
+            ("/usr/bin/hi", Some("hello"), SYMLINK),
+        ]);
+        let target = dir.path().join("root");
+
+        PackageArchive::open(&path).unwrap().unpack_data(&target, |path| Some(path.to_path_buf())).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.path().join("passwd")).unwrap(), "root");
+        assert_eq!(fs::read_to_string(target.join("usr/bin/hello")).unwrap(), "hello");
+        assert_eq!(fs::read_link(target.join("usr/bin/hi")).unwrap(), Path::new("hello"));
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bogus.deb");
+
+        fs::write(&path, "not a package").unwrap();
+        assert!(PackageArchive::open(&path).is_err());
+
+        fs::write(&path, AR_MAGIC).unwrap();
+        assert!(PackageArchive::open(&path).is_err());
+    }
+
+    #[test]
+    fn keeps_entry_paths_inside_the_root() {
+        assert_eq!(normalize_entry_path(Path::new("./usr/bin/foo")), PathBuf::from("/usr/bin/foo"));
+        assert_eq!(normalize_entry_path(Path::new("../../etc/shadow")), PathBuf::from("/etc/shadow"));
+        assert_eq!(normalize_entry_path(Path::new(".")), PathBuf::from("/"));
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use crate::utils::with_suffix;
+
+/// A configuration file shipped by a package, together with the hash of the
+/// version the package installed so local edits can be detected later.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conffile {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+/// What to do with a conffile when a package is installed over an existing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConffileAction {
+    /// Write the package version in place
+    Install,
+    /// Leave the user's file alone, or missing if the user deleted it
+    Keep,
+    /// Keep the user's file and write the package version as `*.pkt-new`
+    InstallNew,
+    /// Move the user's file to `*.pkt-old` and write the package version in place
+    ReplaceOld,
+}
+
+/// The outcome of planning every conffile of an incoming package.
+#[derive(Debug, Default)]
+pub struct ConffilePlan {
+    pub conffiles: Vec<Conffile>,
+    /// Conffiles not written in place, mapped to where the package version
+    /// goes instead (`None` to leave the user's file untouched)
+    pub redirects: HashMap<PathBuf, Option<PathBuf>>,
+    /// Conffiles whose current version is moved to `*.pkt-old`
+    pub replace_old: Vec<PathBuf>,
+}
+
+impl ConffilePlan {
+    /// Returns where a file from the package is written, or `None` to skip it.
+    pub fn destination(&self, path: &Path) -> Option<PathBuf> {
+        self.redirects.get(path).cloned().unwrap_or_else(|| Some(path.to_path_buf()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConffileStatus {
+    Unmodified,
+    Modified,
+    Missing,
+}
+
+impl Conffile {
+    pub fn new(path: PathBuf, hash: String) -> Self {
+        Self { path, hash }
+    }
+
+    pub fn new_path(path: &Path) -> PathBuf {
+        with_suffix(path, ".pkt-new")
+    }
+
+    pub fn old_path(path: &Path) -> PathBuf {
+        with_suffix(path, ".pkt-old")
+    }
+
+    /// Compares the file at `on_disk` against the hash recorded at install time.
+    pub fn status(&self, on_disk: &Path) -> Result<ConffileStatus> {
+        if !on_disk.exists() {
+            return Ok(ConffileStatus::Missing);
+        }
+
+        if hash_file(on_disk)? == self.hash {
+            Ok(ConffileStatus::Unmodified)
+        } else {
+            Ok(ConffileStatus::Modified)
+        }
+    }
+}
+
+/// Parses the `conffiles` control file, one absolute path per line.
+pub fn parse_conffiles(content: &str) -> Vec<PathBuf> {
+    content.lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with('/'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Decides how to install a conffile given the hash of the file currently on
+/// disk, the hash the previous package version installed and the hash of the
+/// incoming version. A conffile the user deleted stays deleted.
+pub fn plan_conffile(
+    current: Option<&str>,
+    original: Option<&str>,
+    new: &str,
+    prefer_new: bool,
+) -> ConffileAction {
+    let current = match (current, original) {
+        (Some(current), _) => current,
+        (None, Some(_)) => return ConffileAction::Keep,
+        (None, None) => return ConffileAction::Install,
+    };
+
+    if current == new || Some(current) == original {
+        return ConffileAction::Install;
+    }
+
+    if original == Some(new) {
+        return ConffileAction::Keep;
+    }
+
+    if prefer_new {
+        ConffileAction::ReplaceOld
+    } else {
+        ConffileAction::InstallNew
+    }
+}
+
+pub fn hash_data(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    Ok(hash_data(&fs::read(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installs_unmodified_and_new_conffiles() {
+        assert_eq!(plan_conffile(None, None, "b", false), ConffileAction::Install);
+        assert_eq!(plan_conffile(Some("a"), Some("a"), "b", false), ConffileAction::Install);
+        assert_eq!(plan_conffile(Some("b"), Some("a"), "b", false), ConffileAction::Install);
+    }
+
+    #[test]
+    fn keeps_changes_the_package_does_not_conflict_with() {
+        assert_eq!(plan_conffile(Some("user"), Some("a"), "a", false), ConffileAction::Keep);
+        assert_eq!(plan_conffile(Some("user"), Some("a"), "a", true), ConffileAction::Keep);
+    }
+
+    #[test]
+    fn keeps_deleted_conffiles_deleted() {
+        assert_eq!(plan_conffile(None, Some("a"), "a", false), ConffileAction::Keep);
+        assert_eq!(plan_conffile(None, Some("a"), "b", false), ConffileAction::Keep);
+        assert_eq!(plan_conffile(None, Some("a"), "b", true), ConffileAction::Keep);
+    }
+
+    #[test]
+    fn asks_where_both_sides_changed() {
+        assert_eq!(plan_conffile(Some("user"), Some("a"), "b", false), ConffileAction::InstallNew);
+        assert_eq!(plan_conffile(Some("user"), Some("a"), "b", true), ConffileAction::ReplaceOld);
+        assert_eq!(plan_conffile(Some("user"), None, "b", false), ConffileAction::InstallNew);
+    }
+
+    #[test]
+    fn parses_conffile_lists() {
+        assert_eq!(parse_conffiles("/etc/foo.conf\n\n  /etc/bar/baz  \nrelative\n"),
+            [PathBuf::from("/etc/foo.conf"), PathBuf::from("/etc/bar/baz")]);
+    }
+}
@@ -0,0 +1,262 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::Write;
+
+use crate::package::Package;
+use crate::utils::with_suffix;
+
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseFile {
+    schema_version: u32,
+    packages: BTreeMap<String, Package>,
+}
+
+/// The record of every installed package, stored as a single JSON file.
+/// Lookups by package name and by owned file path are indexed in memory.
+pub struct InstalledDatabase {
+    path: PathBuf,
+    packages: BTreeMap<String, Package>,
+    files: HashMap<PathBuf, String>,
+}
+
+impl InstalledDatabase {
+    pub const FILE_NAME: &'static str = "installed.json";
+
+    /// Opens the database in `packages_dir`. When it does not exist yet, the
+    /// per-version `package.json` records of older releases are imported.
+    pub fn open(packages_dir: &Path) -> Result<Self> {
+        let path = packages_dir.join(Self::FILE_NAME);
+
+        let packages = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read installed package database: {}", e))?;
+            let file: DatabaseFile = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse installed package database: {}", e))?;
+
+            if file.schema_version > SCHEMA_VERSION {
+                return Err(anyhow!("Installed package database has schema version {}, this pkt supports up to {}",
+                    file.schema_version, SCHEMA_VERSION));
+            }
+
+            file.packages
+        } else {
+            import_package_records(packages_dir)?
+        };
+
+        let mut database = Self {
+            path,
+            packages: BTreeMap::new(),
+            files: HashMap::new(),
+        };
+
+        for (_, package) in packages {
+            database.insert(package);
+        }
+
+        Ok(database)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the database to a temporary file and renames it over the old
+    /// one, so readers never see a partially written database.
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = DatabaseFile {
+            schema_version: SCHEMA_VERSION,
+            packages: self.packages.clone(),
+        };
+        let content = serde_json::to_string_pretty(&file)?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to save installed package database: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, package_name: &str) -> Option<&Package> {
+        self.packages.get(package_name)
+    }
+
+    /// Returns all installed packages ordered by name.
+    pub fn packages(&self) -> impl Iterator<Item = &Package> {
+        self.packages.values()
+    }
+
+    /// Returns the package that owns `path`, if any.
+    pub fn owner_of(&self, path: &Path) -> Option<&Package> {
+        self.files.get(path).and_then(|name| self.packages.get(name))
+    }
+
+    /// Adds or replaces the record of a package.
+    pub fn insert(&mut self, package: Package) {
+        self.remove(&package.name);
+
+        for file in &package.files {
+            self.files.insert(file.clone(), package.name.clone());
+        }
+
+        self.packages.insert(package.name.clone(), package);
+    }
+
+    pub fn remove(&mut self, package_name: &str) -> Option<Package> {
+        let package = self.packages.remove(package_name)?;
+
+        for file in &package.files {
+            if self.files.get(file).map(|owner| owner == package_name).unwrap_or(false) {
+                self.files.remove(file);
+            }
+        }
+
+        Some(package)
+    }
+
+    /// Removes files from a package's record, e.g. when another package takes them over.
+    pub fn disown_files(&mut self, package_name: &str, paths: &[PathBuf]) {
+        if let Some(mut package) = self.packages.get(package_name).cloned() {
+            package.files.retain(|f| !paths.contains(f));
+            self.insert(package);
+        }
+    }
+}
+
+/// Reads the `<name>/<version>/package.json` records written before the
+/// database existed, keeping the most recently installed version of each.
+fn import_package_records(packages_dir: &Path) -> Result<BTreeMap<String, Package>> {
+    let mut packages: BTreeMap<String, Package> = BTreeMap::new();
+
+    if !packages_dir.exists() {
+        return Ok(packages);
+    }
+
+    for entry in fs::read_dir(packages_dir)? {
+        let package_dir = entry?.path();
+        if !package_dir.is_dir() {
+            continue;
+        }
+
+        for version in fs::read_dir(&package_dir)? {
+            let metadata_path = version?.path().join("package.json");
+            if !metadata_path.exists() {
+                continue;
+            }
+
+            let package = Package::load_from_file(&metadata_path)?;
+            let newer = packages.get(&package.name)
+                .map(|existing| package.install_date > existing.install_date)
+                .unwrap_or(true);
+
+            if newer {
+                packages.insert(package.name.clone(), package);
+            }
+        }
+    }
+
+    Ok(packages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::PackageInfo;
+    use std::time::{Duration, SystemTime};
+
+    fn package(name: &str, version: &str, files: &[&str]) -> Package {
+        let info = PackageInfo::new(name.to_string(), version.to_string(), "amd64".to_string(), String::new());
+        let mut package = Package::new(info, PathBuf::from("/var/lib/persianpkt/packages").join(name).join(version));
+        package.files = files.iter().map(PathBuf::from).collect();
+        package
+    }
+
+    #[test]
+    fn saves_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = InstalledDatabase::open(dir.path()).unwrap();
+        assert_eq!(database.packages().count(), 0);
+
+        database.insert(package("hello", "1.0", &["/usr/bin/hello"]));
+        database.insert(package("bash", "5.2", &["/bin/bash"]));
+        database.save().unwrap();
+
+        assert!(!with_suffix(database.path(), ".tmp").exists());
+        let content: serde_json::Value = serde_json::from_str(&fs::read_to_string(database.path()).unwrap()).unwrap();
+        assert_eq!(content["schema_version"], SCHEMA_VERSION);
+
+        let reopened = InstalledDatabase::open(dir.path()).unwrap();
+        assert_eq!(reopened.packages().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["bash", "hello"]);
+        assert_eq!(reopened.get("hello").unwrap().version, "1.0");
+        assert_eq!(reopened.owner_of(Path::new("/bin/bash")).unwrap().name, "bash");
+    }
+
+    #[test]
+    fn refuses_newer_schema_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = format!("{{\"schema_version\": {}, \"packages\": {{}}}}", SCHEMA_VERSION + 1);
+        fs::write(dir.path().join(InstalledDatabase::FILE_NAME), content).unwrap();
+
+        assert!(InstalledDatabase::open(dir.path()).is_err());
+    }
+
+    #[test]
+    fn imports_legacy_package_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = |package: &Package, age: u64| {
+            let mut package = package.clone();
+            package.install_date = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 - age);
+            let version_dir = dir.path().join(&package.name).join(&package.version);
+            fs::create_dir_all(&version_dir).unwrap();
+            package.save_to_file(&version_dir.join("package.json")).unwrap();
+        };
+        record(&package("hello", "1.0", &["/usr/bin/hello"]), 100);
+        record(&package("hello", "2.0", &["/usr/bin/hello", "/usr/share/man/man1/hello.1"]), 10);
+        record(&package("bash", "5.2", &["/bin/bash"]), 50);
+        fs::create_dir_all(dir.path().join("empty/1.0")).unwrap();
+        fs::write(dir.path().join("stray"), "").unwrap();
+
+        let database = InstalledDatabase::open(dir.path()).unwrap();
+
+        assert_eq!(database.packages().count(), 2);
+        assert_eq!(database.get("hello").unwrap().version, "2.0");
+        assert_eq!(database.owner_of(Path::new("/usr/share/man/man1/hello.1")).unwrap().name, "hello");
+        assert!(!database.path().exists());
+    }
+
+    #[test]
+    fn indexes_owned_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut database = InstalledDatabase::open(dir.path()).unwrap();
+        database.insert(package("hello", "1.0", &["/usr/bin/hello", "/usr/share/doc/hello"]));
+        database.insert(package("greeter", "1.0", &["/usr/bin/greet", "/usr/share/doc/hello"]));
+
+        assert_eq!(database.owner_of(Path::new("/usr/bin/hello")).unwrap().name, "hello");
+        assert_eq!(database.owner_of(Path::new("/usr/share/doc/hello")).unwrap().name, "greeter");
+        assert!(database.owner_of(Path::new("/usr/bin/other")).is_none());
+
+        // Replacing a record drops the files the new version no longer ships
+        database.insert(package("hello", "2.0", &["/usr/bin/hello2"]));
+        assert!(database.owner_of(Path::new("/usr/bin/hello")).is_none());
+        assert_eq!(database.owner_of(Path::new("/usr/bin/hello2")).unwrap().version, "2.0");
+
+        database.disown_files("greeter", &[PathBuf::from("/usr/share/doc/hello")]);
+        assert!(database.owner_of(Path::new("/usr/share/doc/hello")).is_none());
+        assert_eq!(database.get("greeter").unwrap().files, [PathBuf::from("/usr/bin/greet")]);
+
+        assert!(database.remove("greeter").is_some());
+        assert!(database.owner_of(Path::new("/usr/bin/greet")).is_none());
+        assert!(database.remove("greeter").is_none());
+    }
+}
@@ -1,11 +1,31 @@
+use anyhow::{Result, anyhow};
 use serde::{Serialize, Deserialize};
-use semver::{Version, VersionReq};
+use std::fmt;
 
+use crate::package::{compare_versions, VersionOperator};
+
+/// One relation of a relationship field such as `Depends`, in the Debian
+/// grammar: `name[:qualifier] [(op version)] [[arch ...]] [<profile ...>]`.
+/// A relation written as `a | b` keeps the other choices in `alternatives`,
+/// so it is satisfied by any one of them.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackageDependency {
     pub name: String,
+    /// The requirement as `op version`, e.g. `>= 1.2`
     pub version_req: Option<String>,
     pub is_optional: bool,
+    /// `any`, `native` or an architecture, from `name:qualifier`
+    #[serde(default)]
+    pub arch_qualifier: Option<String>,
+    /// Architectures the relation is limited to, `!` negating one
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    /// Build profile formulas, e.g. `[["!nocheck"]]` for `<!nocheck>`; the
+    /// relation applies if any one of them holds
+    #[serde(default)]
+    pub profiles: Vec<Vec<String>>,
+    #[serde(default)]
+    pub alternatives: Vec<PackageDependency>,
 }
 
 impl PackageDependency {
@@ -14,56 +34,367 @@ impl PackageDependency {
             name,
             version_req: None,
             is_optional: false,
+            arch_qualifier: None,
+            architectures: Vec::new(),
+            profiles: Vec::new(),
+            alternatives: Vec::new(),
         }
     }
 
     pub fn with_version_req(name: String, version_req: String) -> Self {
         Self {
-            name,
             version_req: Some(version_req),
-            is_optional: false,
+            ..Self::new(name)
         }
     }
 
-    pub fn optional(mut self) -> Self {
-        self.is_optional = true;
-        self
+    /// Whether `version` meets the version requirement, compared by Debian
+    /// policy. An unparsable requirement is never met.
+    pub fn satisfies(&self, version: &str) -> bool {
+        match &self.version_req {
+            Some(req_str) => match VersionOperator::parse_requirement(req_str) {
+                Ok((operator, required)) => operator.matches(compare_versions(version, &required)),
+                Err(_) => false,
+            },
+            None => true,
+        }
     }
 
-    pub fn satisfies(&self, version: &str) -> bool {
-        if let Some(req_str) = &self.version_req {
-            if let (Ok(version_parsed), Ok(req_parsed)) = (Version::parse(version), VersionReq::parse(req_str)) {
-                req_parsed.matches(&version_parsed)
-            } else {
-                false
-            }
-        } else {
+    /// Returns every package that can satisfy the relation: this one first,
+    /// then the other alternatives.
+    pub fn choices(&self) -> impl Iterator<Item = &PackageDependency> {
+        std::iter::once(self).chain(self.alternatives.iter())
+    }
+
+    /// Whether one of the alternatives is package `name` at `version`.
+    #[cfg(test)]
+    pub fn satisfied_by(&self, name: &str, version: &str) -> bool {
+        self.choices().any(|choice| choice.name == name && choice.satisfies(version))
+    }
+
+    /// Whether this entry of a `Provides` field, e.g. `mail-transport-agent`
+    /// or `libfoo-abi (= 2)`, satisfies `choice`. An unversioned provide
+    /// never meets a version requirement.
+    pub fn provides(&self, choice: &PackageDependency) -> bool {
+        if self.name != choice.name {
+            return false;
+        }
+        match (&choice.version_req, self.provided_version()) {
+            (None, _) => true,
+            (Some(_), Some(version)) => choice.satisfies(version),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// The version a `Provides` entry gives its virtual package.
+    pub fn provided_version(&self) -> Option<&str> {
+        self.version_req.as_deref()?.strip_prefix("= ")
+    }
+
+    /// Whether `name` is one of the alternatives, whatever the version.
+    pub fn mentions(&self, name: &str) -> bool {
+        self.choices().any(|choice| choice.name == name)
+    }
+
+    /// Whether this choice, ignoring the other alternatives, applies on
+    /// `architecture` with no build profiles active, as when installing
+    /// binary packages.
+    pub fn applies_to(&self, architecture: &str) -> bool {
+        let arch_matches = if self.architectures.is_empty() {
             true
+        } else if self.architectures.iter().all(|a| a.starts_with('!')) {
+            !self.architectures.iter().any(|a| architecture_matches(&a[1..], architecture))
+        } else {
+            self.architectures.iter().any(|a| architecture_matches(a, architecture))
+        };
+
+        // Positive profile terms never hold, negated ones always do
+        let profiles_match = self.profiles.is_empty()
+            || self.profiles.iter().any(|formula| formula.iter().all(|term| term.starts_with('!')));
+
+        arch_matches && profiles_match
+    }
+
+    /// Drops the alternatives that do not apply on `architecture`, so that
+    /// `b | c [!amd64]` becomes `b` on amd64. Returns `None` if none of them
+    /// applies, in which case the whole relation is ignored.
+    pub fn restrict_to(&self, architecture: &str) -> Option<Self> {
+        let mut choices = self.choices()
+            .filter(|choice| choice.applies_to(architecture))
+            .map(|choice| PackageDependency { alternatives: Vec::new(), ..choice.clone() });
+
+        let mut dependency = choices.next()?;
+        dependency.alternatives = choices.collect();
+        Some(dependency)
+    }
+
+    /// Parses one relation with its `|` alternatives, e.g.
+    /// `libc6 (>= 2.36) | libc6.1 [alpha] <!nocheck>`.
+    pub fn parse(relation: &str) -> Result<Self> {
+        let mut choices = relation.split('|').map(parse_choice);
+        let mut dependency = choices.next()
+            .ok_or_else(|| anyhow!("Empty relation"))??;
+
+        for choice in choices {
+            dependency.alternatives.push(choice?);
         }
+
+        Ok(dependency)
     }
 
     pub fn from_string(dep_str: &str) -> Option<Self> {
-        let parts: Vec<&str> = dep_str.split_whitespace().collect();
-        if parts.is_empty() {
-            return None;
+        match Self::parse(dep_str) {
+            Ok(dependency) => Some(dependency),
+            Err(e) => {
+                log::warn!("Ignoring relation '{}': {}", dep_str.trim(), e);
+                None
+            }
         }
+    }
+}
+
+impl fmt::Display for PackageDependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.choices().map(format_choice).collect::<Vec<_>>().join(" | "))
+    }
+}
 
-        let name = parts[0].to_string();
-        let mut dependency = Self::new(name);
+/// The relationship fields that keep two packages from being installed
+/// together. `Breaks` only forbids the other package being configured, but
+/// planning treats both alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    Conflicts,
+    Breaks,
+}
 
-        if parts.len() > 1 {
-            let version_str = parts[1..].join(" ");
-            dependency.version_req = Some(version_str);
+impl fmt::Display for ConflictKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflicts => write!(f, "conflicts with"),
+            Self::Breaks => write!(f, "breaks"),
         }
+    }
+}
 
-        Some(dependency)
+/// The relationship fields naming packages that are useful next to a
+/// package but not needed for it to work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeakKind {
+    Recommends,
+    Suggests,
+}
+
+impl fmt::Display for WeakKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recommends => write!(f, "recommends"),
+            Self::Suggests => write!(f, "suggests"),
+        }
+    }
+}
+
+/// Parses a whole relationship field, e.g. the value of `Depends`, skipping
+/// the alternatives that do not apply on `architecture`.
+pub fn parse_relations(field: &str, architecture: &str) -> Vec<PackageDependency> {
+    field.split(',')
+        .filter(|relation| !relation.trim().is_empty())
+        .filter_map(PackageDependency::from_string)
+        .filter_map(|dependency| dependency.restrict_to(architecture))
+        .collect()
+}
+
+/// The Debian name of the architecture pkt was built for, which relations
+/// with architecture restrictions are checked against.
+pub fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "i386",
+        "aarch64" => "arm64",
+        "arm" => "armhf",
+        "powerpc64" => "ppc64el",
+        "loongarch64" => "loong64",
+        arch => arch,
     }
+}
 
-    pub fn to_string(&self) -> String {
-        if let Some(version_req) = &self.version_req {
-            format!("{} ({})", self.name, version_req)
-        } else {
-            self.name.clone()
+/// Whether an architecture restriction term such as `amd64`, `linux-any`
+/// or `any-arm64` matches the Debian `architecture`. Plain names like
+/// `amd64` stand for `linux-amd64`.
+fn architecture_matches(pattern: &str, architecture: &str) -> bool {
+    if pattern == "any" || pattern == architecture {
+        return true;
+    }
+
+    let split = |name: &str| match name.split_once('-') {
+        Some((os, cpu)) => (os.to_string(), cpu.to_string()),
+        None => ("linux".to_string(), name.to_string()),
+    };
+    let (pattern_os, pattern_cpu) = split(pattern);
+    let (os, cpu) = split(architecture);
+
+    (pattern_os == "any" || pattern_os == os) && (pattern_cpu == "any" || pattern_cpu == cpu)
+}
+
+fn parse_choice(choice: &str) -> Result<PackageDependency> {
+    let choice = choice.trim();
+    let name_end = choice.find(|c: char| c.is_whitespace() || "([<".contains(c)).unwrap_or(choice.len());
+    let (name, mut rest) = choice.split_at(name_end);
+
+    if name.is_empty() {
+        return Err(anyhow!("Relation '{}' has no package name", choice));
+    }
+
+    let mut dependency = match name.split_once(':') {
+        Some((name, qualifier)) => {
+            let mut dependency = PackageDependency::new(name.to_string());
+            dependency.arch_qualifier = Some(qualifier.to_string());
+            dependency
+        }
+        None => PackageDependency::new(name.to_string()),
+    };
+
+    loop {
+        rest = rest.trim_start();
+        let Some(open) = rest.chars().next() else {
+            break;
+        };
+
+        let close = match open {
+            '(' => ')',
+            '[' => ']',
+            '<' => '>',
+            _ => return Err(anyhow!("Unexpected '{}' in relation '{}'", rest, choice)),
+        };
+        let end = rest.find(close)
+            .ok_or_else(|| anyhow!("Missing '{}' in relation '{}'", close, choice))?;
+        let inner = rest[1..end].trim();
+
+        match open {
+            '(' => {
+                let (operator, version) = VersionOperator::parse_requirement(inner)?;
+                dependency.version_req = Some(format!("{} {}", operator, version));
+            }
+            '[' => dependency.architectures = inner.split_whitespace().map(|a| a.to_string()).collect(),
+            _ => dependency.profiles.push(inner.split_whitespace().map(|p| p.to_string()).collect()),
         }
+
+        rest = &rest[end + 1..];
+    }
+
+    Ok(dependency)
+}
+
+fn format_choice(choice: &PackageDependency) -> String {
+    let mut text = choice.name.clone();
+
+    if let Some(qualifier) = &choice.arch_qualifier {
+        text.push_str(&format!(":{}", qualifier));
+    }
+    if let Some(version_req) = &choice.version_req {
+        text.push_str(&format!(" ({})", version_req));
     }
-} 
\ No newline at end of file
+    if !choice.architectures.is_empty() {
+        text.push_str(&format!(" [{}]", choice.architectures.join(" ")));
+    }
+    for formula in &choice.profiles {
+        text.push_str(&format!(" <{}>", formula.join(" ")));
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(dependency: &PackageDependency) -> Vec<&str> {
+        dependency.choices().map(|choice| choice.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_alternatives_with_versions() {
+        let dependency = PackageDependency::parse("libc6 (>= 2.36) | libc6.1 (>>2.36) | musl").unwrap();
+
+        assert_eq!(names(&dependency), ["libc6", "libc6.1", "musl"]);
+        assert_eq!(dependency.version_req.as_deref(), Some(">= 2.36"));
+        assert_eq!(dependency.alternatives[0].version_req.as_deref(), Some(">> 2.36"));
+        assert_eq!(dependency.alternatives[1].version_req, None);
+
+        assert!(dependency.satisfied_by("libc6", "2.36-9"));
+        assert!(!dependency.satisfied_by("libc6", "2.31"));
+        assert!(!dependency.satisfied_by("libc6.1", "2.36"));
+        assert!(dependency.satisfied_by("musl", "1.2"));
+    }
+
+    #[test]
+    fn parses_qualifiers_restrictions_and_profiles() {
+        let dependency = PackageDependency::parse("python3:any (= 3.11) [amd64 arm64] <!nocheck> <cross>").unwrap();
+
+        assert_eq!(dependency.name, "python3");
+        assert_eq!(dependency.arch_qualifier.as_deref(), Some("any"));
+        assert_eq!(dependency.version_req.as_deref(), Some("= 3.11"));
+        assert_eq!(dependency.architectures, ["amd64", "arm64"]);
+        assert_eq!(dependency.profiles, [vec!["!nocheck"], vec!["cross"]]);
+    }
+
+    #[test]
+    fn rejects_malformed_relations() {
+        assert!(PackageDependency::parse("").is_err());
+        assert!(PackageDependency::parse("foo | ").is_err());
+        assert!(PackageDependency::parse("foo (>= 1").is_err());
+        assert!(PackageDependency::parse("foo (>= )").is_err());
+        assert!(PackageDependency::parse("foo bar").is_err());
+    }
+
+    #[test]
+    fn displays_in_the_parsed_grammar() {
+        let text = "libfoo:native (>= 1.0) [!i386] <!nocheck> | libbar";
+        assert_eq!(PackageDependency::parse(text).unwrap().to_string(), text);
+    }
+
+    #[test]
+    fn drops_alternatives_for_other_architectures() {
+        let relations = parse_relations("b (= 1) | c [!amd64], foo [i386] | bar, baz [i386]", "amd64");
+
+        assert_eq!(relations.len(), 2);
+        assert_eq!(names(&relations[0]), ["b"]);
+        assert_eq!(relations[0].version_req.as_deref(), Some("= 1"));
+        assert_eq!(names(&relations[1]), ["bar"]);
+    }
+
+    #[test]
+    fn matches_architecture_wildcards() {
+        let applies = |relation: &str, architecture: &str| {
+            PackageDependency::parse(relation).unwrap().applies_to(architecture)
+        };
+
+        assert!(applies("foo [any]", "arm64"));
+        assert!(applies("foo [linux-any]", "amd64"));
+        assert!(!applies("foo [linux-any]", "hurd-i386"));
+        assert!(applies("foo [any-amd64]", "amd64"));
+        assert!(applies("foo [any-i386]", "hurd-i386"));
+        assert!(!applies("foo [any-i386]", "amd64"));
+        assert!(!applies("foo [!linux-any]", "arm64"));
+        assert!(applies("foo [!any-i386]", "amd64"));
+        assert!(applies("foo [i386 amd64]", "amd64"));
+    }
+
+    #[test]
+    fn applies_with_no_build_profiles_active() {
+        let relations = parse_relations("a <!nocheck>, b <nocheck>, c <cross> <!stage1>, d <!nocheck cross>", "amd64");
+
+        assert_eq!(relations.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), ["a", "c"]);
+    }
+
+    #[test]
+    fn matches_provided_versions() {
+        let provided = PackageDependency::parse("libfoo-abi (= 2)").unwrap();
+        let unversioned = PackageDependency::parse("libfoo-abi").unwrap();
+
+        assert!(provided.provides(&PackageDependency::parse("libfoo-abi (>= 2)").unwrap()));
+        assert!(!provided.provides(&PackageDependency::parse("libfoo-abi (>> 2)").unwrap()));
+        assert!(unversioned.provides(&PackageDependency::parse("libfoo-abi").unwrap()));
+        assert!(!unversioned.provides(&PackageDependency::parse("libfoo-abi (>= 1)").unwrap()));
+    }
+}
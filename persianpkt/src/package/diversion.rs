@@ -0,0 +1,213 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::utils::with_suffix;
+
+/// A file path that packages install somewhere else, so a local or another
+/// package's version can live at the original location.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diversion {
+    pub path: PathBuf,
+    pub divert_to: PathBuf,
+    /// Package still allowed to write the original path, `None` for a local
+    /// diversion that applies to every package
+    pub package: Option<String>,
+}
+
+impl Diversion {
+    /// Whether files from `package_name` are written to `divert_to`.
+    pub fn applies_to(&self, package_name: &str) -> bool {
+        self.package.as_deref() != Some(package_name)
+    }
+}
+
+/// The diversion registry, kept in `diversions.json` next to the installed
+/// package database.
+pub struct Diversions {
+    path: PathBuf,
+    diversions: BTreeMap<PathBuf, Diversion>,
+}
+
+impl Diversions {
+    pub const FILE_NAME: &'static str = "diversions.json";
+
+    pub fn open(packages_dir: &Path) -> Result<Self> {
+        let path = packages_dir.join(Self::FILE_NAME);
+
+        let diversions = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<Diversion> = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?;
+            list.into_iter().map(|d| (d.path.clone(), d)).collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self { path, diversions })
+    }
+
+    /// Writes the registry atomically, like the installed package database.
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let list: Vec<&Diversion> = self.diversions.values().collect();
+        let content = serde_json::to_string_pretty(&list)?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to save diversions: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Diversion> {
+        self.diversions.get(path)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diversion> {
+        self.diversions.values()
+    }
+
+    /// Returns the diversion that sends other files to `divert_to`, if any.
+    pub fn diverted_to(&self, divert_to: &Path) -> Option<&Diversion> {
+        self.diversions.values().find(|d| d.divert_to == divert_to)
+    }
+
+    /// Returns where a file that `package_name` ships at `path` is written.
+    pub fn destination(&self, path: &Path, package_name: &str) -> PathBuf {
+        match self.get(path) {
+            Some(diversion) if diversion.applies_to(package_name) => diversion.divert_to.clone(),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Registers a diversion. Adding the exact same diversion again is a no-op.
+    pub fn add(&mut self, diversion: Diversion) -> Result<()> {
+        if !diversion.path.is_absolute() || !diversion.divert_to.is_absolute() {
+            return Err(anyhow!("Diversion paths must be absolute"));
+        }
+        if diversion.path == diversion.divert_to {
+            return Err(anyhow!("Cannot divert {} to itself", diversion.path.display()));
+        }
+
+        if let Some(existing) = self.get(&diversion.path) {
+            if *existing == diversion {
+                return Ok(());
+            }
+            return Err(anyhow!("{} is already diverted to {}", existing.path.display(), existing.divert_to.display()));
+        }
+        if let Some(existing) = self.diverted_to(&diversion.divert_to) {
+            return Err(anyhow!("{} is already the diversion target of {}", diversion.divert_to.display(), existing.path.display()));
+        }
+        if let Some(existing) = self.get(&diversion.divert_to) {
+            return Err(anyhow!("{} is itself diverted to {}", existing.path.display(), existing.divert_to.display()));
+        }
+        if let Some(existing) = self.diverted_to(&diversion.path) {
+            return Err(anyhow!("{} is already the diversion target of {}", diversion.path.display(), existing.path.display()));
+        }
+
+        self.diversions.insert(diversion.path.clone(), diversion);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<Diversion> {
+        self.diversions.remove(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::tests::build_deb;
+    use crate::package::{InstallRequest, PackageManager};
+
+    fn diversion(path: &str, divert_to: &str, package: Option<&str>) -> Diversion {
+        Diversion {
+            path: PathBuf::from(path),
+            divert_to: PathBuf::from(divert_to),
+            package: package.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn routes_files_of_other_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut diversions = Diversions::open(dir.path()).unwrap();
+        diversions.add(diversion("/usr/bin/ls", "/usr/bin/ls.real", Some("fancy-ls"))).unwrap();
+        diversions.add(diversion("/etc/motd", "/etc/motd.local", None)).unwrap();
+
+        assert_eq!(diversions.destination(Path::new("/usr/bin/ls"), "coreutils"), Path::new("/usr/bin/ls.real"));
+        assert_eq!(diversions.destination(Path::new("/usr/bin/ls"), "fancy-ls"), Path::new("/usr/bin/ls"));
+        assert_eq!(diversions.destination(Path::new("/etc/motd"), "base-files"), Path::new("/etc/motd.local"));
+        assert_eq!(diversions.destination(Path::new("/usr/bin/cp"), "coreutils"), Path::new("/usr/bin/cp"));
+    }
+
+    #[test]
+    fn rejects_conflicting_diversions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut diversions = Diversions::open(dir.path()).unwrap();
+        diversions.add(diversion("/usr/bin/ls", "/usr/bin/ls.real", Some("fancy-ls"))).unwrap();
+
+        assert!(diversions.add(diversion("/usr/bin/ls", "/usr/bin/ls.real", Some("fancy-ls"))).is_ok());
+        assert!(diversions.add(diversion("/usr/bin/ls", "/usr/bin/ls.other", None)).is_err());
+        assert!(diversions.add(diversion("/usr/bin/dir", "/usr/bin/ls.real", None)).is_err());
+        assert!(diversions.add(diversion("/usr/bin/vdir", "/usr/bin/ls", None)).is_err());
+        assert!(diversions.add(diversion("/usr/bin/ls.real", "/usr/bin/ls.orig", None)).is_err());
+        assert!(diversions.add(diversion("/usr/bin/cp", "/usr/bin/cp", None)).is_err());
+        assert!(diversions.add(diversion("usr/bin/cp", "/usr/bin/cp.real", None)).is_err());
+    }
+
+    #[test]
+    fn saves_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut diversions = Diversions::open(dir.path()).unwrap();
+        diversions.add(diversion("/etc/motd", "/etc/motd.local", None)).unwrap();
+        diversions.save().unwrap();
+
+        let mut reopened = Diversions::open(dir.path()).unwrap();
+        assert_eq!(reopened.iter().collect::<Vec<_>>(), [&diversion("/etc/motd", "/etc/motd.local", None)]);
+        assert!(reopened.remove(Path::new("/etc/motd")).is_some());
+        assert!(reopened.get(Path::new("/etc/motd")).is_none());
+    }
+
+    #[tokio::test]
+    async fn installs_diverted_files_at_the_diverted_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/tool"), "local").unwrap();
+
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new()).with_root_dir(root.clone());
+        manager.add_diversion(diversion("/usr/bin/tool", "/usr/bin/tool.distrib", None), true).unwrap();
+        assert!(!root.join("usr/bin/tool").exists());
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool.distrib")).unwrap(), "local");
+        fs::write(root.join("usr/bin/tool"), "mine").unwrap();
+        fs::remove_file(root.join("usr/bin/tool.distrib")).unwrap();
+
+        let deb = dir.path().join("tool.deb");
+        build_deb(&deb, &[("control", "Package: tool\nVersion: 1.0\nArchitecture: all\nDescription: tool\n")], &[
+            ("/usr", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            ("/usr/bin/tool", Some("packaged"), 0o755),
+        ]);
+        let plan = manager.plan_install(&[InstallRequest::File(deb)]).await.unwrap();
+        manager.install_packages(&plan).await.unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool.distrib")).unwrap(), "packaged");
+
+        manager.remove_diversion(Path::new("/usr/bin/tool"), false).unwrap();
+        assert!(manager.diversions().unwrap().get(Path::new("/usr/bin/tool")).is_none());
+        assert!(manager.remove_diversion(Path::new("/usr/bin/tool"), false).is_err());
+    }
+}
@@ -1,20 +1,40 @@
 use anyhow::{Result, anyhow};
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::fs;
 use reqwest::Client;
 use futures_util::StreamExt;
 use tokio::io::AsyncWriteExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::sync::Arc;
-use std::time::SystemTime;
 
-use crate::package::{Package, PackageInfo};
-use crate::repository::Repository;
+use crate::package::{
+    compare_versions, hash_data, hash_file, plan_conffile, Alternatives, Conffile, ConffileAction, ConffilePlan, ConffileStatus,
+    local_package, verify_archive, Diversion, MaintainerScript, Diversions, InstallPlan, InstallRequest, InstalledDatabase, Package, PackageArchive, PackageDependency, Removal,
+    ScriptRunner, Transaction, UpgradePlan, VersionStore,
+};
+use crate::core::{DependencyResolver, Failure, Preferences, Unsatisfiable};
+use crate::repository::{PackageIndex, Repository};
+
+/// Where user mode looks for commands to link into the user's bin directory.
+const USER_COMMAND_DIRS: &[&str] = &["/usr/local/bin", "/usr/bin", "/bin", "/usr/sbin", "/sbin"];
 
 pub struct PackageManager {
     client: Client,
     install_dir: PathBuf,
+    root_dir: PathBuf,
     repositories: Vec<Repository>,
+    index: PackageIndex,
+    triggers_dir: Option<PathBuf>,
+    prefix_dir: Option<PathBuf>,
+    user_bin_dir: Option<PathBuf>,
+    held_packages: Vec<String>,
+    preferred_providers: BTreeMap<String, String>,
+    ask_providers: bool,
+    install_recommends: bool,
+    install_suggests: bool,
+    replace_conffiles: bool,
 }
 
 impl PackageManager {
@@ -22,9 +42,129 @@ impl PackageManager {
         Self {
             client: Client::new(),
             install_dir,
+            root_dir: PathBuf::from("/"),
             repositories,
+            index: PackageIndex::default(),
+            triggers_dir: None,
+            prefix_dir: None,
+            user_bin_dir: None,
+            held_packages: Vec::new(),
+            preferred_providers: BTreeMap::new(),
+            ask_providers: false,
+            install_recommends: true,
+            install_suggests: false,
+            replace_conffiles: false,
+        }
+    }
+    
+    /// Unpack packages below `root_dir` instead of the host filesystem root.
+    pub fn with_root_dir(mut self, root_dir: PathBuf) -> Self {
+        self.root_dir = root_dir;
+        self
+    }
+    
+    /// Install the package version of modified conffiles, keeping the user's copy as `*.pkt-old`.
+    pub fn with_replace_conffiles(mut self, replace_conffiles: bool) -> Self {
+        self.replace_conffiles = replace_conffiles;
+        self
+    }
+    
+    /// Look packages up in the package lists stored by `pkt update`.
+    pub fn with_index(mut self, index: PackageIndex) -> Self {
+        self.index = index;
+        self
+    }
+    
+    pub fn index(&self) -> &PackageIndex {
+        &self.index
+    }
+    
+    /// Never change the version of these installed packages.
+    pub fn with_held_packages(mut self, held_packages: Vec<String>) -> Self {
+        self.held_packages = held_packages;
+        self
+    }
+    
+    /// Provide each virtual package with the package named for it.
+    pub fn with_preferred_providers(mut self, preferred_providers: BTreeMap<String, String>) -> Self {
+        self.preferred_providers = preferred_providers;
+        self
+    }
+    
+    pub fn prefer_provider(&mut self, virtual_name: String, package_name: String) {
+        self.preferred_providers.insert(virtual_name, package_name);
+    }
+    
+    /// Fail installs with a `ProviderChoice` error when a virtual package has
+    /// several providers and none is preferred, so the user can be asked.
+    pub fn with_ask_providers(mut self, ask_providers: bool) -> Self {
+        self.ask_providers = ask_providers;
+        self
+    }
+    
+    /// Also install what the new packages recommend, as far as possible.
+    pub fn with_install_recommends(mut self, install_recommends: bool) -> Self {
+        self.install_recommends = install_recommends;
+        self
+    }
+    
+    /// Also install what the new packages suggest, as far as possible.
+    pub fn with_install_suggests(mut self, install_suggests: bool) -> Self {
+        self.install_suggests = install_suggests;
+        self
+    }
+    
+    /// Read trigger definitions from `triggers_dir` instead of `triggers.d`
+    /// next to the installed package database.
+    pub fn with_triggers_dir(mut self, triggers_dir: PathBuf) -> Self {
+        self.triggers_dir = Some(triggers_dir);
+        self
+    }
+    
+    pub fn triggers_dir(&self) -> PathBuf {
+        self.triggers_dir.clone().unwrap_or_else(|| self.install_dir.join("triggers.d"))
+    }
+    
+    /// Link the active side-by-side versions into `prefix_dir` instead of
+    /// `prefix` next to the installed package database.
+    pub fn with_prefix_dir(mut self, prefix_dir: PathBuf) -> Self {
+        self.prefix_dir = Some(prefix_dir);
+        self
+    }
+    
+    pub fn prefix_dir(&self) -> PathBuf {
+        self.prefix_dir.clone().unwrap_or_else(|| self.install_dir.join("prefix"))
+    }
+    
+    /// Installs for an unprivileged user: packages that need root are
+    /// refused, and installed commands are linked into `bin_dir`.
+    pub fn with_user_mode(mut self, bin_dir: PathBuf) -> Self {
+        self.user_bin_dir = Some(bin_dir);
+        self
+    }
+    
+    /// Creates a runner for maintainer scripts and trigger handlers.
+    pub fn script_runner(&self) -> ScriptRunner {
+        let runner = ScriptRunner::new(self.root_dir.clone(), self.install_dir.clone());
+        if self.user_bin_dir.is_some() {
+            runner.with_user_mode()
+        } else {
+            runner
         }
     }
+    
+    pub fn install_dir(&self) -> &Path {
+        &self.install_dir
+    }
+    
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+    
+    /// Maps an absolute package path onto the installation root.
+    pub fn root_path(&self, path: &Path) -> PathBuf {
+        self.root_dir.join(path.strip_prefix("/").unwrap_or(path))
+    }
 
     pub async fn download_package(&self, package_name: &str, version: Option<&str>) -> Result<(PathBuf, Package)> {
         let package = self.find_package(package_name, version).await?;
@@ -40,15 +180,41 @@ impl PackageManager {
         // Download the package
         println!("Downloading {} version {}...", package.name, package.version);
         
-        // This is a stub for demonstration
-        // In a real implementation, we would get the URL from the package metadata
-        let url = format!("https://example.com/{}/{}", package.name, package.version);
+        let entry = self.index.find(&package.name, &package.version)
+            .ok_or_else(|| anyhow!("Package {} ({}) is not in any repository index", package.name, package.version))?;
+        let url = entry.download_url()?.to_string();
+        
+        self.download_file(&url, &download_path).await?;
         
-        let res = self.client.get(&url)
+        Ok((download_path, package))
+    }
+    
+    /// Downloads a package file given by URL, e.g. from a vendor website.
+    pub async fn download_package_file(&self, url: &str) -> Result<PathBuf> {
+        let temp_dir = self.install_dir.join("temp");
+        if !temp_dir.exists() {
+            fs::create_dir_all(&temp_dir)?;
+        }
+        
+        let file_name = url.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("package.deb");
+        let download_path = temp_dir.join(format!("{}-{}", std::process::id(), file_name));
+        
+        println!("Downloading {}...", url);
+        self.download_file(url, &download_path).await?;
+        
+        Ok(download_path)
+    }
+    
+    async fn download_file(&self, url: &str, download_path: &Path) -> Result<()> {
+        let res = self.client.get(url)
             .send()
             .await
             .map_err(|e| anyhow!("Failed to download package: {}", e))?;
             
+        if !res.status().is_success() {
+            return Err(anyhow!("Failed to download package: HTTP {}", res.status()));
+        }
+        
         let total_size = res.content_length().unwrap_or(0);
         
         let pb = ProgressBar::new(total_size);
@@ -57,7 +223,7 @@ impl PackageManager {
             .unwrap()
             .progress_chars("#>-"));
             
-        let mut file = tokio::fs::File::create(&download_path).await?;
+        let mut file = tokio::fs::File::create(download_path).await?;
         let mut downloaded: u64 = 0;
         let mut stream = res.bytes_stream();
         
@@ -68,59 +234,335 @@ impl PackageManager {
             downloaded += chunk.len() as u64;
             pb.set_position(downloaded);
         }
+        // The last write may still be in flight until flushed
+        file.flush().await?;
         
         pb.finish_with_message("Download completed");
         
-        Ok((download_path, package))
+        Ok(())
     }
     
-    pub async fn install_package(&self, package_name: &str, version: Option<&str>) -> Result<()> {
-        let (package_path, package) = self.download_package(package_name, version).await?;
-        
-        println!("Installing {} package...", package_name);
-        
-        // Create package directory
-        let install_path = self.install_dir.join(&package.name).join(&package.version);
-        
-        if !install_path.exists() {
-            fs::create_dir_all(&install_path)?;
-        }
-        
-        // Extract the package - simulated for demonstration
-        // In a real implementation, we would extract the package file
-        let installed_package = Package {
-            name: package.name.clone(),
-            version: package.version.clone(),
-            architecture: package.architecture.clone(),
-            description: package.description.clone(),
-            dependencies: package.dependencies.clone(),
-            conflicts: package.conflicts.clone(),
-            provides: package.provides.clone(),
-            replaces: package.replaces.clone(),
-            install_path: install_path.clone(),
-            files: Vec::new(), // Would be populated from the package contents
-            install_date: SystemTime::now(),
-            size: package.size,
-            installed_size: package.installed_size,
-            maintainer: package.maintainer.clone(),
-            homepage: package.homepage.clone(),
-            section: package.section.clone(),
-            priority: package.priority.clone(),
-        };
+    /// Works out what installing the requested packages changes: the
+    /// packages to install with the dependencies they are missing, and the
+    /// installed packages they replace. Nothing is changed yet.
+    pub async fn plan_install(&self, requests: &[InstallRequest]) -> Result<InstallPlan> {
+        let database = self.database()?;
+        let mut resolver = DependencyResolver::new(&self.index)
+            .with_preferences(Preferences {
+                ask_providers: self.ask_providers,
+                install_recommends: self.install_recommends,
+                install_suggests: self.install_suggests,
+                ..Preferences::default()
+            })
+            .with_held_packages(&self.held_packages)
+            .with_preferred_providers(self.preferred_providers.clone());
+        resolver.load_installed_packages(&database)?;
+    
+        let mut plan = InstallPlan::default();
+        let mut requested = Vec::new();
+        let mut virtual_names = Vec::new();
+        for request in requests {
+            let package = match request {
+                // A virtual package is resolved to one of its providers
+                InstallRequest::Repository { name, version: None }
+                    if self.index.candidates(name).is_empty() && !self.index.providers(name).is_empty() => {
+                    let virtual_package = PackageDependency::new(name.clone());
+                    match database.packages().find(|p| p.is_present() && p.provides_choice(&virtual_package)) {
+                        Some(provider) => plan.provided.push((name.clone(), provider.name.clone())),
+                        None => virtual_names.push(name.clone()),
+                    }
+                    continue;
+                }
+                InstallRequest::Repository { name, version } => self.find_package(name, version.as_deref()).await?,
+                InstallRequest::File(path) => local_package(PackageArchive::open(path)?)?.1,
+                // Package files behind a URL are only known once downloaded
+                InstallRequest::Url(url) => {
+                    let download_path = self.download_package_file(url).await?;
+                    plan.downloads.push(download_path.clone());
+                    let package = local_package(PackageArchive::open(&download_path)?)?.1;
+                    plan.requests.insert(package.name.clone(), InstallRequest::File(download_path));
+                    requested.push(package);
+                    continue;
+                }
+            };
+            plan.requests.insert(package.name.clone(), request.clone());
+            requested.push(package);
+        }
+    
+        plan.solution = resolver.resolve_dependencies(&requested, &virtual_names)?;
+    
+        for name in &virtual_names {
+            let virtual_package = PackageDependency::new(name.clone());
+            if let Some(provider) = plan.solution.installs.iter().find(|p| p.provides_choice(&virtual_package)) {
+                plan.providers.push((name.clone(), provider.name.clone()));
+                plan.requests.insert(provider.name.clone(), InstallRequest::Repository {
+                    name: provider.name.clone(),
+                    version: Some(provider.version.clone()),
+                });
+            }
+        }
+    
+        for package in &plan.solution.installs {
+            if let Some(installed) = database.get(&package.name).filter(|p| p.is_present()) {
+                plan.installed.insert(package.name.clone(), installed.clone());
+            }
+        }
+    
+        Ok(plan)
+    }
+    
+    /// Carries out an install plan in a single transaction, so either all
+    /// of its packages end up installed or the system is left as it was.
+    pub async fn install_packages(&self, plan: &InstallPlan) -> Result<Vec<Package>> {
+        let mut transaction = Transaction::new(self)?;
+    
+        for package in &plan.solution.removals {
+            transaction.add_removal(&package.name);
+        }
+    
+        // Dependencies come first, so they are also configured first
+        for package in &plan.solution.installs {
+            match plan.requests.get(&package.name) {
+                Some(request) => transaction.add_request(request.clone()),
+                None if plan.installed.contains_key(&package.name) => transaction.add_upgrade(&package.name, Some(&package.version)),
+                None => transaction.add_dependency(&package.name, Some(&package.version)),
+            }
+        }
+    
+        transaction.run().await
+    }
+    
+    /// Works out which versions installing side by side adds, without
+    /// changing anything. Their dependencies must already be installed
+    /// system-wide, as nothing is pulled in next to them.
+    pub async fn plan_side_by_side(&self, requests: &[InstallRequest]) -> Result<InstallPlan> {
+        let database = self.database()?;
+        let mut plan = InstallPlan::default();
+        
+        for request in requests {
+            let (request, package) = match request {
+                InstallRequest::Repository { name, version } => (request.clone(), self.find_package(name, version.as_deref()).await?),
+                InstallRequest::File(path) => (request.clone(), local_package(PackageArchive::open(path)?)?.1),
+                InstallRequest::Url(url) => {
+                    let download_path = self.download_package_file(url).await?;
+                    plan.downloads.push(download_path.clone());
+                    let package = local_package(PackageArchive::open(&download_path)?)?.1;
+                    (InstallRequest::File(download_path), package)
+                }
+            };
+            
+            if database.get(&package.name).map(|p| p.version == package.version).unwrap_or(false) {
+                return Err(anyhow!("{} {} is already installed system-wide", package.name, package.version));
+            }
+            
+            let missing: Vec<String> = package.dependencies.iter()
+                .filter(|d| !d.is_optional)
+                .filter(|d| !d.choices().any(|choice| database.get(&choice.name)
+                    .map(|p| p.is_present() && choice.satisfies(&p.version))
+                    .unwrap_or(false)))
+                .map(|d| d.to_string())
+                .collect();
+            if !missing.is_empty() {
+                return Err(anyhow!("{} depends on {}, which is not installed system-wide",
+                    package.name, missing.join(", ")));
+            }
+            
+            plan.requests.insert(package.name.clone(), request);
+            plan.solution.installs.push(package);
+        }
+        
+        Ok(plan)
+    }
+    
+    /// Installs the packages of a side-by-side plan next to their other
+    /// versions instead of into the root. Maintainer scripts are not run,
+    /// since they expect the files in their system locations.
+    pub async fn install_side_by_side(&self, plan: &InstallPlan) -> Result<Vec<Package>> {
+        let mut versions = self.versions()?;
+        let mut installed = Vec::new();
+        
+        for package in &plan.solution.installs {
+            let (archive, download_path) = match plan.requests.get(&package.name) {
+                Some(InstallRequest::Repository { .. }) => {
+                    let (download_path, _) = self.download_package(&package.name, Some(&package.version)).await?;
+                    let archive = PackageArchive::open(&download_path)?;
+                    verify_archive(&archive, package)?;
+                    (archive, Some(download_path))
+                }
+                Some(InstallRequest::File(path)) => (PackageArchive::open(path)?, None),
+                _ => return Err(anyhow!("No package file for {}", package.name)),
+            };
+            
+            let result = self.check_user_installable(&archive, package)
+                .and_then(|()| versions.install(&archive, package));
+            
+            if let Some(download_path) = download_path {
+                let _ = fs::remove_file(download_path);
+            }
+            
+            result?;
+            installed.push(package.clone());
+        }
         
-        // Save package metadata
-        let metadata_path = install_path.join("package.json");
-        installed_package.save_to_file(&metadata_path)?;
+        Ok(installed)
+    }
+    
+    /// Opens the registry of side-by-side versions.
+    pub fn versions(&self) -> Result<VersionStore> {
+        VersionStore::open(&self.install_dir, &self.prefix_dir())
+    }
+    
+    /// Carries out an upgrade plan in one transaction: packages to be
+    /// removed go first, then the upgrades and new dependencies are installed.
+    pub async fn upgrade_packages(&self, plan: &UpgradePlan) -> Result<Vec<Package>> {
+        let mut transaction = Transaction::new(self)?;
         
-        println!("Package {} v{} has been successfully installed", package.name, package.version);
+        for (package, _) in &plan.removals {
+            transaction.add_removal(&package.name);
+        }
         
-        // Clean up temporary files
-        fs::remove_file(package_path)?;
+        // New packages are mostly dependencies of the upgrades, so they go first
+        for package in &plan.installs {
+            transaction.add_dependency(&package.name, Some(&package.version));
+        }
         
-        Ok(())
+        for (_, candidate) in &plan.upgrades {
+            transaction.add_upgrade(&candidate.name, Some(&candidate.version));
+        }
+        
+        transaction.run().await
+    }
+    
+    /// Returns the packages removing `package_names` would remove, in order.
+    pub fn plan_removal(&self, package_names: &[String], purge: bool) -> Result<Vec<Package>> {
+        let mut removal = Removal::new(self, purge)?;
+        
+        for package_name in package_names {
+            removal.add(package_name);
+        }
+        
+        removal.plan()
+    }
+    
+    /// Removes installed packages, and with `purge` their configuration files
+    /// and the automatically installed dependencies no longer needed.
+    pub fn remove_packages(&self, package_names: &[String], purge: bool) -> Result<Vec<Package>> {
+        let mut removal = Removal::new(self, purge)?;
+        
+        for package_name in package_names {
+            removal.add(package_name);
+        }
+        
+        removal.run()
+    }
+    
+    /// Sets whether installed packages count as automatically installed,
+    /// which lets `autoremove` take them once nothing needs them.
+    pub fn mark_packages(&self, package_names: &[String], auto_installed: bool) -> Result<()> {
+        let mut database = self.database()?;
+    
+        for package_name in package_names {
+            let mut package = database.get(package_name)
+                .filter(|p| p.is_present())
+                .cloned()
+                .ok_or_else(|| anyhow!("Package '{}' is not installed", package_name))?;
+            package.auto_installed = auto_installed;
+            database.insert(package);
+        }
+    
+        database.save()
+    }
+    
+    /// Tells why each needed installed package is there: `None` for one
+    /// that is manually installed or held, otherwise the package that needs
+    /// it and the relation, on the shortest chain from such a package.
+    /// Packages are needed through `Depends`, `Pre-Depends` and `Recommends`.
+    pub fn install_reasons(&self) -> Result<HashMap<String, Option<(String, String)>>> {
+        let database = self.database()?;
+        let present: Vec<&Package> = database.packages().filter(|p| p.is_present()).collect();
+    
+        // Installed packages by their own and their provided names
+        let mut by_name: HashMap<String, Vec<&Package>> = HashMap::new();
+        for package in &present {
+            for name in std::iter::once(package.name.clone()).chain(package.provided().into_iter().map(|p| p.name)) {
+                by_name.entry(name).or_default().push(package);
+            }
+        }
+    
+        let mut reasons = HashMap::new();
+        let mut queue = VecDeque::new();
+        for package in present.iter().filter(|p| !p.auto_installed || self.held_packages.contains(&p.name)) {
+            reasons.insert(package.name.clone(), None);
+            queue.push_back(*package);
+        }
+    
+        while let Some(package) = queue.pop_front() {
+            let relations = package.pre_depends.iter().map(|r| ("pre-depends on", r))
+                .chain(package.dependencies.iter().map(|r| ("depends on", r)))
+                .chain(package.recommends.iter().map(|r| ("recommends", r)));
+    
+            // Every installed alternative is kept, whichever one was meant
+            for (label, relation) in relations {
+                for choice in relation.choices() {
+                    for other in by_name.get(&choice.name).into_iter().flatten().filter(|p| p.satisfies(choice)) {
+                        if !reasons.contains_key(&other.name) {
+                            let reason = format!("{} {} {} {}", package.name, package.version, label, relation);
+                            reasons.insert(other.name.clone(), Some((package.name.clone(), reason)));
+                            queue.push_back(*other);
+                        }
+                    }
+                }
+            }
+        }
+    
+        Ok(reasons)
+    }
+    
+    /// Returns the automatically installed packages that no manually
+    /// installed or held package needs, see `install_reasons`.
+    pub fn unneeded_packages(&self) -> Result<Vec<Package>> {
+        let reasons = self.install_reasons()?;
+        Ok(self.database()?.packages()
+            .filter(|p| p.is_present() && !reasons.contains_key(&p.name))
+            .cloned()
+            .collect())
+    }
+    
+    /// Tells what keeps a package meeting `dependency` from being installed
+    /// next to the installed packages, or `None` if nothing does.
+    pub fn installation_blockers(&self, dependency: &PackageDependency) -> Result<Option<Vec<Failure>>> {
+        let database = self.database()?;
+        let mut resolver = DependencyResolver::new(&self.index)
+            .with_preferences(Preferences { install_recommends: false, ..Preferences::default() })
+            .with_held_packages(&self.held_packages)
+            .with_preferred_providers(self.preferred_providers.clone());
+        resolver.load_installed_packages(&database)?;
+    
+        match resolver.solve(std::slice::from_ref(dependency), &[]) {
+            Ok(_) => Ok(None),
+            Err(e) => match e.downcast::<Unsatisfiable>() {
+                Ok(unsatisfiable) => Ok(Some(unsatisfiable.failures)),
+                Err(e) => Err(e),
+            },
+        }
+    }
+    
+    /// Removes the automatically installed packages nothing needs anymore.
+    pub fn autoremove(&self, purge: bool) -> Result<Vec<Package>> {
+        let package_names: Vec<String> = self.unneeded_packages()?.into_iter().map(|p| p.name).collect();
+        self.remove_packages(&package_names, purge)
     }
     
     pub async fn find_package(&self, package_name: &str, version: Option<&str>) -> Result<Package> {
+        let entry = match version {
+            Some(version) => self.index.find(package_name, version),
+            None => self.index.best_candidate(package_name),
+        };
+        
+        if let Some(entry) = entry {
+            return Ok(entry.to_package());
+        }
+        
         for repo in &self.repositories {
             if let Ok(packages) = repo.search_packages(package_name).await {
                 if !packages.is_empty() {
@@ -136,8 +578,7 @@ impl PackageManager {
                         let mut latest_package = packages[0].clone();
                         for package in packages {
                             if package.name == package_name && 
-                               semver::Version::parse(&package.version).unwrap() > 
-                               semver::Version::parse(&latest_package.version).unwrap() {
+                               compare_versions(&package.version, &latest_package.version) == Ordering::Greater {
                                 latest_package = package;
                             }
                         }
@@ -149,4 +590,581 @@ impl PackageManager {
         
         Err(anyhow!("Package {} not found", package_name))
     }
-} 
\ No newline at end of file
+    
+    /// Opens the installed package database.
+    pub fn database(&self) -> Result<InstalledDatabase> {
+        InstalledDatabase::open(&self.install_dir)
+    }
+    
+    /// Returns the records of every installed package, ordered by name.
+    pub fn installed_packages(&self) -> Result<Vec<Package>> {
+        Ok(self.database()?.packages().cloned().collect())
+    }
+    
+    /// Returns the installed record of a package, if any.
+    pub fn installed_package(&self, package_name: &str) -> Result<Option<Package>> {
+        Ok(self.database()?.get(package_name).cloned())
+    }
+    
+    /// Refuses a package that cannot be installed without root in user mode:
+    /// one with maintainer scripts or setuid/setgid files.
+    pub fn check_user_installable(&self, archive: &PackageArchive, package: &Package) -> Result<()> {
+        if self.user_bin_dir.is_none() {
+            return Ok(());
+        }
+        
+        let scripts: Vec<&str> = MaintainerScript::ALL.iter()
+            .map(|script| script.name())
+            .filter(|name| archive.control_file(name).map(|f| f.is_some()).unwrap_or(true))
+            .collect();
+        if !scripts.is_empty() {
+            return Err(anyhow!("{} cannot be installed in user mode, it has maintainer scripts that need root ({})",
+                package.name, scripts.join(", ")));
+        }
+        
+        let setuid = archive.setuid_paths()?;
+        if !setuid.is_empty() {
+            let paths: Vec<String> = setuid.iter().map(|p| p.display().to_string()).collect();
+            return Err(anyhow!("{} cannot be installed in user mode, it has setuid or setgid files ({})",
+                package.name, paths.join(", ")));
+        }
+        
+        Ok(())
+    }
+    
+    /// Links the commands installed below the root into the user's bin
+    /// directory and drops links whose command is gone. Does nothing outside
+    /// user mode.
+    pub fn sync_user_bin(&self) -> Result<()> {
+        let bin_dir = match &self.user_bin_dir {
+            Some(bin_dir) => bin_dir,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(bin_dir)?;
+        
+        for entry in fs::read_dir(bin_dir)? {
+            let link = entry?.path();
+            let stale = fs::read_link(&link)
+                .map(|target| target.starts_with(&self.root_dir) && !target.exists())
+                .unwrap_or(false);
+            if stale {
+                fs::remove_file(&link)?;
+            }
+        }
+        
+        // Earlier directories win when two hold a command of the same name
+        for dir in USER_COMMAND_DIRS {
+            let dir = self.root_path(Path::new(dir));
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            
+            for entry in entries {
+                let target = entry?.path();
+                let link = bin_dir.join(target.file_name().unwrap_or_default());
+                if target.is_dir() || fs::symlink_metadata(&link).is_ok() {
+                    continue;
+                }
+                std::os::unix::fs::symlink(&target, &link)
+                    .map_err(|e| anyhow!("Failed to link {}: {}", link.display(), e))?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Opens the diversion registry.
+    pub fn diversions(&self) -> Result<Diversions> {
+        Diversions::open(&self.install_dir)
+    }
+    
+    /// Registers a diversion. With `rename`, a file already at the original
+    /// path is moved to the diverted location.
+    pub fn add_diversion(&self, diversion: Diversion, rename: bool) -> Result<()> {
+        let mut diversions = self.diversions()?;
+        diversions.add(diversion.clone())?;
+        
+        if rename {
+            self.move_file(&diversion.path, &diversion.divert_to)?;
+        }
+        
+        diversions.save()
+    }
+    
+    /// Removes a diversion. With `rename`, the diverted file is moved back to
+    /// the original path.
+    pub fn remove_diversion(&self, path: &Path, rename: bool) -> Result<Diversion> {
+        let mut diversions = self.diversions()?;
+        let diversion = diversions.remove(path)
+            .ok_or_else(|| anyhow!("{} is not diverted", path.display()))?;
+        
+        if rename {
+            self.move_file(&diversion.divert_to, &diversion.path)?;
+        }
+        
+        diversions.save()?;
+        Ok(diversion)
+    }
+    
+    fn move_file(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (self.root_path(from), self.root_path(to));
+        if fs::symlink_metadata(&from).is_err() {
+            return Ok(());
+        }
+        if fs::symlink_metadata(&to).is_ok() {
+            return Err(anyhow!("Cannot rename {} to {}, the target already exists", from.display(), to.display()));
+        }
+        
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&from, &to)
+            .map_err(|e| anyhow!("Failed to rename {} to {}: {}", from.display(), to.display(), e))
+    }
+    
+    /// Opens the alternatives registry.
+    pub fn alternatives(&self) -> Result<Alternatives> {
+        Alternatives::open(&self.install_dir)
+    }
+    
+    /// Applies `change` to the alternatives registry, then updates the links
+    /// to match and saves it.
+    pub fn change_alternatives<F>(&self, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut Alternatives) -> Result<()>,
+    {
+        let mut alternatives = self.alternatives()?;
+        let previous_links = alternatives.links();
+        
+        change(&mut alternatives)?;
+        
+        alternatives.update_links(&self.root_dir, &previous_links)?;
+        alternatives.save()
+    }
+    
+    /// Checks the files of an installed package against the filesystem,
+    /// looking for diverted files at their diverted location. Returns each
+    /// problem path with a short description.
+    pub fn verify_package(&self, package: &Package, diversions: &Diversions) -> Result<Vec<(PathBuf, String)>> {
+        let mut problems = Vec::new();
+        
+        for path in &package.files {
+            let on_disk = self.root_path(&diversions.destination(path, &package.name));
+            let status = match package.conffiles.iter().find(|c| c.path == *path) {
+                Some(conffile) => conffile.status(&on_disk)?,
+                None if fs::symlink_metadata(&on_disk).is_ok() => ConffileStatus::Unmodified,
+                None => ConffileStatus::Missing,
+            };
+            
+            let problem = match status {
+                ConffileStatus::Unmodified => continue,
+                ConffileStatus::Modified => "modified configuration file".to_string(),
+                ConffileStatus::Missing => "missing".to_string(),
+            };
+            
+            if on_disk != self.root_path(path) {
+                problems.push((path.clone(), format!("{} (diverted to {})", problem, on_disk.display())));
+            } else {
+                problems.push((path.clone(), problem));
+            }
+        }
+        
+        Ok(problems)
+    }
+    
+    /// Decides what happens to each conffile of the incoming package without
+    /// touching the filesystem.
+    pub fn plan_conffiles(&self, archive: &PackageArchive, previous: Option<&Package>) -> Result<ConffilePlan> {
+        let mut plan = ConffilePlan::default();
+        let diversions = self.diversions()?;
+        let package_name = archive.control_fields()?.get("Package").cloned().unwrap_or_default();
+        
+        for path in archive.conffiles()? {
+            let data = archive.data_file(&path)?
+                .ok_or_else(|| anyhow!("Conffile {} is missing from the package", path.display()))?;
+            let new_hash = hash_data(&data);
+            
+            let on_disk = self.root_path(&diversions.destination(&path, &package_name));
+            let current_hash = if on_disk.exists() { Some(hash_file(&on_disk)?) } else { None };
+            let original_hash = previous
+                .and_then(|p| p.conffiles.iter().find(|c| c.path == path))
+                .map(|c| c.hash.as_str());
+            
+            match plan_conffile(current_hash.as_deref(), original_hash, &new_hash, self.replace_conffiles) {
+                ConffileAction::Install => {}
+                ConffileAction::Keep => {
+                    plan.redirects.insert(path.clone(), None);
+                }
+                ConffileAction::InstallNew => {
+                    println!("Keeping modified {}, new version installed as {}",
+                        path.display(), Conffile::new_path(&path).display());
+                    plan.redirects.insert(path.clone(), Some(Conffile::new_path(&path)));
+                }
+                ConffileAction::ReplaceOld => {
+                    println!("Replacing modified {}, your version saved as {}",
+                        path.display(), Conffile::old_path(&path).display());
+                    plan.replace_old.push(path.clone());
+                }
+            }
+            
+            plan.conffiles.push(Conffile::new(path, new_hash));
+        }
+        
+        Ok(plan)
+    }
+    
+    /// Checks the paths of an incoming package against the files owned by
+    /// other installed packages. Files owned by a package listed in the
+    /// incoming package's `replaces` are returned grouped by owner so their
+    /// ownership can be transferred; any other overlap aborts the install.
+    /// Files that a diversion sends to different locations do not overlap.
+    pub fn check_file_conflicts(
+        &self,
+        database: &InstalledDatabase,
+        diversions: &Diversions,
+        package: &Package,
+        paths: &[PathBuf],
+    ) -> Result<Vec<(String, Vec<PathBuf>)>> {
+        let mut conflicts = Vec::new();
+        let mut transfers: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        
+        for path in paths {
+            let owner = match database.owner_of(path) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            
+            // Files of an older version of the same package are simply replaced
+            if owner.name == package.name {
+                continue;
+            }
+            
+            if diversions.destination(path, &package.name) != diversions.destination(path, &owner.name) {
+                continue;
+            }
+            
+            if package.replaces_package(owner) {
+                transfers.entry(owner.name.clone()).or_default().push(path.clone());
+            } else {
+                conflicts.push(format!("{} would overwrite {} owned by {}",
+                    package.name, path.display(), owner.name));
+            }
+        }
+        
+        if !conflicts.is_empty() {
+            return Err(anyhow!("File conflicts detected:\n  {}", conflicts.join("\n  ")));
+        }
+        
+        Ok(transfers.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Cause, ProviderChoice};
+    use crate::package::archive::tests::build_deb;
+    use crate::package::{PackageInfo, WeakKind};
+    use crate::repository::{IndexEntry, RepositorySource};
+    
+    fn install(manager: &PackageManager, name: &str, auto_installed: bool, relations: &[(&str, &str)]) {
+        let mut info = PackageInfo::new(name.to_string(), "1.0".to_string(), "all".to_string(), String::new());
+        for (field, value) in relations {
+            let list = match *field {
+                "Depends" => &mut info.dependencies,
+                "Recommends" => &mut info.recommends,
+                "Suggests" => &mut info.suggests,
+                "Provides" => &mut info.provides,
+                _ => unreachable!(),
+            };
+            list.push(value.to_string());
+        }
+    
+        let mut database = manager.database().unwrap();
+        let mut package = Package::new(info, manager.install_dir().join(name));
+        package.auto_installed = auto_installed;
+        database.insert(package);
+        database.save().unwrap();
+    }
+    
+    fn unneeded(manager: &PackageManager) -> Vec<String> {
+        let mut names: Vec<String> = manager.unneeded_packages().unwrap().into_iter().map(|p| p.name).collect();
+        names.sort();
+        names
+    }
+    
+    #[test]
+    fn keeps_what_manual_packages_need() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().to_path_buf(), Vec::new());
+        install(&manager, "editor", false, &[("Depends", "libtext | libtext-alt"), ("Recommends", "spell"), ("Suggests", "docs")]);
+        install(&manager, "libtext", true, &[("Depends", "libc6 (>= 1.0)")]);
+        install(&manager, "libtext-alt", true, &[]);
+        install(&manager, "libc6", true, &[]);
+        install(&manager, "spell", true, &[("Depends", "dictionary")]);
+        install(&manager, "words", true, &[("Provides", "dictionary")]);
+        install(&manager, "docs", true, &[]);
+        install(&manager, "orphan", true, &[("Depends", "libgone")]);
+        install(&manager, "libgone", true, &[]);
+    
+        assert_eq!(unneeded(&manager), ["docs", "libgone", "orphan"]);
+    
+        let reasons = manager.install_reasons().unwrap();
+        assert_eq!(reasons["editor"], None);
+        assert_eq!(reasons["libc6"], Some(("libtext".to_string(), "libtext 1.0 depends on libc6 (>= 1.0)".to_string())));
+        assert_eq!(reasons["words"].as_ref().unwrap().0, "spell");
+    }
+    
+    #[test]
+    fn held_packages_count_as_manual() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().to_path_buf(), Vec::new())
+            .with_held_packages(vec!["tool".to_string()]);
+        install(&manager, "tool", true, &[("Depends", "libtool")]);
+        install(&manager, "libtool", true, &[]);
+    
+        assert!(unneeded(&manager).is_empty());
+    }
+    
+    #[test]
+    fn marks_change_what_is_unneeded() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().to_path_buf(), Vec::new());
+        install(&manager, "app", false, &[("Depends", "lib")]);
+        install(&manager, "lib", true, &[]);
+        install(&manager, "extra", false, &[]);
+    
+        manager.mark_packages(&["app".to_string(), "extra".to_string()], true).unwrap();
+        assert_eq!(unneeded(&manager), ["app", "extra", "lib"]);
+    
+        manager.mark_packages(&["lib".to_string()], false).unwrap();
+        assert_eq!(unneeded(&manager), ["app", "extra"]);
+        assert!(!manager.installed_package("lib").unwrap().unwrap().auto_installed);
+    
+        assert!(manager.mark_packages(&["missing".to_string()], false).is_err());
+    }
+    
+    #[test]
+    fn autoremove_removes_unneeded_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new())
+            .with_root_dir(dir.path().join("root"));
+        install(&manager, "app", false, &[("Depends", "lib")]);
+        install(&manager, "lib", true, &[]);
+        install(&manager, "stale", true, &[]);
+    
+        let removed: Vec<String> = manager.autoremove(false).unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(removed, ["stale"]);
+        assert!(!manager.installed_package("stale").unwrap().unwrap().is_present());
+        assert!(manager.installed_package("lib").unwrap().unwrap().is_present());
+        assert!(manager.autoremove(false).unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn side_by_side_needs_installed_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new())
+            .with_prefix_dir(dir.path().join("prefix"));
+        let deb = dir.path().join("gcc.deb");
+        build_deb(&deb, &[("control", "Package: gcc\nVersion: 12\nArchitecture: all\nDescription: gcc\nDepends: libc6 (>= 2), binutils | binutils-multiarch\n")], &[
+            ("/usr", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            ("/usr/bin/gcc", Some("12"), 0o755),
+        ]);
+        let requests = [InstallRequest::File(deb)];
+        install(&manager, "libc6", false, &[]);
+    
+        let error = manager.plan_side_by_side(&requests).await.unwrap_err();
+        assert_eq!(error.to_string(), "gcc depends on libc6 (>= 2), binutils | binutils-multiarch, which is not installed system-wide");
+    
+        install(&manager, "binutils-multiarch", false, &[]);
+        let mut database = manager.database().unwrap();
+        let mut libc6 = database.get("libc6").unwrap().clone();
+        libc6.version = "2.36".to_string();
+        database.insert(libc6);
+        database.save().unwrap();
+    
+        let plan = manager.plan_side_by_side(&requests).await.unwrap();
+        let installed: Vec<String> = manager.install_side_by_side(&plan).await.unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(installed, ["gcc"]);
+        assert_eq!(fs::read_to_string(dir.path().join("prefix/bin/gcc")).unwrap(), "12");
+        assert!(manager.installed_package("gcc").unwrap().is_none());
+    }
+    
+    fn user_package(dir: &Path, name: &str, control: &[(&str, &str)], mode: u32) -> InstallRequest {
+        let deb = dir.join(format!("{}.deb", name));
+        let fields = format!("Package: {}\nVersion: 1.0\nArchitecture: all\nDescription: {}\n", name, name);
+        let binary = format!("/usr/bin/{}", name);
+        let mut control_files = vec![("control", fields.as_str())];
+        control_files.extend_from_slice(control);
+        build_deb(&deb, &control_files, &[
+            ("/usr", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            (&binary, Some(name), mode),
+        ]);
+        InstallRequest::File(deb)
+    }
+    
+    #[tokio::test]
+    async fn user_mode_links_installed_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, bin_dir) = (dir.path().join("root"), dir.path().join("bin"));
+        fs::create_dir_all(&bin_dir).unwrap();
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new())
+            .with_root_dir(root.clone())
+            .with_user_mode(bin_dir.clone());
+    
+        let plan = manager.plan_install(&[user_package(dir.path(), "hello", &[], 0o755)]).await.unwrap();
+        manager.install_packages(&plan).await.unwrap();
+    
+        assert_eq!(fs::read_link(bin_dir.join("hello")).unwrap(), root.join("usr/bin/hello"));
+        assert_eq!(fs::read_to_string(bin_dir.join("hello")).unwrap(), "hello");
+    
+        manager.remove_packages(&["hello".to_string()], false).unwrap();
+        assert!(fs::symlink_metadata(bin_dir.join("hello")).is_err());
+    }
+    
+    #[tokio::test]
+    async fn user_mode_refuses_packages_that_need_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new())
+            .with_root_dir(dir.path().join("root"))
+            .with_user_mode(dir.path().join("bin"));
+    
+        let scripted = user_package(dir.path(), "daemon", &[("postinst", "#!/bin/sh\n"), ("prerm", "#!/bin/sh\n")], 0o755);
+        let plan = manager.plan_install(&[scripted]).await.unwrap();
+        let error = manager.install_packages(&plan).await.unwrap_err();
+        assert!(error.to_string().contains("daemon cannot be installed in user mode, it has maintainer scripts that need root (postinst, prerm)"), "{}", error);
+    
+        let setuid = user_package(dir.path(), "sudo", &[], 0o4755);
+        let plan = manager.plan_install(&[setuid]).await.unwrap();
+        let error = manager.install_packages(&plan).await.unwrap_err();
+        assert!(error.to_string().contains("sudo cannot be installed in user mode, it has setuid or setgid files (/usr/bin/sudo)"), "{}", error);
+    
+        assert!(manager.database().unwrap().packages().next().is_none());
+        assert!(!dir.path().join("root/usr/bin").exists());
+    }
+    
+    /// An index of the packages described in `Packages` file syntax.
+    fn index(packages: &str) -> PackageIndex {
+        let mut index = PackageIndex::default();
+        for info in RepositorySource::parse_packages_file(packages).unwrap() {
+            index.insert(IndexEntry {
+                repository: "test".to_string(),
+                priority: 500,
+                base_url: url::Url::parse("http://localhost/debian/").unwrap(),
+                info,
+            });
+        }
+        index
+    }
+    
+    fn request(name: &str) -> [InstallRequest; 1] {
+        [InstallRequest::Repository { name: name.to_string(), version: None }]
+    }
+    
+    fn planned(plan: &InstallPlan) -> Vec<&str> {
+        plan.solution.installs.iter().map(|p| p.name.as_str()).collect()
+    }
+    
+    const MAILERS: &str = "\
+Package: mailer
+Version: 1
+Architecture: all
+Depends: mail-transport-agent
+
+Package: exim4
+Version: 4.96
+Architecture: all
+Provides: mail-transport-agent
+
+Package: postfix
+Version: 3.7
+Architecture: all
+Provides: mail-transport-agent
+";
+    
+    #[tokio::test]
+    async fn asks_which_provider_to_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = PackageManager::new(dir.path().to_path_buf(), Vec::new())
+            .with_index(index(MAILERS))
+            .with_ask_providers(true);
+    
+        let error = manager.plan_install(&request("mailer")).await.unwrap_err();
+        let choice = error.downcast::<ProviderChoice>().unwrap();
+        assert_eq!(choice.virtual_name, "mail-transport-agent");
+        assert_eq!(choice.providers, ["exim4", "postfix"]);
+    
+        // What the user picks is used for the rest of the command
+        manager.prefer_provider(choice.virtual_name, "postfix".to_string());
+        let plan = manager.plan_install(&request("mailer")).await.unwrap();
+        assert_eq!(planned(&plan), ["postfix", "mailer"]);
+    
+        let plan = manager.plan_install(&request("mail-transport-agent")).await.unwrap();
+        assert_eq!(planned(&plan), ["postfix"]);
+        assert_eq!(plan.providers, [("mail-transport-agent".to_string(), "postfix".to_string())]);
+    }
+    
+    #[tokio::test]
+    async fn prefers_configured_and_installed_providers() {
+        let dir = tempfile::tempdir().unwrap();
+        let preferred = BTreeMap::from([("mail-transport-agent".to_string(), "exim4".to_string())]);
+        let manager = PackageManager::new(dir.path().to_path_buf(), Vec::new())
+            .with_index(index(MAILERS))
+            .with_preferred_providers(preferred)
+            .with_ask_providers(true);
+    
+        let plan = manager.plan_install(&request("mailer")).await.unwrap();
+        assert_eq!(planned(&plan), ["exim4", "mailer"]);
+    
+        install(&manager, "postfix", false, &[("Provides", "mail-transport-agent")]);
+        let plan = manager.plan_install(&request("mailer")).await.unwrap();
+        assert_eq!(planned(&plan), ["mailer"]);
+        let plan = manager.plan_install(&request("mail-transport-agent")).await.unwrap();
+        assert!(planned(&plan).is_empty());
+        assert_eq!(plan.provided, [("mail-transport-agent".to_string(), "postfix".to_string())]);
+    }
+    
+    const EDITOR: &str = "\
+Package: editor
+Version: 1
+Architecture: all
+Recommends: spell, missing
+Suggests: docs
+
+Package: spell
+Version: 1
+Architecture: all
+
+Package: docs
+Version: 1
+Architecture: all
+Conflicts: editor
+";
+    
+    #[tokio::test]
+    async fn plans_recommends_and_suggests_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = |recommends: bool, suggests: bool| PackageManager::new(dir.path().to_path_buf(), Vec::new())
+            .with_index(index(EDITOR))
+            .with_install_recommends(recommends)
+            .with_install_suggests(suggests);
+    
+        let plan = manager(true, false).plan_install(&request("editor")).await.unwrap();
+        assert_eq!(planned(&plan), ["editor", "spell"]);
+        assert!(matches!(&plan.solution.causes["spell"], Cause::Weak { kind: WeakKind::Recommends, package, .. } if package == "editor"));
+        assert_eq!(plan.solution.recommended, ["missing"]);
+        assert_eq!(plan.solution.suggested, ["docs"]);
+    
+        let plan = manager(false, false).plan_install(&request("editor")).await.unwrap();
+        assert_eq!(planned(&plan), ["editor"]);
+        assert_eq!(plan.solution.recommended, ["missing", "spell"]);
+    
+        // A suggestion that cannot be installed is left out, not an error
+        let plan = manager(false, true).plan_install(&request("editor")).await.unwrap();
+        assert_eq!(planned(&plan), ["editor"]);
+        assert_eq!(plan.solution.suggested, ["docs"]);
+    }
+}
@@ -1,9 +1,35 @@
 mod package;
 mod package_info;
 mod dependency;
+mod version;
 mod manager;
+mod archive;
+mod conffile;
+mod scripts;
+mod transaction;
+mod database;
+mod removal;
+mod upgrade;
+mod triggers;
+mod diversion;
+mod alternatives;
+mod versions;
+mod ordering;
  
 pub use package::*;
 pub use package_info::*;
 pub use dependency::*; 
-pub use manager::*; 
\ No newline at end of file
+pub use version::*;
+pub use manager::*;
+pub use archive::*;
+pub use conffile::*;
+pub use scripts::*;
+pub use transaction::*;
+pub use database::*;
+pub use removal::*;
+pub use upgrade::*;
+pub use triggers::*;
+pub use diversion::*;
+pub use alternatives::*;
+pub use versions::*;
+pub use ordering::*; 
\ No newline at end of file
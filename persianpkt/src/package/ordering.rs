@@ -0,0 +1,207 @@
+use crate::package::{Package, PackageDependency};
+
+/// One step of carrying out a transaction, by index into its packages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Run the preinst and move the files into place
+    Unpack(usize),
+    /// Run the postinst
+    Configure(usize),
+}
+
+impl Step {
+    /// The step's place in a `Graph`.
+    fn node(self) -> usize {
+        match self {
+            Self::Unpack(index) => index * 2,
+            Self::Configure(index) => index * 2 + 1,
+        }
+    }
+}
+
+/// Orders the unpack and configure steps of `packages` so that each package
+/// is configured after it is unpacked and after the packages it depends on
+/// in the transaction, and unpacked only once its `Pre-Depends` in the
+/// transaction are configured. Unpacking goes first where possible, in the
+/// order of `packages`, and configuring as late as possible.
+///
+/// When the relations form a loop, one of its packages is let go first: a
+/// package whose configuration only waits for its dependencies is configured
+/// before them, and otherwise a package is unpacked before its
+/// pre-dependencies are configured. The first such package in the order of
+/// `packages` is chosen, and the break is logged.
+pub fn plan_steps(packages: &[Package]) -> Vec<Step> {
+    let mut graph = Graph::new(packages);
+    let mut steps = Vec::with_capacity(packages.len() * 2);
+
+    while steps.len() < packages.len() * 2 {
+        let ready = (0..packages.len()).map(Step::Unpack)
+            .chain((0..packages.len()).map(Step::Configure))
+            .find(|step| graph.is_ready(*step));
+
+        match ready {
+            Some(step) => {
+                graph.done[step.node()] = true;
+                steps.push(step);
+            }
+            None => graph.break_loop(packages),
+        }
+    }
+
+    steps
+}
+
+/// The steps of a transaction, each with the steps it has to wait for.
+struct Graph {
+    waits_for: Vec<Vec<usize>>,
+    done: Vec<bool>,
+}
+
+impl Graph {
+    fn new(packages: &[Package]) -> Self {
+        let mut graph = Self {
+            waits_for: vec![Vec::new(); packages.len() * 2],
+            done: vec![false; packages.len() * 2],
+        };
+
+        // The package in the transaction that meets a relation, if any
+        let target = |relation: &PackageDependency, index: usize| packages.iter()
+            .position(|p| p.name != packages[index].name && relation.choices().any(|choice| p.satisfies(choice)));
+
+        for (index, package) in packages.iter().enumerate() {
+            let configure = Step::Configure(index).node();
+            graph.waits_for[configure].push(Step::Unpack(index).node());
+
+            for dependency in package.dependencies.iter().filter(|d| !d.is_optional) {
+                if let Some(other) = target(dependency, index) {
+                    graph.waits_for[configure].push(Step::Configure(other).node());
+                }
+            }
+
+            let unpack = Step::Unpack(index).node();
+            for dependency in &package.pre_depends {
+                if let Some(other) = target(dependency, index) {
+                    graph.waits_for[unpack].push(Step::Configure(other).node());
+                }
+            }
+        }
+
+        graph
+    }
+
+    fn is_ready(&self, step: Step) -> bool {
+        let node = step.node();
+        !self.done[node] && self.waits_for[node].iter().all(|other| self.done[*other])
+    }
+
+    /// The steps `node` still waits for.
+    fn pending(&self, node: usize) -> Vec<usize> {
+        self.waits_for[node].iter().copied().filter(|other| !self.done[*other]).collect()
+    }
+
+    /// Whether `node` waits for itself through the steps it waits for.
+    fn in_loop(&self, node: usize) -> bool {
+        let mut seen = vec![false; self.done.len()];
+        let mut stack = self.pending(node);
+
+        while let Some(current) = stack.pop() {
+            if current == node {
+                return true;
+            }
+            if !std::mem::replace(&mut seen[current], true) {
+                stack.extend(self.pending(current));
+            }
+        }
+
+        false
+    }
+
+    /// Drops what one step of a loop waits for, see `plan_steps`.
+    fn break_loop(&mut self, packages: &[Package]) {
+        let configures: Vec<usize> = (0..packages.len())
+            .filter(|index| self.done[Step::Unpack(*index).node()] && !self.done[Step::Configure(*index).node()])
+            .collect();
+        let unpacks: Vec<usize> = (0..packages.len())
+            .filter(|index| !self.done[Step::Unpack(*index).node()])
+            .collect();
+
+        let step = configures.iter().map(|index| Step::Configure(*index))
+            .find(|step| self.in_loop(step.node()))
+            .or_else(|| unpacks.iter().map(|index| Step::Unpack(*index)).find(|step| self.in_loop(step.node())))
+            .or_else(|| configures.first().map(|index| Step::Configure(*index)))
+            .or_else(|| unpacks.first().map(|index| Step::Unpack(*index)))
+            .expect("a step left to take");
+
+        let node = step.node();
+        let mut names: Vec<&str> = self.pending(node).iter().map(|other| packages[other / 2].name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        match step {
+            Step::Configure(index) => log::info!("Breaking dependency loop: configuring {} before {}, which it depends on",
+                packages[index].name, names.join(", ")),
+            Step::Unpack(index) => log::warn!("Breaking pre-dependency loop: unpacking {} before {} is configured",
+                packages[index].name, names.join(", ")),
+        }
+
+        self.waits_for[node].retain(|other| self.done[*other]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::PackageInfo;
+    use std::path::PathBuf;
+    use Step::{Configure, Unpack};
+
+    fn package(name: &str, depends: &[&str], pre_depends: &[&str], provides: &[&str]) -> Package {
+        let mut info = PackageInfo::new(name.to_string(), "1.0".to_string(), "all".to_string(), String::new());
+        info.dependencies = depends.iter().map(|d| d.to_string()).collect();
+        info.pre_depends = pre_depends.iter().map(|d| d.to_string()).collect();
+        info.provides = provides.iter().map(|p| p.to_string()).collect();
+        Package::new(info, PathBuf::new())
+    }
+
+    #[test]
+    fn unpacks_first_and_configures_late() {
+        let packages = [package("a", &[], &[], &[]), package("b", &[], &[], &[])];
+        assert_eq!(plan_steps(&packages), [Unpack(0), Unpack(1), Configure(0), Configure(1)]);
+    }
+
+    #[test]
+    fn configures_dependencies_first() {
+        let packages = [
+            package("app", &["lib (>= 1.0)", "mail-transport-agent"], &[], &[]),
+            package("lib", &[], &[], &[]),
+            package("postfix", &[], &[], &["mail-transport-agent"]),
+            package("unrelated", &["lib (>= 2.0)"], &[], &[]),
+        ];
+        let steps = plan_steps(&packages);
+
+        assert_eq!(steps[..4], [Unpack(0), Unpack(1), Unpack(2), Unpack(3)]);
+        let position = |step| steps.iter().position(|s| *s == step).unwrap();
+        assert!(position(Configure(1)) < position(Configure(0)));
+        assert!(position(Configure(2)) < position(Configure(0)));
+    }
+
+    #[test]
+    fn configures_pre_dependencies_before_unpacking() {
+        let packages = [package("app", &[], &["libc6"], &[]), package("libc6", &[], &[], &[])];
+        assert_eq!(plan_steps(&packages), [Unpack(1), Configure(1), Unpack(0), Configure(0)]);
+    }
+
+    #[test]
+    fn breaks_dependency_loops_at_the_first_package() {
+        let packages = [package("a", &["b"], &[], &[]), package("b", &["a"], &[], &[])];
+        assert_eq!(plan_steps(&packages), [Unpack(0), Unpack(1), Configure(0), Configure(1)]);
+
+        let packages = [package("c", &[], &[], &[]), package("b", &["a"], &[], &[]), package("a", &["b"], &[], &[])];
+        assert_eq!(plan_steps(&packages), [Unpack(0), Unpack(1), Unpack(2), Configure(0), Configure(1), Configure(2)]);
+    }
+
+    #[test]
+    fn breaks_pre_dependency_loops() {
+        let packages = [package("a", &[], &["b"], &[]), package("b", &[], &["a"], &[])];
+        assert_eq!(plan_steps(&packages), [Unpack(0), Configure(0), Unpack(1), Configure(1)]);
+    }
+}
@@ -1,10 +1,38 @@
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::fmt;
 use std::time::SystemTime;
 
-use crate::package::{PackageInfo, PackageDependency};
+use crate::package::{host_architecture, Conffile, ConflictKind, PackageInfo, PackageDependency};
+
+/// Where a package is in its install lifecycle. `ConfigFiles` is left by a
+/// removal without purge; anything else other than `Installed` means an
+/// earlier operation did not finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageState {
+    ConfigFiles,
+    HalfInstalled,
+    Unpacked,
+    HalfConfigured,
+    #[default]
+    Installed,
+}
+
+impl fmt::Display for PackageState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            Self::ConfigFiles => "config-files",
+            Self::HalfInstalled => "half-installed",
+            Self::Unpacked => "unpacked",
+            Self::HalfConfigured => "half-configured",
+            Self::Installed => "installed",
+        };
+        write!(f, "{}", state)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Package {
@@ -13,11 +41,38 @@ pub struct Package {
     pub architecture: String,
     pub description: String,
     pub dependencies: Vec<PackageDependency>,
+    /// Dependencies to configure before the package is unpacked. They are
+    /// also in `dependencies`, as they have to hold like any other.
+    #[serde(default)]
+    pub pre_depends: Vec<PackageDependency>,
+    /// Packages installed along by default, see `WeakKind`
+    #[serde(default)]
+    pub recommends: Vec<PackageDependency>,
+    #[serde(default)]
+    pub suggests: Vec<PackageDependency>,
     pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub breaks: Vec<String>,
     pub provides: Vec<String>,
     pub replaces: Vec<String>,
     pub install_path: PathBuf,
     pub files: Vec<PathBuf>,
+    #[serde(default)]
+    pub conffiles: Vec<Conffile>,
+    #[serde(default)]
+    pub state: PackageState,
+    /// Installed only to satisfy a dependency of another package
+    #[serde(default)]
+    pub auto_installed: bool,
+    /// Repository the package was installed from, or `local` for package files
+    #[serde(default)]
+    pub origin: String,
+    /// SHA256 of the package file as listed in the repository index
+    #[serde(default)]
+    pub sha256: String,
+    /// Named triggers and path prefixes the package's postinst handles
+    #[serde(default)]
+    pub trigger_interests: Vec<String>,
     pub install_date: SystemTime,
     pub size: u64,
     pub installed_size: u64,
@@ -29,17 +84,35 @@ pub struct Package {
 
 impl Package {
     pub fn new(info: PackageInfo, install_path: PathBuf) -> Self {
+        let parse = |relations: &[String]| -> Vec<PackageDependency> {
+            relations.iter()
+                .filter_map(|d| PackageDependency::from_string(d))
+                .filter_map(|d| d.restrict_to(host_architecture()))
+                .collect()
+        };
+        let pre_depends = parse(&info.pre_depends);
+
         Self {
             name: info.name,
             version: info.version,
             architecture: info.architecture,
             description: info.description,
-            dependencies: Vec::new(),
-            conflicts: Vec::new(),
-            provides: Vec::new(),
-            replaces: Vec::new(),
+            dependencies: pre_depends.iter().cloned().chain(parse(&info.dependencies)).collect(),
+            pre_depends,
+            recommends: parse(&info.recommends),
+            suggests: parse(&info.suggests),
+            conflicts: info.conflicts,
+            breaks: info.breaks,
+            provides: info.provides,
+            replaces: info.replaces,
             install_path,
             files: Vec::new(),
+            conffiles: Vec::new(),
+            state: PackageState::Installed,
+            auto_installed: false,
+            origin: String::new(),
+            sha256: info.sha256,
+            trigger_interests: Vec::new(),
             install_date: SystemTime::now(),
             size: info.size,
             installed_size: info.installed_size,
@@ -54,6 +127,80 @@ impl Package {
         self.install_path.exists()
     }
 
+    /// Directory holding the maintainer scripts of the installed package.
+    pub fn scripts_dir(&self) -> PathBuf {
+        self.install_path.join("scripts")
+    }
+
+    /// Whether the package is present on the system, as opposed to only
+    /// its configuration files being left behind.
+    pub fn is_present(&self) -> bool {
+        self.state != PackageState::ConfigFiles
+    }
+
+    /// Whether this package depends on `package_name`, ignoring optional dependencies.
+    pub fn depends_on(&self, package_name: &str) -> bool {
+        self.dependencies.iter().any(|d| !d.is_optional && d.mentions(package_name))
+    }
+
+    /// Whether the package meets `choice`, one alternative of a relation,
+    /// either itself or through its `Provides`.
+    pub fn satisfies(&self, choice: &PackageDependency) -> bool {
+        (self.name == choice.name && choice.satisfies(&self.version)) || self.provides_choice(choice)
+    }
+
+    /// Whether one of the `Provides` of the package meets `choice`.
+    pub fn provides_choice(&self, choice: &PackageDependency) -> bool {
+        self.provided().iter().any(|provided| provided.provides(choice))
+    }
+
+    /// The parsed `Provides` of the package.
+    pub fn provided(&self) -> Vec<PackageDependency> {
+        self.provides.iter()
+            .filter_map(|p| PackageDependency::parse(p).ok())
+            .collect()
+    }
+
+    /// The `Conflicts` and `Breaks` of the package that apply on the host
+    /// architecture, except those naming the package itself.
+    pub fn conflict_relations(&self) -> Vec<(ConflictKind, PackageDependency)> {
+        let parse = |kind: ConflictKind, relations: &[String]| relations.iter()
+            .filter_map(|r| PackageDependency::from_string(r))
+            .filter(|r| r.name != self.name && r.applies_to(host_architecture()))
+            .map(|r| (kind, r))
+            .collect::<Vec<_>>();
+
+        let mut relations = parse(ConflictKind::Conflicts, &self.conflicts);
+        relations.extend(parse(ConflictKind::Breaks, &self.breaks));
+        relations
+    }
+
+    /// Returns the `Conflicts` or `Breaks` relation that keeps `other` from
+    /// being installed next to this package, if any.
+    pub fn rejects(&self, other: &Package) -> Option<(ConflictKind, PackageDependency)> {
+        if other.name == self.name {
+            return None;
+        }
+        self.conflict_relations().into_iter().find(|(_, relation)| other.satisfies(relation))
+    }
+
+    /// Describes `relation` as matched by this package, naming the package
+    /// when it matches through its `Provides`.
+    pub fn describe_match(&self, relation: &PackageDependency) -> String {
+        if relation.name == self.name {
+            relation.to_string()
+        } else {
+            format!("{}, provided by {} {}", relation, self.name, self.version)
+        }
+    }
+
+    /// Whether a `Replaces` of this package matches `other` in its version.
+    pub fn replaces_package(&self, other: &Package) -> bool {
+        self.replaces.iter()
+            .filter_map(|r| PackageDependency::from_string(r))
+            .any(|r| other.satisfies(&r))
+    }
+
     pub fn add_file(&mut self, file: PathBuf) {
         if !self.files.contains(&file) {
             self.files.push(file);
@@ -88,6 +235,14 @@ impl Package {
         &self.files
     }
 
+    pub fn get_conffiles(&self) -> &[Conffile] {
+        &self.conffiles
+    }
+
+    pub fn is_conffile(&self, path: &Path) -> bool {
+        self.conffiles.iter().any(|c| c.path == path)
+    }
+
     pub fn get_dependencies(&self) -> &[PackageDependency] {
         &self.dependencies
     }
@@ -7,6 +7,20 @@ pub struct PackageInfo {
     pub architecture: String,
     pub description: String,
     pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub pre_depends: Vec<String>,
+    #[serde(default)]
+    pub recommends: Vec<String>,
+    #[serde(default)]
+    pub suggests: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub breaks: Vec<String>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
     pub size: u64,
     pub installed_size: u64,
     pub maintainer: String,
@@ -31,6 +45,13 @@ impl PackageInfo {
             architecture,
             description,
             dependencies: Vec::new(),
+            pre_depends: Vec::new(),
+            recommends: Vec::new(),
+            suggests: Vec::new(),
+            conflicts: Vec::new(),
+            breaks: Vec::new(),
+            provides: Vec::new(),
+            replaces: Vec::new(),
             size: 0,
             installed_size: 0,
             maintainer: String::new(),
@@ -0,0 +1,354 @@
+use anyhow::{Result, anyhow};
+use std::collections::BTreeSet;
+use std::fs;
+
+use crate::core::DependencyResolver;
+use crate::package::{
+    Conffile, Diversions, InstalledDatabase, MaintainerScript, Package, PackageDependency, PackageManager, PackageState,
+    ScriptRunner, Triggers,
+};
+use crate::utils::{remove_empty_parents, with_suffix};
+
+/// Removes a set of installed packages. Without purge the configuration
+/// files stay behind and the package is kept in the `config-files` state;
+/// with purge they are deleted too, along with automatically installed
+/// dependencies nothing else needs anymore.
+pub struct Removal<'a> {
+    manager: &'a PackageManager,
+    database: InstalledDatabase,
+    diversions: Diversions,
+    runner: ScriptRunner,
+    triggers: Triggers,
+    requests: Vec<String>,
+    purge: bool,
+}
+
+impl<'a> Removal<'a> {
+    pub fn new(manager: &'a PackageManager, purge: bool) -> Result<Self> {
+        Ok(Self {
+            manager,
+            database: manager.database()?,
+            diversions: manager.diversions()?,
+            runner: manager.script_runner(),
+            triggers: Triggers::new(),
+            requests: Vec::new(),
+            purge,
+        })
+    }
+
+    pub fn add(&mut self, package_name: &str) {
+        self.requests.push(package_name.to_string());
+    }
+
+    /// Returns the packages that will be removed, dependents before the
+    /// packages they depend on. Fails if a package that stays installed
+    /// needs one of them, and nothing else left installed meets the need.
+    pub fn plan(&self) -> Result<Vec<Package>> {
+        let mut removing = BTreeSet::new();
+
+        for name in &self.requests {
+            let package = self.database.get(name)
+                .ok_or_else(|| anyhow!("Package '{}' is not installed", name))?;
+
+            if !package.is_present() && !self.purge {
+                return Err(anyhow!("Package '{}' is already removed, use --purge to delete its configuration files", name));
+            }
+
+            removing.insert(name.clone());
+        }
+
+        // A dependency blocks the removal if the packages that stay can no
+        // longer meet it, through another alternative or provider
+        let present: Vec<&Package> = self.database.packages().filter(|p| p.is_present()).collect();
+        let remaining: Vec<&Package> = present.iter().copied().filter(|p| !removing.contains(&p.name)).collect();
+        let met_by = |packages: &[&Package], dependency: &PackageDependency| dependency.choices()
+            .any(|choice| packages.iter().any(|p| p.satisfies(choice)));
+
+        let mut blockers = Vec::new();
+        for package in &remaining {
+            for dependency in package.dependencies.iter().filter(|d| !d.is_optional) {
+                if met_by(&present, dependency) && !met_by(&remaining, dependency) {
+                    blockers.push(format!("{} depends on {}", package.name, dependency));
+                }
+            }
+        }
+
+        if !blockers.is_empty() {
+            return Err(anyhow!("Cannot remove packages that are still needed:\n  {}", blockers.join("\n  ")));
+        }
+
+        if self.purge {
+            self.add_unneeded_dependencies(&mut removing)?;
+        }
+
+        Ok(self.removal_order(removing))
+    }
+
+    /// Removes the planned packages one at a time, recording progress in the
+    /// database so an interrupted removal shows up as half-installed.
+    pub fn run(mut self) -> Result<Vec<Package>> {
+        let plan = self.plan()?;
+
+        for package in &plan {
+            self.remove(package)?;
+        }
+
+        if let Err(e) = self.triggers.run(self.manager, &self.database, &self.runner) {
+            eprintln!("Warning: failed to process triggers: {}", e);
+        }
+        if let Err(e) = self.manager.sync_user_bin() {
+            eprintln!("Warning: failed to link commands: {}", e);
+        }
+
+        Ok(plan)
+    }
+
+    /// Adds automatically installed packages whose every installed reverse
+    /// dependency is being removed, until no more are found.
+    fn add_unneeded_dependencies(&self, removing: &mut BTreeSet<String>) -> Result<()> {
+        let mut resolver = DependencyResolver::new(self.manager.index());
+        resolver.load_installed_packages(&self.database)?;
+
+        loop {
+            let mut unneeded = Vec::new();
+            for package in self.database.packages()
+                .filter(|p| p.auto_installed && p.is_present() && !removing.contains(&p.name))
+                .filter(|p| removing.iter().any(|name| self.database.get(name).map(|r| r.depends_on(&p.name)).unwrap_or(false)))
+            {
+                let dependents = resolver.find_reverse_dependencies(&package.name)?;
+                if dependents.iter().all(|dependent| removing.contains(&dependent.name)) {
+                    unneeded.push(package.name.clone());
+                }
+            }
+
+            if unneeded.is_empty() {
+                return Ok(());
+            }
+
+            removing.extend(unneeded);
+        }
+    }
+
+    /// Orders packages so each one is removed before anything it depends on,
+    /// by name or through something it provides.
+    fn removal_order(&self, mut remaining: BTreeSet<String>) -> Vec<Package> {
+        let mut order = Vec::new();
+        let needs = |other: &Package, name: &str| self.database.get(name)
+            .map(|package| other.dependencies.iter()
+                .filter(|d| !d.is_optional)
+                .any(|d| d.choices().any(|choice| package.satisfies(choice))))
+            .unwrap_or(false);
+
+        while !remaining.is_empty() {
+            let next = remaining.iter()
+                .find(|name| !remaining.iter()
+                    .filter(|other| other != name)
+                    .filter_map(|other| self.database.get(other))
+                    .any(|other| needs(other, name)))
+                // Dependency cycles are broken arbitrarily
+                .or_else(|| remaining.iter().next())
+                .cloned()
+                .unwrap();
+
+            remaining.remove(&next);
+            if let Some(package) = self.database.get(&next) {
+                order.push(package.clone());
+            }
+        }
+
+        order
+    }
+
+    fn remove(&mut self, package: &Package) -> Result<()> {
+        let scripts_dir = package.scripts_dir();
+        let mut record = package.clone();
+
+        if package.is_present() {
+            println!("Removing {} ({})...", package.name, package.version);
+
+            if let Err(e) = self.runner.run(&scripts_dir, MaintainerScript::Prerm, package, &["remove"]) {
+                let _ = self.runner.run(&scripts_dir, MaintainerScript::Postinst, package, &["abort-remove"]);
+                return Err(e);
+            }
+
+            record.state = PackageState::HalfInstalled;
+            self.save(&record)?;
+
+            for path in package.files.iter().filter(|path| !package.is_conffile(path)) {
+                let on_disk = self.manager.root_path(&self.diversions.destination(path, &package.name));
+                if fs::symlink_metadata(&on_disk).is_ok() {
+                    fs::remove_file(&on_disk)
+                        .map_err(|e| anyhow!("Failed to remove {}: {}", on_disk.display(), e))?;
+                }
+                self.triggers.activate_path(path);
+                remove_empty_parents(&on_disk, self.manager.root_dir());
+            }
+
+            self.manager.change_alternatives(|alternatives| {
+                alternatives.remove_package(&package.name);
+                Ok(())
+            })?;
+
+            self.runner.run(&scripts_dir, MaintainerScript::Postrm, package, &["remove"])?;
+
+            record.files.retain(|path| package.is_conffile(path));
+            record.state = PackageState::ConfigFiles;
+            self.save(&record)?;
+        }
+
+        if self.purge {
+            println!("Purging configuration files for {} ({})...", package.name, package.version);
+
+            for conffile in &package.conffiles {
+                let on_disk = self.manager.root_path(&self.diversions.destination(&conffile.path, &package.name));
+                for path in [
+                    on_disk.clone(),
+                    Conffile::new_path(&on_disk),
+                    Conffile::old_path(&on_disk),
+                    with_suffix(&on_disk, ".pkt-bak"),
+                ] {
+                    if fs::symlink_metadata(&path).is_ok() {
+                        fs::remove_file(&path)?;
+                    }
+                }
+                remove_empty_parents(&on_disk, self.manager.root_dir());
+            }
+
+            self.runner.run(&scripts_dir, MaintainerScript::Postrm, package, &["purge"])?;
+
+            self.database.remove(&package.name);
+            self.database.save()?;
+
+            if package.install_path.exists() {
+                fs::remove_dir_all(&package.install_path)?;
+            }
+            remove_empty_parents(&package.install_path, self.manager.install_dir());
+        }
+
+        Ok(())
+    }
+
+    fn save(&mut self, record: &Package) -> Result<()> {
+        self.database.insert(record.clone());
+        self.database.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::tests::build_deb;
+    use crate::package::InstallRequest;
+    use std::path::Path;
+
+    fn manager(dir: &Path) -> PackageManager {
+        PackageManager::new(dir.join("packages"), Vec::new()).with_root_dir(dir.join("root"))
+    }
+
+    /// Installs a package named `name` with the control fields in `control`,
+    /// shipping `/usr/bin/<name>` and a conffile `/etc/<name>.conf`.
+    async fn install(manager: &PackageManager, dir: &Path, name: &str, control: &str, auto_installed: bool) {
+        let deb = dir.join(format!("{}.deb", name));
+        let binary = format!("/usr/bin/{}", name);
+        let conffile = format!("/etc/{}.conf", name);
+        build_deb(&deb, &[
+            ("control", &format!("Package: {}\nVersion: 1.0\nArchitecture: all\nDescription: {}\n{}", name, name, control)),
+            ("conffiles", &format!("{}\n", conffile)),
+        ], &[
+            ("/etc", None, 0o755),
+            (&conffile, Some("setting"), 0o644),
+            ("/usr", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            (&binary, Some(name), 0o755),
+        ]);
+
+        let plan = manager.plan_install(&[InstallRequest::File(deb)]).await.unwrap();
+        manager.install_packages(&plan).await.unwrap();
+        if auto_installed {
+            manager.mark_packages(&[name.to_string()], true).unwrap();
+        }
+    }
+
+    fn names(packages: Vec<Package>) -> Vec<String> {
+        packages.into_iter().map(|p| p.name).collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_configuration_until_purged() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let manager = manager(dir.path());
+        install(&manager, dir.path(), "tool", "", false).await;
+
+        assert_eq!(names(manager.remove_packages(&["tool".to_string()], false).unwrap()), ["tool"]);
+        assert!(!root.join("usr/bin/tool").exists());
+        assert_eq!(fs::read_to_string(root.join("etc/tool.conf")).unwrap(), "setting");
+        let record = manager.installed_package("tool").unwrap().unwrap();
+        assert_eq!(record.state, PackageState::ConfigFiles);
+        assert!(manager.remove_packages(&["tool".to_string()], false).is_err());
+
+        assert_eq!(names(manager.remove_packages(&["tool".to_string()], true).unwrap()), ["tool"]);
+        assert!(!root.join("etc/tool.conf").exists());
+        assert!(manager.installed_package("tool").unwrap().is_none());
+        assert!(!record.install_path.exists());
+        assert!(manager.remove_packages(&["tool".to_string()], true).is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_remove_what_others_need() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        install(&manager, dir.path(), "lib", "", false).await;
+        install(&manager, dir.path(), "lib-alt", "Provides: libapi\n", false).await;
+        install(&manager, dir.path(), "app", "Depends: lib (>= 1.0)\n", false).await;
+        install(&manager, dir.path(), "viewer", "Depends: libapi\n", false).await;
+
+        let error = manager.plan_removal(&["lib".to_string(), "lib-alt".to_string()], false).unwrap_err();
+        assert_eq!(error.to_string(), "Cannot remove packages that are still needed:\n  app depends on lib (>= 1.0)\n  viewer depends on libapi");
+        assert!(manager.remove_packages(&["lib".to_string()], false).is_err());
+        assert!(manager.installed_package("lib").unwrap().unwrap().is_present());
+
+        // Removing the dependents along with their dependencies is fine
+        let plan = manager.plan_removal(&["lib".to_string(), "app".to_string(), "lib-alt".to_string(), "viewer".to_string()], false).unwrap();
+        assert_eq!(names(plan), ["app", "lib", "viewer", "lib-alt"]);
+    }
+
+    #[tokio::test]
+    async fn accepts_other_ways_to_meet_a_dependency() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(dir.path());
+        install(&manager, dir.path(), "lib", "Provides: libapi\n", false).await;
+        install(&manager, dir.path(), "lib-alt", "Provides: libapi\n", false).await;
+        install(&manager, dir.path(), "app", "Depends: libapi\n", false).await;
+        install(&manager, dir.path(), "tool", "Depends: lib | lib-alt\nRecommends: app\n", false).await;
+
+        assert_eq!(names(manager.remove_packages(&["lib".to_string(), "app".to_string()], false).unwrap()), ["app", "lib"]);
+        assert!(manager.installed_package("tool").unwrap().unwrap().is_present());
+    }
+
+    #[tokio::test]
+    async fn purges_dependencies_nothing_needs_anymore() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let manager = manager(dir.path());
+        install(&manager, dir.path(), "base", "", true).await;
+        install(&manager, dir.path(), "shared", "", true).await;
+        install(&manager, dir.path(), "lib", "Depends: base, shared\n", true).await;
+        install(&manager, dir.path(), "manual", "", false).await;
+        install(&manager, dir.path(), "app", "Depends: lib, manual\n", false).await;
+        install(&manager, dir.path(), "other", "Depends: shared\n", false).await;
+
+        // Only a purge takes the dependencies along
+        assert_eq!(names(manager.plan_removal(&["app".to_string()], false).unwrap()), ["app"]);
+
+        let removed = manager.remove_packages(&["app".to_string()], true).unwrap();
+        assert_eq!(names(removed), ["app", "lib", "base"]);
+        for name in ["app", "lib", "base"] {
+            assert!(manager.installed_package(name).unwrap().is_none(), "{}", name);
+            assert!(!root.join("usr/bin").join(name).exists(), "{}", name);
+        }
+        for name in ["shared", "manual", "other"] {
+            assert!(manager.installed_package(name).unwrap().unwrap().is_present(), "{}", name);
+        }
+    }
+}
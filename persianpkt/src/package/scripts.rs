@@ -0,0 +1,262 @@
+use anyhow::{Result, anyhow};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+use crate::package::{Package, PackageArchive};
+use crate::utils::LOCKED_ENV;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintainerScript {
+    Preinst,
+    Postinst,
+    Prerm,
+    Postrm,
+}
+
+impl MaintainerScript {
+    pub const ALL: [MaintainerScript; 4] = [
+        MaintainerScript::Preinst,
+        MaintainerScript::Postinst,
+        MaintainerScript::Prerm,
+        MaintainerScript::Postrm,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Preinst => "preinst",
+            Self::Postinst => "postinst",
+            Self::Prerm => "prerm",
+            Self::Postrm => "postrm",
+        }
+    }
+}
+
+/// Copies the maintainer scripts shipped in a package's control archive
+/// into `scripts_dir` so they can be run later, e.g. on removal.
+pub fn extract_scripts(archive: &PackageArchive, scripts_dir: &Path) -> Result<()> {
+    if scripts_dir.exists() {
+        fs::remove_dir_all(scripts_dir)?;
+    }
+    fs::create_dir_all(scripts_dir)?;
+
+    for script in MaintainerScript::ALL {
+        if let Some(content) = archive.control_file(script.name())? {
+            let path = scripts_dir.join(script.name());
+            fs::write(&path, content)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs maintainer scripts with the arguments and environment dpkg uses.
+pub struct ScriptRunner {
+    root_dir: PathBuf,
+    admin_dir: PathBuf,
+    user_mode: bool,
+}
+
+impl ScriptRunner {
+    pub fn new(root_dir: PathBuf, admin_dir: PathBuf) -> Self {
+        Self { root_dir, admin_dir, user_mode: false }
+    }
+
+    /// Runs everything in place with `DPKG_ROOT` pointing at the root, as an
+    /// unprivileged user cannot chroot.
+    pub fn with_user_mode(mut self) -> Self {
+        self.user_mode = true;
+        self
+    }
+
+    /// Runs `script` from `scripts_dir` if the package ships it. A missing
+    /// script counts as success.
+    pub fn run(&self, scripts_dir: &Path, script: MaintainerScript, package: &Package, args: &[&str]) -> Result<()> {
+        let path = scripts_dir.join(script.name());
+        if !path.exists() {
+            return Ok(());
+        }
+
+        // Scripts of packages installed into an alternate root run chrooted into it
+        let mut command = if !self.chrooted() {
+            Command::new(&path)
+        } else {
+            if !path.starts_with(&self.root_dir) {
+                return Err(anyhow!("{} is outside of {}", path.display(), self.root_dir.display()));
+            }
+            let mut command = Command::new("chroot");
+            command.arg(&self.root_dir).arg(self.inner_path(&path));
+            command
+        };
+
+        let status = command
+            .args(args)
+            .current_dir(&self.root_dir)
+            .env("DPKG_MAINTSCRIPT_PACKAGE", &package.name)
+            .env("DPKG_MAINTSCRIPT_NAME", script.name())
+            .env("DPKG_MAINTSCRIPT_ARCH", &package.architecture)
+            .env("DPKG_ROOT", self.dpkg_root())
+            .env("DPKG_ADMINDIR", self.inner_path(&self.admin_dir))
+            .env("DPKG_RUNNING_VERSION", env!("CARGO_PKG_VERSION"))
+            .env(LOCKED_ENV, "1")
+            .status()
+            .map_err(|e| anyhow!("Failed to run {} {}: {}", package.name, script.name(), e))?;
+
+        if !status.success() {
+            return Err(anyhow!("{} {} {} failed with {}",
+                package.name, script.name(), args.join(" "), status));
+        }
+
+        Ok(())
+    }
+
+    /// Runs a shell command inside the root, e.g. a trigger handler.
+    pub fn run_command(&self, command_line: &str) -> Result<()> {
+        let mut command = if !self.chrooted() {
+            Command::new("sh")
+        } else {
+            let mut command = Command::new("chroot");
+            command.arg(&self.root_dir).arg("sh");
+            command
+        };
+
+        let status = command
+            .arg("-c")
+            .arg(command_line)
+            .current_dir(&self.root_dir)
+            .env("DPKG_ROOT", self.dpkg_root())
+            .status()
+            .map_err(|e| anyhow!("Failed to run {}: {}", command_line, e))?;
+
+        if !status.success() {
+            return Err(anyhow!("{} failed with {}", command_line, status));
+        }
+
+        Ok(())
+    }
+
+    fn chrooted(&self) -> bool {
+        !self.user_mode && self.root_dir != Path::new("/")
+    }
+
+    /// The root as scripts see it: empty when they run inside it.
+    fn dpkg_root(&self) -> PathBuf {
+        if self.chrooted() || self.root_dir == Path::new("/") {
+            PathBuf::new()
+        } else {
+            self.root_dir.clone()
+        }
+    }
+
+    /// Returns how `path` is seen from inside the root the scripts run in.
+    fn inner_path(&self, path: &Path) -> PathBuf {
+        if !self.chrooted() {
+            return path.to_path_buf();
+        }
+        match path.strip_prefix(&self.root_dir) {
+            Ok(inner) => Path::new("/").join(inner),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::tests::build_deb;
+
+    const POSTINST: &str = "#!/bin/sh
+echo \"$@\" > args
+env | grep -E '^(DPKG_|PERSIANPKT_)' | sort > env
+";
+
+    /// Extracts the scripts of a package shipping `postinst` below `root`.
+    fn hello(root: &Path, postinst: &str) -> (PathBuf, Package) {
+        let deb = root.join("hello.deb");
+        build_deb(&deb, &[
+            ("control", "Package: hello\nVersion: 1.0\nArchitecture: amd64\nDescription: hello\n"),
+            ("postinst", postinst),
+        ], &[]);
+        let archive = PackageArchive::open(&deb).unwrap();
+
+        let scripts_dir = root.join("var/lib/persianpkt/hello/scripts");
+        extract_scripts(&archive, &scripts_dir).unwrap();
+        (scripts_dir, archive.package().unwrap())
+    }
+
+    #[test]
+    fn extracts_only_shipped_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (scripts_dir, _) = hello(dir.path(), POSTINST);
+
+        let mode = fs::metadata(scripts_dir.join("postinst")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert!(!scripts_dir.join("preinst").exists());
+    }
+
+    #[test]
+    fn passes_dpkg_arguments_and_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (scripts_dir, package) = hello(root, POSTINST);
+        let runner = ScriptRunner::new(root.to_path_buf(), root.join("var/lib/persianpkt")).with_user_mode();
+
+        runner.run(&scripts_dir, MaintainerScript::Postinst, &package, &["configure", "0.9"]).unwrap();
+
+        assert_eq!(fs::read_to_string(root.join("args")).unwrap(), "configure 0.9\n");
+        let expected = format!("\
+DPKG_ADMINDIR={root}/var/lib/persianpkt
+DPKG_MAINTSCRIPT_ARCH=amd64
+DPKG_MAINTSCRIPT_NAME=postinst
+DPKG_MAINTSCRIPT_PACKAGE=hello
+DPKG_ROOT={root}
+DPKG_RUNNING_VERSION={version}
+PERSIANPKT_LOCKED=1
+", root = root.display(), version = env!("CARGO_PKG_VERSION"));
+        assert_eq!(fs::read_to_string(root.join("env")).unwrap(), expected);
+    }
+
+    #[test]
+    fn reports_failing_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (scripts_dir, package) = hello(root, "#!/bin/sh\nexit 3\n");
+        let runner = ScriptRunner::new(root.to_path_buf(), root.join("var/lib/persianpkt")).with_user_mode();
+
+        let error = runner.run(&scripts_dir, MaintainerScript::Postinst, &package, &["configure"]).unwrap_err();
+        assert!(error.to_string().starts_with("hello postinst configure failed"), "{}", error);
+
+        // A script the package does not ship counts as success
+        assert!(runner.run(&scripts_dir, MaintainerScript::Prerm, &package, &["remove"]).is_ok());
+    }
+
+    #[test]
+    fn runs_commands_in_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = ScriptRunner::new(dir.path().to_path_buf(), dir.path().join("admin")).with_user_mode();
+
+        runner.run_command("echo \"$DPKG_ROOT\" > root").unwrap();
+
+        assert_eq!(fs::read_to_string(dir.path().join("root")).unwrap(), format!("{}\n", dir.path().display()));
+        assert!(runner.run_command("exit 1").is_err());
+    }
+
+    #[test]
+    fn sees_paths_from_inside_the_root() {
+        let chrooted = ScriptRunner::new(PathBuf::from("/srv/root"), PathBuf::from("/srv/root/var/lib/persianpkt"));
+        assert!(chrooted.chrooted());
+        assert_eq!(chrooted.dpkg_root(), PathBuf::new());
+        assert_eq!(chrooted.inner_path(&chrooted.admin_dir), Path::new("/var/lib/persianpkt"));
+
+        let user = ScriptRunner::new(PathBuf::from("/home/me/root"), PathBuf::from("/home/me/root/admin")).with_user_mode();
+        assert!(!user.chrooted());
+        assert_eq!(user.dpkg_root(), Path::new("/home/me/root"));
+        assert_eq!(user.inner_path(&user.admin_dir), Path::new("/home/me/root/admin"));
+
+        let host = ScriptRunner::new(PathBuf::from("/"), PathBuf::from("/var/lib/persianpkt"));
+        assert!(!host.chrooted());
+        assert_eq!(host.dpkg_root(), PathBuf::new());
+    }
+}
@@ -0,0 +1,911 @@
+use anyhow::{Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::SystemTime;
+
+use crate::package::{
+    extract_scripts, hash_file, parse_alternatives, parse_triggers, plan_steps, AlternativeSpec, Conffile,
+    ConffilePlan, Diversions, InstalledDatabase, MaintainerScript, Package, PackageArchive,
+    PackageManager, PackageState, ScriptRunner, Step, Triggers,
+};
+use crate::core::{DependencyResolver, Solution};
+use crate::utils::{copy_dir_contents, ensure_dir_exists, move_path, remove_empty_parents, with_suffix};
+
+/// A filesystem change made while committing, recorded so it can be undone.
+enum JournalEntry {
+    Created(PathBuf),
+    CreatedDir(PathBuf),
+    Moved { from: PathBuf, to: PathBuf, backup: bool },
+}
+
+/// Records every change a transaction makes outside its staging area.
+#[derive(Default)]
+struct Journal {
+    entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    /// Creates `path` and any missing parents, remembering which ones are new.
+    fn create_dir_all(&mut self, path: &Path) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut current = Some(path);
+
+        while let Some(dir) = current {
+            if fs::symlink_metadata(dir).is_ok() {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+            current = dir.parent();
+        }
+
+        for dir in missing.into_iter().rev() {
+            fs::create_dir(&dir)?;
+            self.entries.push(JournalEntry::CreatedDir(dir));
+        }
+
+        Ok(())
+    }
+
+    /// Moves an existing path out of the way. The backup is restored on
+    /// rollback and deleted once the transaction completes.
+    fn move_aside(&mut self, path: &Path) -> Result<()> {
+        let backup = with_suffix(path, ".pkt-bak");
+        remove_path(&backup)?;
+        fs::rename(path, &backup)?;
+        self.entries.push(JournalEntry::Moved { from: path.to_path_buf(), to: backup, backup: true });
+        Ok(())
+    }
+
+    /// Keeps a copy of a file that is about to be rewritten in place.
+    fn backup_copy(&mut self, path: &Path) -> Result<()> {
+        let backup = with_suffix(path, ".pkt-bak");
+        fs::copy(path, &backup)?;
+        self.entries.push(JournalEntry::Moved { from: path.to_path_buf(), to: backup, backup: true });
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        if fs::symlink_metadata(to).is_ok() {
+            self.move_aside(to)?;
+        }
+        fs::rename(from, to)?;
+        self.entries.push(JournalEntry::Moved { from: from.to_path_buf(), to: to.to_path_buf(), backup: false });
+        Ok(())
+    }
+
+    /// Moves a staged file into place, backing up whatever it replaces.
+    fn place(&mut self, staged: &Path, target: &Path) -> Result<()> {
+        if let Some(parent) = target.parent() {
+            self.create_dir_all(parent)?;
+        }
+
+        if let Ok(metadata) = fs::symlink_metadata(target) {
+            if metadata.is_dir() {
+                return Err(anyhow!("Cannot replace directory {} with a file", target.display()));
+            }
+            self.move_aside(target)?;
+        }
+
+        move_path(staged, target)?;
+        self.entries.push(JournalEntry::Created(target.to_path_buf()));
+        Ok(())
+    }
+
+    fn record_created(&mut self, path: &Path) {
+        self.entries.push(JournalEntry::Created(path.to_path_buf()));
+    }
+
+    /// Undoes every recorded change, newest first.
+    fn rollback(&mut self) {
+        while let Some(entry) = self.entries.pop() {
+            let result = match &entry {
+                JournalEntry::Created(path) => remove_path(path),
+                JournalEntry::CreatedDir(path) => {
+                    let _ = fs::remove_dir(path);
+                    Ok(())
+                }
+                JournalEntry::Moved { from, to, .. } => fs::rename(to, from).map_err(Into::into),
+            };
+
+            if let Err(e) = result {
+                eprintln!("Warning: rollback step failed: {}", e);
+            }
+        }
+    }
+
+    /// Drops the backups once the transaction has succeeded.
+    fn finish(&mut self) {
+        for entry in self.entries.drain(..) {
+            if let JournalEntry::Moved { to, backup: true, .. } = entry {
+                if let Err(e) = remove_path(&to) {
+                    eprintln!("Warning: failed to remove backup {}: {}", to.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// What to install: a package from the repositories, or a package file
+/// given by path or URL.
+#[derive(Debug, Clone)]
+pub enum InstallRequest {
+    Repository { name: String, version: Option<String> },
+    File(PathBuf),
+    Url(String),
+}
+
+impl InstallRequest {
+    /// Parses a command line argument: `name`, `name@version`, a path to a
+    /// package file or an http(s) URL.
+    pub fn parse(argument: &str) -> Self {
+        if argument.starts_with("http://") || argument.starts_with("https://") {
+            return Self::Url(argument.to_string());
+        }
+
+        if argument.contains('/') || argument.ends_with(".deb") || argument.ends_with(".pkg") {
+            return Self::File(PathBuf::from(argument));
+        }
+
+        match argument.split_once('@') {
+            Some((name, version)) => Self::Repository { name: name.to_string(), version: Some(version.to_string()) },
+            None => Self::Repository { name: argument.to_string(), version: None },
+        }
+    }
+}
+
+/// What installing a set of requests changes, for the user to confirm.
+#[derive(Debug, Default)]
+pub struct InstallPlan {
+    /// The packages to install and the installed ones to remove
+    pub solution: Solution,
+    /// The request each requested package comes from, by package name
+    pub requests: HashMap<String, InstallRequest>,
+    /// Package files downloaded from a URL to plan with, removed along with
+    /// the plan
+    pub downloads: Vec<PathBuf>,
+    /// The installed version of each package to install, by package name
+    pub installed: HashMap<String, Package>,
+    /// Requested virtual packages and the package installed to provide each
+    pub providers: Vec<(String, String)>,
+    /// Requested virtual packages an installed package provides already
+    pub provided: Vec<(String, String)>,
+}
+
+impl Drop for InstallPlan {
+    fn drop(&mut self) {
+        for path in &self.downloads {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A package that has been downloaded, verified and unpacked into the staging area.
+struct StagedPackage {
+    package: Package,
+    previous: Option<Package>,
+    staging_dir: PathBuf,
+    files: Vec<PathBuf>,
+    /// Directories the package ships, with their permission bits
+    dirs: Vec<(PathBuf, u32)>,
+    conffiles: ConffilePlan,
+    transfers: Vec<(String, Vec<PathBuf>)>,
+    activates: Vec<String>,
+    alternatives: Vec<AlternativeSpec>,
+    preinst_run: bool,
+}
+
+impl StagedPackage {
+    fn data_dir(&self) -> PathBuf {
+        self.staging_dir.join("data")
+    }
+
+    fn scripts_dir(&self) -> PathBuf {
+        self.staging_dir.join("scripts")
+    }
+
+    /// Copy of the previous version's scripts, which must outlive its record.
+    fn old_scripts_dir(&self) -> PathBuf {
+        self.staging_dir.join("old-scripts")
+    }
+}
+
+/// Installs a set of packages as one unit, removing the installed packages
+/// they take the place of. Everything is downloaded, verified and unpacked
+/// into a staging area before the system is touched, then moved into place.
+/// If any step fails, removed and replaced files and package records are
+/// restored from backup.
+pub struct Transaction<'a> {
+    manager: &'a PackageManager,
+    staging_dir: PathBuf,
+    requests: Vec<InstallRequest>,
+    dependencies: HashSet<String>,
+    upgrades: HashSet<String>,
+    /// Installed packages to remove, keeping their configuration files
+    removals: Vec<String>,
+    /// The records of the packages being removed, and whether their prerm ran
+    removed: Vec<(Package, bool)>,
+    downloads: Vec<PathBuf>,
+    staged: Vec<StagedPackage>,
+    database: InstalledDatabase,
+    diversions: Diversions,
+    journal: Journal,
+    triggers: Triggers,
+    runner: ScriptRunner,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(manager: &'a PackageManager) -> Result<Self> {
+        let staging_dir = manager.install_dir()
+            .join("temp")
+            .join(format!("transaction-{}", std::process::id()));
+
+        Ok(Self {
+            manager,
+            staging_dir,
+            requests: Vec::new(),
+            dependencies: HashSet::new(),
+            upgrades: HashSet::new(),
+            removals: Vec::new(),
+            removed: Vec::new(),
+            downloads: Vec::new(),
+            staged: Vec::new(),
+            database: manager.database()?,
+            diversions: manager.diversions()?,
+            journal: Journal::default(),
+            triggers: Triggers::new(),
+            runner: manager.script_runner(),
+        })
+    }
+
+    pub fn add(&mut self, package_name: &str, version: Option<&str>) {
+        self.add_request(InstallRequest::Repository {
+            name: package_name.to_string(),
+            version: version.map(|v| v.to_string()),
+        });
+    }
+
+    pub fn add_request(&mut self, request: InstallRequest) {
+        self.requests.push(request);
+    }
+
+    /// Adds a package that is only installed to satisfy a dependency, so it
+    /// is marked as automatically installed unless it already is manual.
+    pub fn add_dependency(&mut self, package_name: &str, version: Option<&str>) {
+        self.add(package_name, version);
+        self.dependencies.insert(package_name.to_string());
+    }
+
+    /// Adds a new version of an installed package, keeping its manual or
+    /// automatic mark.
+    pub fn add_upgrade(&mut self, package_name: &str, version: Option<&str>) {
+        self.add(package_name, version);
+        self.upgrades.insert(package_name.to_string());
+    }
+
+    /// Adds an installed package to remove before the new packages are
+    /// unpacked, e.g. one they conflict with. Its configuration files stay.
+    pub fn add_removal(&mut self, package_name: &str) {
+        self.removals.push(package_name.to_string());
+    }
+
+    pub async fn run(mut self) -> Result<Vec<Package>> {
+        match self.execute().await {
+            Ok(()) => {
+                self.journal.finish();
+                self.cleanup();
+
+                // The backups of removed files are gone, so their directories may be empty now
+                for (package, _) in &self.removed {
+                    for path in &package.files {
+                        let on_disk = self.manager.root_path(&self.diversions.destination(path, &package.name));
+                        remove_empty_parents(&on_disk, self.manager.root_dir());
+                    }
+                }
+
+                // Everything is in place, so failing alternatives or triggers no longer undo the transaction
+                if let Err(e) = self.register_alternatives() {
+                    eprintln!("Warning: failed to update alternatives: {}", e);
+                }
+                if let Err(e) = self.manager.sync_user_bin() {
+                    eprintln!("Warning: failed to link commands: {}", e);
+                }
+                if let Err(e) = self.triggers.run(self.manager, &self.database, &self.runner) {
+                    eprintln!("Warning: failed to process triggers: {}", e);
+                }
+
+                Ok(self.staged.into_iter().map(|s| s.package).collect())
+            }
+            Err(e) => {
+                if !self.journal.entries.is_empty() {
+                    println!("Rolling back transaction...");
+                }
+                self.journal.rollback();
+                self.abort_scripts();
+                self.cleanup();
+                Err(e)
+            }
+        }
+    }
+
+    async fn execute(&mut self) -> Result<()> {
+        ensure_dir_exists(&self.staging_dir)?;
+
+        // Packages to remove count as removed for the checks below
+        for name in self.removals.clone() {
+            let package = self.database.get(&name)
+                .filter(|p| p.is_present())
+                .cloned()
+                .ok_or_else(|| anyhow!("Package '{}' is not installed", name))?;
+            let mut record = package.clone();
+            record.files.retain(|path| package.is_conffile(path));
+            record.state = PackageState::ConfigFiles;
+            self.database.insert(record);
+            self.removed.push((package, false));
+        }
+
+        let requests = self.requests.clone();
+        for request in &requests {
+            let staged = self.stage(request).await?;
+            self.staged.push(staged);
+        }
+
+        self.check_transaction_conflicts()?;
+        self.check_relations()?;
+
+        // The database is rewritten in place as packages progress, keep the original
+        let database_path = self.database.path().to_path_buf();
+        if database_path.exists() {
+            self.journal.backup_copy(&database_path)?;
+        } else {
+            self.journal.record_created(&database_path);
+        }
+
+        for index in 0..self.removed.len() {
+            self.remove(index)?;
+        }
+
+        // Pre-Depends have to be configured before the package is unpacked
+        let packages: Vec<Package> = self.staged.iter().map(|s| s.package.clone()).collect();
+        for step in plan_steps(&packages) {
+            match step {
+                Step::Unpack(index) => self.commit(index)?,
+                Step::Configure(index) => self.configure(index)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Downloads, verifies and unpacks a package into the staging area.
+    async fn stage(&mut self, request: &InstallRequest) -> Result<StagedPackage> {
+        let (archive, package) = match request {
+            InstallRequest::Repository { name, version } => {
+                let (download_path, package) = self.manager.download_package(name, version.as_deref()).await?;
+                self.downloads.push(download_path.clone());
+                let archive = PackageArchive::open(&download_path)?;
+                verify_archive(&archive, &package)?;
+                (archive, package)
+            }
+            InstallRequest::File(path) => local_package(PackageArchive::open(path)?)?,
+            InstallRequest::Url(url) => {
+                let download_path = self.manager.download_package_file(url).await?;
+                self.downloads.push(download_path.clone());
+                local_package(PackageArchive::open(&download_path)?)?
+            }
+        };
+        let staging_dir = self.staging_dir.join(&package.name);
+
+        self.manager.check_user_installable(&archive, &package)?;
+
+        // Both would live in packages_dir/<name>/<version>
+        if self.manager.versions()?.get(&package.name).map(|p| p.versions.contains_key(&package.version)).unwrap_or(false) {
+            return Err(anyhow!("{} {} is installed side by side, remove it first with 'pkt remove {}@{}'",
+                package.name, package.version, package.name, package.version));
+        }
+
+        let mut package = package;
+        let triggers = parse_triggers(&archive.control_file("triggers")?.unwrap_or_default());
+        package.trigger_interests = triggers.interests;
+
+        // Make sure no other installed package owns the files we are about to write
+        let transfers = self.manager.check_file_conflicts(&self.database, &self.diversions, &package, &archive.data_paths()?)?;
+
+        // A removed package that left its configuration behind is installed
+        // fresh, but its conffiles are still handled as an upgrade would
+        let record = self.database.get(&package.name).cloned();
+        let conffiles = self.manager.plan_conffiles(&archive, record.as_ref())?;
+        let previous = record.filter(|p| p.is_present());
+
+        println!("Unpacking {} ({})...", package.name, package.version);
+
+        let mut staged = StagedPackage {
+            package,
+            previous,
+            staging_dir,
+            files: Vec::new(),
+            dirs: archive.data_dirs()?,
+            conffiles,
+            transfers,
+            activates: triggers.activates,
+            alternatives: parse_alternatives(&archive.control_file("alternatives")?.unwrap_or_default())?,
+            preinst_run: false,
+        };
+
+        extract_scripts(&archive, &staged.scripts_dir())?;
+        if let Some(previous) = &staged.previous {
+            if previous.scripts_dir().exists() {
+                copy_dir_contents(&previous.scripts_dir(), &staged.old_scripts_dir())?;
+            }
+        }
+
+        let conffiles = &staged.conffiles;
+        staged.files = archive.unpack_data(&staged.data_dir(), |path| conffiles.destination(path))?;
+
+        Ok(staged)
+    }
+
+    /// Refuses staged packages that conflict with or break each other or the
+    /// installed packages. The plan was made with these relations already,
+    /// but the installed packages may have changed since.
+    fn check_relations(&self) -> Result<()> {
+        let mut resolver = DependencyResolver::new(self.manager.index());
+        resolver.load_installed_packages(&self.database)?;
+
+        let packages: Vec<Package> = self.staged.iter().map(|s| s.package.clone()).collect();
+        let conflicts = resolver.check_conflicts(&packages)?;
+        if !conflicts.is_empty() {
+            return Err(anyhow!("Conflicting packages:\n  {}", conflicts.join("\n  ")));
+        }
+
+        Ok(())
+    }
+
+    /// Refuses two packages of the same transaction shipping the same file,
+    /// unless one of them replaces the other.
+    fn check_transaction_conflicts(&self) -> Result<()> {
+        let mut owners: HashMap<&Path, &Package> = HashMap::new();
+        let mut conflicts = Vec::new();
+
+        for staged in &self.staged {
+            for path in &staged.files {
+                if let Some(owner) = owners.get(path.as_path()) {
+                    if !staged.package.replaces_package(owner) && !owner.replaces_package(&staged.package) {
+                        conflicts.push(format!("{} would overwrite {} also shipped by {}",
+                            staged.package.name, path.display(), owner.name));
+                    }
+                }
+                owners.insert(path, &staged.package);
+            }
+        }
+
+        if !conflicts.is_empty() {
+            return Err(anyhow!("File conflicts detected:\n  {}", conflicts.join("\n  ")));
+        }
+
+        Ok(())
+    }
+
+    /// Registers the alternatives the staged packages declare, dropping the
+    /// providers of earlier versions whose path is no longer shipped and of
+    /// removed packages.
+    fn register_alternatives(&self) -> Result<()> {
+        self.manager.change_alternatives(|alternatives| {
+            for (package, _) in &self.removed {
+                alternatives.remove_package(&package.name);
+            }
+            for staged in &self.staged {
+                alternatives.retain_package(&staged.package.name, |p| staged.package.files.contains(&p.path));
+                for spec in &staged.alternatives {
+                    alternatives.install(spec, Some(&staged.package.name))?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes an installed package, moving its files aside so a rollback
+    /// can bring them back. Its record was marked removed already.
+    fn remove(&mut self, index: usize) -> Result<()> {
+        let Self { manager, removed, database, diversions, journal, triggers, runner, .. } = self;
+        let (package, prerm_run) = &mut removed[index];
+        let scripts_dir = package.scripts_dir();
+
+        println!("Removing {} ({})...", package.name, package.version);
+
+        if let Err(e) = runner.run(&scripts_dir, MaintainerScript::Prerm, package, &["remove"]) {
+            let _ = runner.run(&scripts_dir, MaintainerScript::Postinst, package, &["abort-remove"]);
+            return Err(e);
+        }
+        *prerm_run = true;
+
+        for path in package.files.iter().filter(|path| !package.is_conffile(path)) {
+            let on_disk = manager.root_path(&diversions.destination(path, &package.name));
+            if fs::symlink_metadata(&on_disk).map(|m| !m.is_dir()).unwrap_or(false) {
+                journal.move_aside(&on_disk)?;
+            }
+            triggers.activate_path(path);
+        }
+
+        runner.run(&scripts_dir, MaintainerScript::Postrm, package, &["remove"])?;
+        database.save()
+    }
+
+    /// Runs the pre-unpack scripts and moves the staged files into place.
+    fn commit(&mut self, index: usize) -> Result<()> {
+        let Self { manager, staged, dependencies, upgrades, database, diversions, journal, triggers, runner, .. } = self;
+        let staged = &mut staged[index];
+        let package = &staged.package;
+        let old_version = staged.previous.as_ref().map(|p| p.version.clone());
+
+        if let Some(previous) = &staged.previous {
+            if let Err(e) = runner.run(&staged.old_scripts_dir(), MaintainerScript::Prerm, previous, &["upgrade", &package.version]) {
+                let _ = runner.run(&staged.old_scripts_dir(), MaintainerScript::Postinst, previous, &["abort-upgrade", &package.version]);
+                return Err(e);
+            }
+        }
+
+        let leftover = database.get(&package.name).filter(|p| !p.is_present()).cloned();
+        let preinst_args = match (&old_version, &leftover) {
+            (Some(old_version), _) => vec!["upgrade", old_version.as_str()],
+            (None, Some(leftover)) => vec!["install", leftover.version.as_str()],
+            (None, None) => vec!["install"],
+        };
+        staged.preinst_run = true;
+        runner.run(&staged.scripts_dir(), MaintainerScript::Preinst, package, &preinst_args)?;
+
+        // Keep the scripts of the new version, replacing them if it is a reinstall
+        let install_path = manager.install_dir().join(&package.name).join(&package.version);
+        if install_path.exists() {
+            journal.move_aside(&install_path)?;
+        }
+        if let Some(leftover) = &leftover {
+            if leftover.install_path != install_path && leftover.install_path.exists() {
+                journal.move_aside(&leftover.install_path)?;
+            }
+        }
+        journal.create_dir_all(&install_path)?;
+
+        // Explicitly requested packages become manual, others keep their mark
+        let auto_installed = match &staged.previous {
+            Some(previous) if upgrades.contains(&package.name) || dependencies.contains(&package.name) => previous.auto_installed,
+            Some(_) => false,
+            None => dependencies.contains(&package.name),
+        };
+
+        let mut installed_package = Package {
+            install_path: install_path.clone(),
+            files: staged.files.clone(),
+            conffiles: staged.conffiles.conffiles.clone(),
+            state: PackageState::HalfInstalled,
+            auto_installed,
+            install_date: SystemTime::now(),
+            ..package.clone()
+        };
+
+        database.insert(installed_package.clone());
+
+        for (owner, paths) in &staged.transfers {
+            println!("Taking over {} file(s) from replaced package {}", paths.len(), owner);
+            database.disown_files(owner, paths);
+        }
+        database.save()?;
+
+        for path in &staged.conffiles.replace_old {
+            let on_disk = manager.root_path(&diversions.destination(path, &package.name));
+            journal.rename(&on_disk, &Conffile::old_path(&on_disk))?;
+        }
+
+        // Directories are created first so they get their own permissions, empty ones included
+        for (dir, mode) in &staged.dirs {
+            let on_disk = manager.root_path(dir);
+            if fs::symlink_metadata(&on_disk).is_err() {
+                journal.create_dir_all(&on_disk)?;
+                fs::set_permissions(&on_disk, fs::Permissions::from_mode(*mode))?;
+            }
+        }
+
+        for path in &staged.files {
+            if let Some(destination) = staged.conffiles.destination(path) {
+                let staged_file = staged.data_dir().join(destination.strip_prefix("/")?);
+                let on_disk = manager.root_path(&diversions.destination(&destination, &package.name));
+                journal.place(&staged_file, &on_disk)?;
+            }
+            triggers.activate_path(path);
+        }
+
+        for name in &staged.activates {
+            triggers.activate(name);
+        }
+
+        if let Some(previous) = &staged.previous {
+            // Files the new version no longer ships go away, obsolete conffiles stay
+            for path in &previous.files {
+                let on_disk = manager.root_path(&diversions.destination(path, &previous.name));
+                if !staged.files.contains(path) && !previous.is_conffile(path) && fs::symlink_metadata(&on_disk).is_ok() {
+                    journal.move_aside(&on_disk)?;
+                    triggers.activate_path(path);
+                }
+            }
+
+            if let Err(e) = runner.run(&staged.old_scripts_dir(), MaintainerScript::Postrm, previous, &["upgrade", &package.version]) {
+                eprintln!("Warning: {}", e);
+            }
+
+            // The new version now owns the files, so drop the old version's scripts
+            if previous.install_path != install_path && previous.install_path.exists() {
+                journal.move_aside(&previous.install_path)?;
+            }
+        }
+
+        let scripts_dir = installed_package.scripts_dir();
+        copy_dir_contents(&staged.scripts_dir(), &scripts_dir)?;
+        journal.record_created(&scripts_dir);
+
+        installed_package.state = PackageState::Unpacked;
+        database.insert(installed_package.clone());
+        database.save()?;
+
+        staged.package = installed_package;
+        Ok(())
+    }
+
+    fn configure(&mut self, index: usize) -> Result<()> {
+        let Self { staged, database, runner, .. } = self;
+        let staged = &mut staged[index];
+        let package = &mut staged.package;
+
+        println!("Setting up {} ({})...", package.name, package.version);
+
+        package.state = PackageState::HalfConfigured;
+        database.insert(package.clone());
+        database.save()?;
+
+        let configured_version = staged.previous.as_ref().map(|p| p.version.clone()).unwrap_or_default();
+        runner.run(&package.scripts_dir(), MaintainerScript::Postinst, package, &["configure", &configured_version])?;
+
+        package.state = PackageState::Installed;
+        database.insert(package.clone());
+        database.save()?;
+
+        Ok(())
+    }
+
+    /// Lets the maintainer scripts of packages whose preinst already ran
+    /// clean up after a failed transaction.
+    fn abort_scripts(&self) {
+        for staged in self.staged.iter().rev().filter(|s| s.preinst_run) {
+            let abort_args = match &staged.previous {
+                Some(previous) => vec!["abort-upgrade", previous.version.as_str()],
+                None => vec!["abort-install"],
+            };
+
+            if let Err(e) = self.runner.run(&staged.scripts_dir(), MaintainerScript::Postrm, &staged.package, &abort_args) {
+                eprintln!("Warning: {}", e);
+            }
+
+            if let Some(previous) = &staged.previous {
+                let _ = self.runner.run(&staged.old_scripts_dir(), MaintainerScript::Postinst, previous, &["abort-upgrade", &staged.package.version]);
+            }
+        }
+
+        // Removed packages have their files back and are set up again
+        for (package, _) in self.removed.iter().rev().filter(|(_, prerm_run)| *prerm_run) {
+            if let Err(e) = self.runner.run(&package.scripts_dir(), MaintainerScript::Postinst, package, &["abort-remove"]) {
+                eprintln!("Warning: {}", e);
+            }
+        }
+    }
+
+    fn cleanup(&self) {
+        for download in &self.downloads {
+            let _ = fs::remove_file(download);
+        }
+        let _ = fs::remove_dir_all(&self.staging_dir);
+    }
+}
+
+/// Checks that a downloaded file is the package it claims to be: it has the
+/// checksum the repository index lists, and its control file names it.
+pub(crate) fn verify_archive(archive: &PackageArchive, package: &Package) -> Result<()> {
+    println!("Verifying {} ({})...", package.name, package.version);
+
+    if package.sha256.is_empty() {
+        return Err(anyhow!("{} ({}) has no SHA256 checksum in the repository index", package.name, package.version));
+    }
+    let sha256 = hash_file(archive.path())?;
+    if !sha256.eq_ignore_ascii_case(&package.sha256) {
+        return Err(anyhow!("Downloaded file for {} ({}) has SHA256 {}, the repository index lists {}",
+            package.name, package.version, sha256, package.sha256));
+    }
+
+    let fields = archive.control_fields()?;
+
+    if fields.get("Package") != Some(&package.name) {
+        return Err(anyhow!("Downloaded file for {} contains package {}",
+            package.name, fields.get("Package").map(|s| s.as_str()).unwrap_or("<unknown>")));
+    }
+
+    if fields.get("Version") != Some(&package.version) {
+        return Err(anyhow!("Downloaded file for {} has version {} instead of {}",
+            package.name, fields.get("Version").map(|s| s.as_str()).unwrap_or("<unknown>"), package.version));
+    }
+
+    // Reading the file list walks the whole data archive
+    archive.data_paths()?;
+
+    Ok(())
+}
+
+/// Reads the metadata of a package file that does not come from a repository.
+pub(crate) fn local_package(archive: PackageArchive) -> Result<(PackageArchive, Package)> {
+    let mut package = archive.package()?;
+    package.origin = "local".to_string();
+    println!("Verifying {} ({}) from {}...", package.name, package.version, archive.path().display());
+    Ok((archive, package))
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::tests::build_deb;
+    use crate::package::{hash_data, PackageInfo};
+
+    #[test]
+    fn rolls_back_placed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (staged, root) = (dir.path().join("staged"), dir.path().join("root"));
+        fs::create_dir_all(&staged).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/old.conf"), "old").unwrap();
+        fs::write(staged.join("old.conf"), "new").unwrap();
+        fs::write(staged.join("tool"), "tool").unwrap();
+
+        let mut journal = Journal::default();
+        journal.place(&staged.join("old.conf"), &root.join("etc/old.conf")).unwrap();
+        journal.place(&staged.join("tool"), &root.join("usr/bin/tool")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("etc/old.conf")).unwrap(), "new");
+
+        journal.rollback();
+
+        assert_eq!(fs::read_to_string(root.join("etc/old.conf")).unwrap(), "old");
+        assert!(!root.join("etc/old.conf.pkt-bak").exists());
+        assert!(!root.join("usr").exists());
+        assert!(root.join("etc").is_dir());
+    }
+
+    #[test]
+    fn drops_backups_when_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        fs::write(&target, "old").unwrap();
+        fs::write(dir.path().join("staged"), "new").unwrap();
+
+        let mut journal = Journal::default();
+        journal.place(&dir.path().join("staged"), &target).unwrap();
+        journal.finish();
+        journal.rollback();
+
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(!with_suffix(&target, ".pkt-bak").exists());
+    }
+
+    #[test]
+    fn restores_files_rewritten_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("installed.json");
+        fs::write(&database, "before").unwrap();
+
+        let mut journal = Journal::default();
+        journal.backup_copy(&database).unwrap();
+        fs::write(&database, "after").unwrap();
+        journal.rollback();
+
+        assert_eq!(fs::read_to_string(&database).unwrap(), "before");
+    }
+
+    #[test]
+    fn refuses_to_replace_a_directory_with_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join("staged"), "file").unwrap();
+
+        let mut journal = Journal::default();
+        assert!(journal.place(&dir.path().join("staged"), &dir.path().join("target")).is_err());
+        assert!(dir.path().join("target").is_dir());
+    }
+
+    fn indexed(name: &str, version: &str, sha256: String) -> Package {
+        let mut info = PackageInfo::new(name.to_string(), version.to_string(), "amd64".to_string(), String::new());
+        info.sha256 = sha256;
+        Package::new(info, PathBuf::new())
+    }
+
+    #[test]
+    fn verifies_downloads_against_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.deb");
+        build_deb(&path, &[("control", "Package: hello\nVersion: 1.0\nArchitecture: amd64\n")], &[("/usr/bin/hello", Some("hi"), 0o755)]);
+        let archive = PackageArchive::open(&path).unwrap();
+        let sha256 = hash_file(&path).unwrap();
+
+        assert!(verify_archive(&archive, &indexed("hello", "1.0", sha256.clone())).is_ok());
+        assert!(verify_archive(&archive, &indexed("hello", "1.0", sha256.to_uppercase())).is_ok());
+        assert!(verify_archive(&archive, &indexed("hello", "1.0", String::new())).is_err());
+        assert!(verify_archive(&archive, &indexed("hello", "1.0", hash_data(b"other"))).is_err());
+        assert!(verify_archive(&archive, &indexed("hello", "2.0", sha256.clone())).is_err());
+        assert!(verify_archive(&archive, &indexed("goodbye", "1.0", sha256)).is_err());
+    }
+
+    /// A package shipping `/usr/bin/tool` with `content`, plus `fields`.
+    fn tool_deb(dir: &Path, name: &str, content: &str, fields: &str) -> InstallRequest {
+        let deb = dir.join(format!("{}.deb", name));
+        build_deb(&deb, &[("control", &format!("Package: {}\nVersion: 1.0\nArchitecture: all\nDescription: {}\n{}", name, name, fields))], &[
+            ("/usr", None, 0o755),
+            ("/usr/bin", None, 0o755),
+            ("/usr/bin/tool", Some(content), 0o755),
+        ]);
+        InstallRequest::File(deb)
+    }
+
+    #[tokio::test]
+    async fn takes_over_files_of_replaced_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new()).with_root_dir(root.clone());
+        let plan = manager.plan_install(&[tool_deb(dir.path(), "old-tool", "old", "")]).await.unwrap();
+        manager.install_packages(&plan).await.unwrap();
+
+        let plan = manager.plan_install(&[tool_deb(dir.path(), "new-tool", "new", "")]).await.unwrap();
+        let error = manager.install_packages(&plan).await.unwrap_err();
+        assert!(error.to_string().contains("new-tool would overwrite /usr/bin/tool owned by old-tool"), "{}", error);
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool")).unwrap(), "old");
+
+        let plan = manager.plan_install(&[tool_deb(dir.path(), "new-tool", "new", "Replaces: old-tool (<< 2)\n")]).await.unwrap();
+        manager.install_packages(&plan).await.unwrap();
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool")).unwrap(), "new");
+
+        let database = manager.database().unwrap();
+        assert_eq!(database.owner_of(Path::new("/usr/bin/tool")).unwrap().name, "new-tool");
+        assert!(database.get("old-tool").unwrap().files.is_empty());
+
+        // Removing the replaced package leaves the file it gave up alone
+        manager.remove_packages(&["old-tool".to_string()], false).unwrap();
+        assert_eq!(fs::read_to_string(root.join("usr/bin/tool")).unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn refuses_packages_of_one_transaction_shipping_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let manager = PackageManager::new(dir.path().join("packages"), Vec::new()).with_root_dir(root.clone());
+        let requests = [tool_deb(dir.path(), "tool-a", "a", ""), tool_deb(dir.path(), "tool-b", "b", "")];
+
+        let plan = manager.plan_install(&requests).await.unwrap();
+        let error = manager.install_packages(&plan).await.unwrap_err();
+        assert!(error.to_string().contains("would overwrite /usr/bin/tool also shipped by"), "{}", error);
+        assert!(!root.join("usr/bin/tool").exists());
+        assert!(manager.database().unwrap().packages().next().is_none());
+    }
+
+    #[test]
+    fn parses_install_requests() {
+        let parse = |argument: &str| format!("{:?}", InstallRequest::parse(argument));
+
+        assert_eq!(parse("vim"), r#"Repository { name: "vim", version: None }"#);
+        assert_eq!(parse("vim@2:9.0-1"), r#"Repository { name: "vim", version: Some("2:9.0-1") }"#);
+        assert_eq!(parse("./vim.deb"), r#"File("./vim.deb")"#);
+        assert_eq!(parse("vim.deb"), r#"File("vim.deb")"#);
+        assert_eq!(parse("https://example.org/vim.deb"), r#"Url("https://example.org/vim.deb")"#);
+    }
+}
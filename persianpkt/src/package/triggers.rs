@@ -0,0 +1,350 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::package::{InstalledDatabase, MaintainerScript, PackageManager, ScriptRunner};
+
+/// The `triggers` control file of a package: triggers it is interested in
+/// (named triggers or path prefixes) and named triggers it activates.
+#[derive(Debug, Clone, Default)]
+pub struct PackageTriggers {
+    pub interests: Vec<String>,
+    pub activates: Vec<String>,
+}
+
+/// Parses the `interest` and `activate` directives of a `triggers` control file.
+pub fn parse_triggers(content: &str) -> PackageTriggers {
+    let mut triggers = PackageTriggers::default();
+
+    for line in content.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let mut parts = line.split_whitespace();
+        let (directive, name) = match (parts.next(), parts.next()) {
+            (Some(directive), Some(name)) => (directive, name.to_string()),
+            _ => continue,
+        };
+
+        match directive {
+            "interest" | "interest-await" | "interest-noawait" => triggers.interests.push(name),
+            "activate" | "activate-await" | "activate-noawait" => triggers.activates.push(name),
+            _ => log::warn!("Ignoring unknown trigger directive: {}", directive),
+        }
+    }
+
+    triggers
+}
+
+/// A trigger defined in a `triggers.d` file, e.g.
+///
+/// ```toml
+/// name = "ldconfig"
+/// paths = ["/usr/lib", "/lib"]
+/// command = "ldconfig"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerConfig {
+    pub name: String,
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Handler {
+    Command { name: String, command: String },
+    /// An installed package interested in the trigger, whose postinst is
+    /// run with `triggered`
+    Package(String),
+}
+
+impl Handler {
+    fn name(&self) -> &str {
+        match self {
+            Self::Command { name, .. } => name,
+            Self::Package(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerFailure {
+    pub triggers: Vec<String>,
+    pub error: String,
+    pub time: SystemTime,
+}
+
+/// Trigger handlers that failed, kept in `triggers.json` next to the
+/// installed package database and retried by the next operation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TriggerState {
+    pub failed: BTreeMap<String, TriggerFailure>,
+}
+
+impl TriggerState {
+    pub const FILE_NAME: &'static str = "triggers.json";
+
+    pub fn load(packages_dir: &Path) -> Result<Self> {
+        let path = packages_dir.join(Self::FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content).map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, packages_dir: &Path) -> Result<()> {
+        let path = packages_dir.join(Self::FILE_NAME);
+        if self.failed.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Collects trigger activations while packages are installed or removed,
+/// then runs each interested handler once.
+#[derive(Debug, Default)]
+pub struct Triggers {
+    paths: BTreeSet<PathBuf>,
+    names: BTreeSet<String>,
+}
+
+impl Triggers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a file below one or more path triggers was installed or removed.
+    pub fn activate_path(&mut self, path: &Path) {
+        self.paths.insert(path.to_path_buf());
+    }
+
+    pub fn activate(&mut self, name: &str) {
+        self.names.insert(name.to_string());
+    }
+
+    /// Runs every handler interested in the collected activations, plus the
+    /// handlers that failed last time. Failures are recorded in the trigger
+    /// state rather than failing the operation, since the packages
+    /// themselves are already in place.
+    pub fn run(&self, manager: &PackageManager, database: &InstalledDatabase, runner: &ScriptRunner) -> Result<()> {
+        let configs = load_trigger_configs(&manager.triggers_dir())?;
+        let mut state = TriggerState::load(manager.install_dir())?;
+        let mut pending: BTreeMap<Handler, BTreeSet<String>> = BTreeMap::new();
+
+        for config in &configs {
+            let handler = Handler::Command { name: config.name.clone(), command: config.command.clone() };
+            if self.names.contains(&config.name) {
+                pending.entry(handler.clone()).or_default().insert(config.name.clone());
+            }
+            if config.paths.iter().any(|prefix| self.paths.iter().any(|path| path.starts_with(prefix))) {
+                pending.entry(handler).or_default().insert(config.name.clone());
+            }
+        }
+
+        for package in database.packages().filter(|p| p.is_present()) {
+            for interest in &package.trigger_interests {
+                let activated = if interest.starts_with('/') {
+                    self.paths.iter().any(|path| path.starts_with(interest))
+                } else {
+                    self.names.contains(interest)
+                };
+
+                if activated {
+                    pending.entry(Handler::Package(package.name.clone())).or_default().insert(interest.clone());
+                }
+            }
+        }
+
+        // Retry what failed before, as long as its handler still exists
+        for (name, failure) in std::mem::take(&mut state.failed) {
+            let handler = configs.iter()
+                .find(|c| c.name == name)
+                .map(|c| Handler::Command { name: c.name.clone(), command: c.command.clone() })
+                .or_else(|| database.get(&name).filter(|p| p.is_present()).map(|p| Handler::Package(p.name.clone())));
+
+            if let Some(handler) = handler {
+                pending.entry(handler).or_default().extend(failure.triggers);
+            }
+        }
+
+        for (handler, triggers) in pending {
+            let names: Vec<String> = triggers.into_iter().collect();
+            println!("Processing triggers for {} ({})...", handler.name(), names.join(" "));
+
+            let result = match &handler {
+                Handler::Command { command, .. } => runner.run_command(command),
+                Handler::Package(name) => match database.get(name) {
+                    Some(package) => runner.run(&package.scripts_dir(), MaintainerScript::Postinst, package, &["triggered", &names.join(" ")]),
+                    None => Ok(()),
+                },
+            };
+
+            if let Err(e) = result {
+                eprintln!("Warning: trigger {} failed: {}", handler.name(), e);
+                state.failed.insert(handler.name().to_string(), TriggerFailure {
+                    triggers: names,
+                    error: e.to_string(),
+                    time: SystemTime::now(),
+                });
+            }
+        }
+
+        state.save(manager.install_dir())
+    }
+}
+
+/// Reads every `*.toml` file in `triggers_dir`.
+pub fn load_trigger_configs(triggers_dir: &Path) -> Result<Vec<TriggerConfig>> {
+    let mut configs = Vec::new();
+    if !triggers_dir.exists() {
+        return Ok(configs);
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(triggers_dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|ext| ext == "toml").unwrap_or(false))
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let content = fs::read_to_string(&path)?;
+        let config: TriggerConfig = toml::from_str(&content)
+            .map_err(|e| anyhow!("Invalid trigger file {}: {}", path.display(), e))?;
+        configs.push(config);
+    }
+
+    Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::{Package, PackageInfo};
+    use std::os::unix::fs::PermissionsExt;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        manager: PackageManager,
+        runner: ScriptRunner,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let manager = PackageManager::new(dir.path().join("packages"), Vec::new())
+                .with_triggers_dir(dir.path().join("triggers.d"));
+            let runner = ScriptRunner::new(dir.path().to_path_buf(), dir.path().join("packages")).with_user_mode();
+            fs::create_dir_all(dir.path().join("packages")).unwrap();
+            fs::create_dir_all(dir.path().join("triggers.d")).unwrap();
+            Self { dir, manager, runner }
+        }
+
+        fn define(&self, name: &str, paths: &[&str], command: &str) {
+            let config = TriggerConfig {
+                name: name.to_string(),
+                paths: paths.iter().map(PathBuf::from).collect(),
+                command: command.to_string(),
+            };
+            fs::write(self.dir.path().join("triggers.d").join(format!("{}.toml", name)), toml::to_string(&config).unwrap()).unwrap();
+        }
+
+        fn run(&self, triggers: &Triggers, database: &InstalledDatabase) {
+            triggers.run(&self.manager, database, &self.runner).unwrap();
+        }
+
+        fn database(&self) -> InstalledDatabase {
+            self.manager.database().unwrap()
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.dir.path().join(name)).unwrap_or_default()
+        }
+    }
+
+    #[test]
+    fn parses_trigger_directives() {
+        let triggers = parse_triggers("\
+# comment
+interest /usr/share/icons
+interest-noawait ldconfig
+activate-await update-menus
+bogus directive
+activate
+");
+
+        assert_eq!(triggers.interests, ["/usr/share/icons", "ldconfig"]);
+        assert_eq!(triggers.activates, ["update-menus"]);
+    }
+
+    #[test]
+    fn runs_each_command_once_per_operation() {
+        let fixture = Fixture::new();
+        fixture.define("ldconfig", &["/usr/lib"], "echo ran >> ldconfig.log");
+        fixture.define("menus", &["/usr/share/menu"], "echo ran >> menus.log");
+
+        let mut triggers = Triggers::new();
+        triggers.activate_path(Path::new("/usr/lib/libfoo.so.1"));
+        triggers.activate_path(Path::new("/usr/lib/libbar.so.2"));
+        triggers.activate("ldconfig");
+        fixture.run(&triggers, &fixture.database());
+
+        assert_eq!(fixture.read("ldconfig.log"), "ran\n");
+        assert_eq!(fixture.read("menus.log"), "");
+    }
+
+    #[test]
+    fn runs_interested_packages_with_their_triggers() {
+        let fixture = Fixture::new();
+        let install_path = fixture.dir.path().join("packages/icons/1.0");
+        fs::create_dir_all(install_path.join("scripts")).unwrap();
+        let postinst = install_path.join("scripts/postinst");
+        fs::write(&postinst, "#!/bin/sh\necho \"$@\" >> icons.log\n").unwrap();
+        fs::set_permissions(&postinst, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut database = fixture.database();
+        let mut package = Package::new(
+            PackageInfo::new("icons".to_string(), "1.0".to_string(), "all".to_string(), String::new()),
+            install_path,
+        );
+        package.trigger_interests = vec!["/usr/share/icons".to_string(), "icon-cache".to_string()];
+        database.insert(package);
+
+        let mut triggers = Triggers::new();
+        triggers.activate_path(Path::new("/usr/share/icons/hicolor/a.png"));
+        triggers.activate("icon-cache");
+        triggers.activate("unrelated");
+        fixture.run(&triggers, &database);
+
+        assert_eq!(fixture.read("icons.log"), "triggered /usr/share/icons icon-cache\n");
+    }
+
+    #[test]
+    fn retries_failed_handlers() {
+        let fixture = Fixture::new();
+        fixture.define("cache", &["/usr/share/cache"], "test -e ready && echo ran >> cache.log");
+
+        let mut triggers = Triggers::new();
+        triggers.activate_path(Path::new("/usr/share/cache/entry"));
+        fixture.run(&triggers, &fixture.database());
+
+        let state = TriggerState::load(fixture.manager.install_dir()).unwrap();
+        assert_eq!(state.failed["cache"].triggers, ["cache"]);
+        assert_eq!(fixture.read("cache.log"), "");
+
+        fs::write(fixture.dir.path().join("ready"), "").unwrap();
+        fixture.run(&Triggers::new(), &fixture.database());
+
+        assert_eq!(fixture.read("cache.log"), "ran\n");
+        assert!(TriggerState::load(fixture.manager.install_dir()).unwrap().failed.is_empty());
+        assert!(!fixture.manager.install_dir().join(TriggerState::FILE_NAME).exists());
+    }
+}
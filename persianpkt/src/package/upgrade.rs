@@ -0,0 +1,280 @@
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::core::{DependencyResolver, Preferences, Unsatisfiable};
+use crate::package::{compare_versions, InstalledDatabase, Package, PackageDependency};
+use crate::repository::PackageIndex;
+
+/// How many assignments explaining why a package is held back may try.
+const EXPLAIN_STEPS: usize = 10_000;
+
+/// The changes an upgrade would make, for the user to confirm.
+#[derive(Debug, Default)]
+pub struct UpgradePlan {
+    /// Installed packages and the versions they are upgraded to
+    pub upgrades: Vec<(Package, Package)>,
+    /// Packages newly installed to satisfy dependencies of upgraded packages
+    pub installs: Vec<Package>,
+    /// Installed packages to remove, and the relation that forces it
+    pub removals: Vec<(Package, String)>,
+    /// Packages with a newer version that cannot be upgraded, and why
+    pub held_back: Vec<(Package, String)>,
+    /// Recommends and Suggests of the new versions that are not installed
+    pub recommended: Vec<String>,
+    pub suggested: Vec<String>,
+    pub download_size: u64,
+}
+
+impl UpgradePlan {
+    pub fn is_empty(&self) -> bool {
+        self.upgrades.is_empty() && self.installs.is_empty() && self.removals.is_empty()
+    }
+}
+
+/// Works out how to bring installed packages up to their best candidates in
+/// `index`, letting the dependency solver prefer newer versions. In safe
+/// mode nothing is installed or removed, so an upgrade that needs either is
+/// held back; in full mode missing dependencies are installed and packages
+/// that conflict with or are broken by the upgrade are removed, as few as
+/// possible. Held packages keep their installed version, and virtual
+/// packages not provided yet are provided by the preferred provider where one
+/// is named. New Recommends, and Suggests if asked for, are installed in full
+/// mode only.
+pub fn plan_upgrade(
+    database: &InstalledDatabase,
+    index: &PackageIndex,
+    full: bool,
+    held_packages: &[String],
+    preferred_providers: &BTreeMap<String, String>,
+    install_recommends: bool,
+    install_suggests: bool,
+) -> Result<UpgradePlan> {
+    let preferences = Preferences {
+        keep_installed: false,
+        minimize_removals: true,
+        allow_removals: full,
+        allow_installs: full,
+        ask_providers: false,
+        install_recommends,
+        install_suggests,
+    };
+    let mut resolver = DependencyResolver::new(index)
+        .with_preferences(preferences)
+        .with_held_packages(held_packages)
+        .with_preferred_providers(preferred_providers.clone());
+    resolver.load_installed_packages(database)?;
+
+    let solution = resolver.solve(&[], &[])?;
+
+    let installed: BTreeMap<String, Package> = database.packages()
+        .filter(|p| p.is_present())
+        .map(|p| (p.name.clone(), p.clone()))
+        .collect();
+
+    let mut plan = UpgradePlan::default();
+
+    for package in solution.installs {
+        plan.download_size += package.size;
+        match installed.get(&package.name) {
+            Some(current) => plan.upgrades.push((current.clone(), package)),
+            None => plan.installs.push(package),
+        }
+    }
+    plan.removals = solution.removals.into_iter()
+        .map(|package| {
+            let reason = solution.reasons.get(&package.name).cloned().unwrap_or_default();
+            (package, reason)
+        })
+        .collect();
+    plan.recommended = solution.recommended;
+    plan.suggested = solution.suggested;
+
+    // Whatever blocks the best candidate of a package left behind is the
+    // reason. Other packages stay as they are and the search is kept short,
+    // as there is one for each such package.
+    let mut explainer = DependencyResolver::new(index)
+        .with_preferences(Preferences { keep_installed: true, install_recommends: false, install_suggests: false, ..preferences })
+        .with_held_packages(held_packages)
+        .with_preferred_providers(preferred_providers.clone())
+        .with_max_steps(EXPLAIN_STEPS);
+    explainer.load_installed_packages(database)?;
+
+    let changed: BTreeSet<&str> = plan.upgrades.iter().map(|(p, _)| p.name.as_str())
+        .chain(plan.removals.iter().map(|(p, _)| p.name.as_str()))
+        .collect();
+
+    for package in installed.values().filter(|p| !changed.contains(p.name.as_str())) {
+        let candidate = match index.best_candidate(&package.name) {
+            Some(candidate) if compare_versions(&candidate.info.version, &package.version) == Ordering::Greater => candidate,
+            _ => continue,
+        };
+
+        let reason = if held_packages.contains(&package.name) {
+            "held".to_string()
+        } else {
+            let request = PackageDependency::with_version_req(package.name.clone(), format!(">= {}", candidate.info.version));
+            match explainer.solve(&[request], &[]) {
+                Ok(solution) if !solution.removals.is_empty() => format!("upgrading it would remove {}",
+                    solution.removals.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(", ")),
+                Ok(_) => "upgrading it would keep back other packages".to_string(),
+                Err(e) => match e.downcast_ref::<Unsatisfiable>() {
+                    Some(unsatisfiable) => unsatisfiable.failures.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("; "),
+                    None => e.to_string(),
+                },
+            }
+        };
+
+        plan.held_back.push((package.clone(), reason));
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{IndexEntry, RepositorySource};
+    use std::path::Path;
+
+    /// An index of the packages described in `Packages` file syntax.
+    fn index(packages: &str) -> PackageIndex {
+        let mut index = PackageIndex::default();
+        for info in RepositorySource::parse_packages_file(packages).unwrap() {
+            index.insert(IndexEntry {
+                repository: "test".to_string(),
+                priority: 500,
+                base_url: url::Url::parse("http://localhost/debian/").unwrap(),
+                info,
+            });
+        }
+        index
+    }
+
+    fn installed(dir: &Path, index: &PackageIndex, packages: &[(&str, &str)]) -> InstalledDatabase {
+        let mut database = InstalledDatabase::open(dir).unwrap();
+        for (name, version) in packages {
+            database.insert(index.find(name, version).unwrap().to_package());
+        }
+        database
+    }
+
+    fn names<'a>(packages: impl Iterator<Item = &'a Package>) -> Vec<&'a str> {
+        packages.map(|p| p.name.as_str()).collect()
+    }
+
+    const UPGRADES: &str = "\
+Package: app
+Version: 1
+Architecture: all
+
+Package: app
+Version: 2
+Architecture: all
+Depends: lib (>= 2), libnew
+Recommends: extra
+
+Package: lib
+Version: 1
+Architecture: all
+
+Package: lib
+Version: 2
+Architecture: all
+Size: 100
+
+Package: libnew
+Version: 1
+Architecture: all
+
+Package: extra
+Version: 1
+Architecture: all
+
+Package: tool
+Version: 1
+Architecture: all
+
+Package: tool
+Version: 2
+Architecture: all
+Breaks: legacy (<< 2)
+
+Package: legacy
+Version: 1
+Architecture: all
+
+Package: pinned
+Version: 1
+Architecture: all
+
+Package: pinned
+Version: 2
+Architecture: all
+
+Package: orphan
+Version: 1
+Architecture: all
+Conflicts: libnew
+";
+
+    const INSTALLED: &[(&str, &str)] = &[("app", "1"), ("lib", "1"), ("tool", "1"), ("legacy", "1"), ("pinned", "1"), ("orphan", "1")];
+
+    fn upgrades(plan: &UpgradePlan) -> Vec<(&str, &str, &str)> {
+        plan.upgrades.iter().map(|(from, to)| (from.name.as_str(), from.version.as_str(), to.version.as_str())).collect()
+    }
+
+    fn reasons(list: &[(Package, String)]) -> Vec<(&str, &str)> {
+        list.iter().map(|(package, reason)| (package.name.as_str(), reason.as_str())).collect()
+    }
+
+    #[test]
+    fn safe_upgrades_hold_back_what_needs_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(UPGRADES);
+        let database = installed(dir.path(), &index, INSTALLED);
+
+        let plan = plan_upgrade(&database, &index, false, &["pinned".to_string()], &BTreeMap::new(), true, false).unwrap();
+
+        assert_eq!(upgrades(&plan), [("lib", "1", "2")]);
+        assert!(plan.installs.is_empty());
+        assert!(plan.removals.is_empty());
+        assert_eq!(plan.download_size, 100);
+        assert_eq!(reasons(&plan.held_back), [
+            ("app", "app 2 needs libnew, but libnew is not installed and new packages are not allowed"),
+            ("pinned", "held"),
+            ("tool", "legacy 1 is installed, but tool 2 breaks legacy (<< 2), and tool 2 is selected because it was requested"),
+        ]);
+    }
+
+    #[test]
+    fn full_upgrades_install_and_remove_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(UPGRADES);
+        let database = installed(dir.path(), &index, INSTALLED);
+
+        let plan = plan_upgrade(&database, &index, true, &["pinned".to_string()], &BTreeMap::new(), true, false).unwrap();
+
+        assert_eq!(upgrades(&plan), [("lib", "1", "2"), ("app", "1", "2")]);
+        assert_eq!(names(plan.installs.iter()), ["libnew", "extra"]);
+        assert_eq!(reasons(&plan.removals), [("orphan", "orphan 1 conflicts with libnew")]);
+        assert_eq!(reasons(&plan.held_back), [("pinned", "held"), ("tool", "upgrading it would remove legacy")]);
+
+        // Without install_recommends they are only named
+        let plan = plan_upgrade(&database, &index, true, &["pinned".to_string()], &BTreeMap::new(), false, false).unwrap();
+        assert_eq!(names(plan.installs.iter()), ["libnew"]);
+        assert_eq!(plan.recommended, ["extra"]);
+    }
+
+    #[test]
+    fn nothing_to_do_when_up_to_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(UPGRADES);
+        let database = installed(dir.path(), &index, &[("app", "2"), ("lib", "2"), ("libnew", "1")]);
+
+        let plan = plan_upgrade(&database, &index, true, &[], &BTreeMap::new(), false, false).unwrap();
+
+        assert!(plan.is_empty());
+        assert!(plan.held_back.is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Result, anyhow};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A version as defined by Debian policy: `[epoch:]upstream[-revision]`.
+///
+/// Versions are ordered by epoch, then upstream version, then revision,
+/// where the latter two compare alternating runs of non-digits and digits.
+/// Letters sort before other characters, and `~` sorts before anything,
+/// even the end of the string, so `1.0~rc1` is older than `1.0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DebianVersion {
+    pub epoch: u32,
+    pub upstream: String,
+    pub revision: String,
+}
+
+impl DebianVersion {
+    pub fn parse(version: &str) -> Result<Self> {
+        let version = version.trim();
+        if version.is_empty() {
+            return Err(anyhow!("Version string is empty"));
+        }
+        if version.contains(char::is_whitespace) {
+            return Err(anyhow!("Version {} contains whitespace", version));
+        }
+
+        let (epoch, rest) = match version.split_once(':') {
+            Some((epoch, rest)) => {
+                let epoch = epoch.parse()
+                    .map_err(|_| anyhow!("Version {} has an invalid epoch", version))?;
+                (epoch, rest)
+            }
+            None => (0, version),
+        };
+
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (upstream, revision),
+            None => (rest, ""),
+        };
+
+        if upstream.is_empty() {
+            return Err(anyhow!("Version {} has an empty upstream version", version));
+        }
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || ".+~-:".contains(c);
+        if !upstream.chars().all(valid) || !revision.chars().all(|c| valid(c) && c != '-' && c != ':') {
+            return Err(anyhow!("Version {} contains invalid characters", version));
+        }
+
+        Ok(Self {
+            epoch,
+            upstream: upstream.to_string(),
+            revision: revision.to_string(),
+        })
+    }
+}
+
+impl FromStr for DebianVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for DebianVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.upstream)?;
+        if !self.revision.is_empty() {
+            write!(f, "-{}", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for DebianVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch.cmp(&other.epoch)
+            .then_with(|| compare_parts(&self.upstream, &other.upstream))
+            .then_with(|| compare_parts(&self.revision, &other.revision))
+    }
+}
+
+impl PartialOrd for DebianVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders two version strings by Debian policy. Strings that are not valid
+/// versions are still compared with the same rules, as a whole.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (DebianVersion::parse(a), DebianVersion::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => compare_parts(a, b),
+    }
+}
+
+/// The relation of a versioned dependency, e.g. the `>=` in `libc6 (>= 2.36)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionOperator {
+    Earlier,
+    EarlierOrEqual,
+    Equal,
+    LaterOrEqual,
+    Later,
+}
+
+impl VersionOperator {
+    /// Splits a requirement such as `>= 2.36` or `(<< 3)` into its operator
+    /// and version. A bare version means an exact match; the obsolete `<` and
+    /// `>` mean `<=` and `>=` as in dpkg.
+    pub fn parse_requirement(requirement: &str) -> Result<(Self, String)> {
+        let requirement = requirement.trim().trim_start_matches('(').trim_end_matches(')').trim();
+
+        let (operator, version) = [
+            ("<<", Self::Earlier),
+            ("<=", Self::EarlierOrEqual),
+            (">=", Self::LaterOrEqual),
+            (">>", Self::Later),
+            ("=", Self::Equal),
+            ("<", Self::EarlierOrEqual),
+            (">", Self::LaterOrEqual),
+        ]
+            .iter()
+            .find_map(|(symbol, operator)| requirement.strip_prefix(symbol).map(|rest| (*operator, rest.trim())))
+            .unwrap_or((Self::Equal, requirement));
+
+        if version.is_empty() {
+            return Err(anyhow!("Version requirement '{}' has no version", requirement));
+        }
+
+        Ok((operator, version.to_string()))
+    }
+
+    /// Whether a version comparing as `ordering` against the required version
+    /// satisfies this relation.
+    pub fn matches(&self, ordering: Ordering) -> bool {
+        match self {
+            Self::Earlier => ordering == Ordering::Less,
+            Self::EarlierOrEqual => ordering != Ordering::Greater,
+            Self::Equal => ordering == Ordering::Equal,
+            Self::LaterOrEqual => ordering != Ordering::Less,
+            Self::Later => ordering == Ordering::Greater,
+        }
+    }
+}
+
+impl fmt::Display for VersionOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Self::Earlier => "<<",
+            Self::EarlierOrEqual => "<=",
+            Self::Equal => "=",
+            Self::LaterOrEqual => ">=",
+            Self::Later => ">>",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Weight of a non-digit character: `~` first, then the end of the string,
+/// then letters, then everything else.
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// Compares upstream versions or revisions the way dpkg's `verrevcmp` does.
+fn compare_parts(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let digit = |s: &[u8], k: usize| s.get(k).map(|c| c.is_ascii_digit()).unwrap_or(false);
+
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !digit(a, i)) || (j < b.len() && !digit(b, j)) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while digit(a, i) && digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        // The longer run of digits is the larger number
+        if digit(a, i) {
+            return Ordering::Greater;
+        }
+        if digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pairs compared by `dpkg --compare-versions`.
+    const VECTORS: &[(&str, &str, Ordering)] = &[
+        ("1.0", "1.0", Ordering::Equal),
+        ("1.0", "1.0-0", Ordering::Equal),
+        ("0:1.0", "1.0", Ordering::Equal),
+        ("1.0-1", "1.0-01", Ordering::Equal),
+        ("007", "7", Ordering::Equal),
+        ("1.0", "1.1", Ordering::Less),
+        ("1.9", "1.10", Ordering::Less),
+        ("1.0", "1.0.1", Ordering::Less),
+        ("1.2.3", "1.2.3.0", Ordering::Less),
+        ("1.0~rc1", "1.0", Ordering::Less),
+        ("1.0~rc1", "1.0~rc2", Ordering::Less),
+        ("1.0~~", "1.0~", Ordering::Less),
+        ("1.0~", "1.0", Ordering::Less),
+        ("1.0", "1.0+b1", Ordering::Less),
+        ("1.0", "1.0a", Ordering::Less),
+        ("1.0a", "1.0.1", Ordering::Less),
+        ("1.0A", "1.0a", Ordering::Less),
+        ("1.0+dfsg", "1.0.1", Ordering::Less),
+        ("1.0+", "1.0.", Ordering::Less),
+        ("a", "b", Ordering::Less),
+        ("1.0-1", "1.0-2", Ordering::Less),
+        ("1.0-9", "1.0-10", Ordering::Less),
+        ("1.0-1", "1.0-1ubuntu1", Ordering::Less),
+        ("1.0-1~bpo1", "1.0-1", Ordering::Less),
+        ("2.30-1", "2.3-1", Ordering::Greater),
+        ("1:0.1", "2.0", Ordering::Greater),
+        ("1:1.0", "2:0.1", Ordering::Less),
+    ];
+
+    #[test]
+    fn compares_like_dpkg() {
+        for &(a, b, expected) in VECTORS {
+            assert_eq!(compare_versions(a, b), expected, "{} vs {}", a, b);
+            assert_eq!(compare_versions(b, a), expected.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn splits_epoch_upstream_and_revision() {
+        let version = DebianVersion::parse("2:1.0-rc1-3ubuntu1").unwrap();
+
+        assert_eq!(version.epoch, 2);
+        assert_eq!(version.upstream, "1.0-rc1");
+        assert_eq!(version.revision, "3ubuntu1");
+        assert_eq!(version.to_string(), "2:1.0-rc1-3ubuntu1");
+        assert_eq!(DebianVersion::parse("0:1.0").unwrap().to_string(), "1.0");
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        for version in ["", "1.0 beta", "x:1.0", ":1.0", "-1", "1.0_1", "1.0-1:2"] {
+            assert!(DebianVersion::parse(version).is_err(), "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn parses_requirements() {
+        let parse = |requirement: &str| VersionOperator::parse_requirement(requirement).unwrap();
+
+        assert_eq!(parse(">= 2.36"), (VersionOperator::LaterOrEqual, "2.36".to_string()));
+        assert_eq!(parse("(<<3)"), (VersionOperator::Earlier, "3".to_string()));
+        assert_eq!(parse("1.0"), (VersionOperator::Equal, "1.0".to_string()));
+        assert_eq!(parse("< 1"), (VersionOperator::EarlierOrEqual, "1".to_string()));
+        assert_eq!(parse("> 1"), (VersionOperator::LaterOrEqual, "1".to_string()));
+        assert!(VersionOperator::parse_requirement(">=").is_err());
+    }
+
+    #[test]
+    fn matches_orderings() {
+        use Ordering::*;
+
+        assert!(VersionOperator::Earlier.matches(Less) && !VersionOperator::Earlier.matches(Equal));
+        assert!(VersionOperator::EarlierOrEqual.matches(Equal) && !VersionOperator::EarlierOrEqual.matches(Greater));
+        assert!(VersionOperator::Equal.matches(Equal) && !VersionOperator::Equal.matches(Less));
+        assert!(VersionOperator::LaterOrEqual.matches(Equal) && !VersionOperator::LaterOrEqual.matches(Less));
+        assert!(VersionOperator::Later.matches(Greater) && !VersionOperator::Later.matches(Equal));
+    }
+}
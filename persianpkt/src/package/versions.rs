@@ -0,0 +1,438 @@
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::package::{compare_versions, Package, PackageArchive};
+use crate::utils::with_suffix;
+
+/// Directories below `/usr`, `/usr/local` or `/` whose files get a link in
+/// the shared prefix when their version is active.
+const LINKED_DIRS: &[&str] = &["bin", "sbin", "lib", "lib64", "share/man"];
+
+/// One version of a package installed side by side with others, unpacked
+/// below `packages_dir/<name>/<version>/files` instead of into the root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledVersion {
+    pub version: String,
+    pub files: Vec<PathBuf>,
+    #[serde(default)]
+    pub origin: String,
+    pub install_date: SystemTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedPackage {
+    pub name: String,
+    /// The version linked into the shared prefix
+    pub active: Option<String>,
+    pub versions: BTreeMap<String, InstalledVersion>,
+}
+
+/// Packages installed side by side, kept in `versions.json` next to the
+/// installed package database.
+///
+/// The files of the active version are linked into the prefix through
+/// `packages_dir/<name>/current`, a symlink naming the active version, so
+/// switching versions flips every link with a single rename.
+pub struct VersionStore {
+    path: PathBuf,
+    packages_dir: PathBuf,
+    prefix_dir: PathBuf,
+    packages: BTreeMap<String, VersionedPackage>,
+}
+
+impl VersionStore {
+    pub const FILE_NAME: &'static str = "versions.json";
+
+    pub fn open(packages_dir: &Path, prefix_dir: &Path) -> Result<Self> {
+        let path = packages_dir.join(Self::FILE_NAME);
+
+        let packages = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<VersionedPackage> = serde_json::from_str(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e))?;
+            list.into_iter().map(|p| (p.name.clone(), p)).collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self {
+            path,
+            packages_dir: packages_dir.to_path_buf(),
+            prefix_dir: prefix_dir.to_path_buf(),
+            packages,
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let list: Vec<&VersionedPackage> = self.packages.values().collect();
+        let content = serde_json::to_string_pretty(&list)?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| anyhow!("Failed to save side-by-side versions: {}", e))?;
+
+        Ok(())
+    }
+
+    pub fn prefix_dir(&self) -> &Path {
+        &self.prefix_dir
+    }
+
+    pub fn get(&self, package_name: &str) -> Option<&VersionedPackage> {
+        self.packages.get(package_name)
+    }
+
+    pub fn packages(&self) -> impl Iterator<Item = &VersionedPackage> {
+        self.packages.values()
+    }
+
+    fn version_dir(&self, package_name: &str, version: &str) -> PathBuf {
+        self.packages_dir.join(package_name).join(version)
+    }
+
+    fn current_link(&self, package_name: &str) -> PathBuf {
+        self.packages_dir.join(package_name).join("current")
+    }
+
+    /// Unpacks a package next to its other versions. The first version of a
+    /// package becomes the active one.
+    pub fn install(&mut self, archive: &PackageArchive, package: &Package) -> Result<()> {
+        if self.packages.get(&package.name).map(|p| p.versions.contains_key(&package.version)).unwrap_or(false) {
+            return Err(anyhow!("{} {} is already installed side by side", package.name, package.version));
+        }
+
+        let files_dir = self.version_dir(&package.name, &package.version).join("files");
+        if files_dir.exists() {
+            fs::remove_dir_all(&files_dir)?;
+        }
+        fs::create_dir_all(&files_dir)?;
+
+        println!("Unpacking {} ({}) side by side...", package.name, package.version);
+        let files = archive.unpack_data(&files_dir, |path| Some(path.to_path_buf()))?;
+
+        let entry = self.packages.entry(package.name.clone()).or_insert_with(|| VersionedPackage {
+            name: package.name.clone(),
+            active: None,
+            versions: BTreeMap::new(),
+        });
+        entry.versions.insert(package.version.clone(), InstalledVersion {
+            version: package.version.clone(),
+            files,
+            origin: package.origin.clone(),
+            install_date: SystemTime::now(),
+        });
+
+        if entry.active.is_none() {
+            self.switch(&package.name, &package.version)?;
+        }
+
+        self.save()
+    }
+
+    /// Makes `version` the active version of a package and updates the links
+    /// in the prefix to match its files.
+    pub fn switch(&mut self, package_name: &str, version: &str) -> Result<()> {
+        let package = self.packages.get(package_name)
+            .ok_or_else(|| anyhow!("Package '{}' has no side-by-side versions", package_name))?;
+        let target = package.versions.get(version)
+            .ok_or_else(|| anyhow!("Version {} of {} is not installed side by side, installed versions: {}",
+                version, package_name, package.versions.keys().cloned().collect::<Vec<_>>().join(", ")))?;
+
+        let old_links = match package.active.as_ref().and_then(|v| package.versions.get(v)) {
+            Some(active) => prefix_links(&active.files),
+            None => BTreeMap::new(),
+        };
+        let new_links = prefix_links(&target.files);
+
+        // The rename is atomic, so every existing link moves to the new version at once
+        let current = self.current_link(package_name);
+        let tmp_link = with_suffix(&current, ".tmp");
+        let _ = fs::remove_file(&tmp_link);
+        symlink(version, &tmp_link)
+            .map_err(|e| anyhow!("Failed to create {}: {}", tmp_link.display(), e))?;
+        fs::rename(&tmp_link, &current)
+            .map_err(|e| anyhow!("Failed to switch {} to {}: {}", package_name, version, e))?;
+
+        for link in old_links.keys().filter(|link| !new_links.contains_key(*link)) {
+            self.remove_link(package_name, link)?;
+        }
+        for (link, file) in &new_links {
+            self.create_link(package_name, link, file)?;
+        }
+
+        if let Some(package) = self.packages.get_mut(package_name) {
+            package.active = Some(version.to_string());
+        }
+
+        self.save()
+    }
+
+    /// Removes a side-by-side version. When it is the active one, the newest
+    /// remaining version becomes active.
+    pub fn remove(&mut self, package_name: &str, version: &str) -> Result<()> {
+        let package = self.packages.get(package_name)
+            .ok_or_else(|| anyhow!("Package '{}' has no side-by-side versions", package_name))?;
+        if !package.versions.contains_key(version) {
+            return Err(anyhow!("Version {} of {} is not installed side by side", version, package_name));
+        }
+
+        if package.active.as_deref() == Some(version) {
+            let next = package.versions.keys()
+                .filter(|v| v.as_str() != version)
+                .max_by(|a, b| compare_versions(a, b))
+                .cloned();
+
+            match next {
+                Some(next) => {
+                    log::info!("Switching {} to {}", package_name, next);
+                    self.switch(package_name, &next)?;
+                }
+                None => {
+                    for link in prefix_links(&package.versions[version].files).keys() {
+                        self.remove_link(package_name, link)?;
+                    }
+                    let _ = fs::remove_file(self.current_link(package_name));
+                }
+            }
+        }
+
+        let version_dir = self.version_dir(package_name, version);
+        if version_dir.exists() {
+            fs::remove_dir_all(&version_dir)?;
+        }
+
+        if let Some(package) = self.packages.get_mut(package_name) {
+            package.versions.remove(version);
+            if package.versions.is_empty() {
+                self.packages.remove(package_name);
+                let _ = fs::remove_dir(self.packages_dir.join(package_name));
+            }
+        }
+
+        self.save()
+    }
+
+    /// Where a link in the prefix points, going through the `current` link.
+    fn link_target(&self, package_name: &str, link: &Path, file: &Path) -> PathBuf {
+        let target = self.current_link(package_name)
+            .join("files")
+            .join(file.strip_prefix("/").unwrap_or(file));
+        let link_dir = self.prefix_dir.join(link).parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        relative_path(&target, &link_dir)
+    }
+
+    fn create_link(&self, package_name: &str, link: &Path, file: &Path) -> Result<()> {
+        let path = self.prefix_dir.join(link);
+        let target = self.link_target(package_name, link, file);
+
+        match fs::read_link(&path) {
+            Ok(existing) if existing == target => return Ok(()),
+            Ok(existing) => {
+                log::warn!("Not linking {}, it already points to {}", path.display(), existing.display());
+                return Ok(());
+            }
+            Err(_) if fs::symlink_metadata(&path).is_ok() => {
+                log::warn!("Not linking {}, a file is in the way", path.display());
+                return Ok(());
+            }
+            Err(_) => {}
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        symlink(&target, &path)
+            .map_err(|e| anyhow!("Failed to link {}: {}", path.display(), e))
+    }
+
+    /// Removes a link of the package, leaving links of other packages alone.
+    fn remove_link(&self, package_name: &str, link: &Path) -> Result<()> {
+        let path = self.prefix_dir.join(link);
+        let ours = fs::read_link(&path)
+            .map(|target| target.starts_with(relative_path(&self.packages_dir.join(package_name), path.parent().unwrap_or(&path))))
+            .unwrap_or(false);
+
+        if ours {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps each file that gets a link in the prefix to the path of that link
+/// relative to the prefix, e.g. `/usr/bin/gcc` to `bin/gcc`.
+fn prefix_links(files: &[PathBuf]) -> BTreeMap<PathBuf, PathBuf> {
+    let mut links = BTreeMap::new();
+
+    for file in files {
+        let relative = file.strip_prefix("/").unwrap_or(file);
+        let relative = relative.strip_prefix("usr/local")
+            .or_else(|_| relative.strip_prefix("usr"))
+            .unwrap_or(relative);
+
+        if LINKED_DIRS.iter().any(|dir| relative.starts_with(dir)) {
+            links.insert(relative.to_path_buf(), file.clone());
+        }
+    }
+
+    links
+}
+
+/// Returns `target` relative to the directory `from`, so links keep working
+/// when the tree is seen from a chroot.
+fn relative_path(target: &Path, from: &Path) -> PathBuf {
+    let target: Vec<Component> = target.components().collect();
+    let from: Vec<Component> = from.components().collect();
+    let common = target.iter().zip(&from).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &target[common..] {
+        path.push(component);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::tests::build_deb;
+    use crate::package::PackageInfo;
+
+    struct Fixture {
+        dir: tempfile::TempDir,
+        store: VersionStore,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let store = VersionStore::open(&dir.path().join("packages"), &dir.path().join("prefix")).unwrap();
+            Self { dir, store }
+        }
+
+        fn install(&mut self, version: &str, files: &[&str]) -> Result<()> {
+            let deb = self.dir.path().join(format!("gcc_{}.deb", version));
+            let mut data = vec![("/usr", None, 0o755), ("/usr/bin", None, 0o755), ("/usr/share", None, 0o755)];
+            data.extend(files.iter().map(|file| (*file, Some(version), 0o755)));
+            build_deb(&deb, &[("control", &format!("Package: gcc\nVersion: {}\nArchitecture: all\nDescription: gcc\n", version))], &data);
+
+            let archive = PackageArchive::open(&deb)?;
+            let info = PackageInfo::new("gcc".to_string(), version.to_string(), "all".to_string(), String::new());
+            self.store.install(&archive, &Package::new(info, PathBuf::new()))
+        }
+
+        fn prefix(&self, link: &str) -> PathBuf {
+            self.dir.path().join("prefix").join(link)
+        }
+
+        fn read(&self, link: &str) -> Option<String> {
+            fs::read_to_string(self.prefix(link)).ok()
+        }
+    }
+
+    #[test]
+    fn first_version_becomes_active() {
+        let mut fixture = Fixture::new();
+        fixture.install("12", &["/usr/bin/gcc", "/usr/share/doc"]).unwrap();
+        fixture.install("13", &["/usr/bin/gcc"]).unwrap();
+
+        assert_eq!(fixture.store.get("gcc").unwrap().active.as_deref(), Some("12"));
+        assert_eq!(fixture.read("bin/gcc").as_deref(), Some("12"));
+        assert!(fs::symlink_metadata(fixture.prefix("share/doc")).is_err());
+        assert!(fs::read_link(fixture.prefix("bin/gcc")).unwrap().is_relative());
+        assert!(fixture.install("13", &["/usr/bin/gcc"]).is_err());
+    }
+
+    #[test]
+    fn switch_moves_links_to_the_new_version() {
+        let mut fixture = Fixture::new();
+        fixture.install("12", &["/usr/bin/gcc", "/usr/bin/gcc-12"]).unwrap();
+        fixture.install("13", &["/usr/bin/gcc", "/usr/bin/gcc-13"]).unwrap();
+
+        fixture.store.switch("gcc", "13").unwrap();
+        assert_eq!(fixture.read("bin/gcc").as_deref(), Some("13"));
+        assert_eq!(fixture.read("bin/gcc-13").as_deref(), Some("13"));
+        assert!(fs::symlink_metadata(fixture.prefix("bin/gcc-12")).is_err());
+
+        assert!(fixture.store.switch("gcc", "14").is_err());
+        assert!(fixture.store.switch("clang", "13").is_err());
+
+        let reopened = VersionStore::open(&fixture.dir.path().join("packages"), &fixture.prefix("")).unwrap();
+        assert_eq!(reopened.get("gcc").unwrap().active.as_deref(), Some("13"));
+    }
+
+    #[test]
+    fn leaves_foreign_files_in_the_prefix() {
+        let mut fixture = Fixture::new();
+        fs::create_dir_all(fixture.prefix("bin")).unwrap();
+        fs::write(fixture.prefix("bin/gcc"), "local").unwrap();
+        symlink("/usr/bin/cc", fixture.prefix("bin/cc")).unwrap();
+
+        fixture.install("12", &["/usr/bin/gcc", "/usr/bin/cc"]).unwrap();
+        fixture.store.remove("gcc", "12").unwrap();
+
+        assert_eq!(fixture.read("bin/gcc").as_deref(), Some("local"));
+        assert_eq!(fs::read_link(fixture.prefix("bin/cc")).unwrap(), Path::new("/usr/bin/cc"));
+    }
+
+    #[test]
+    fn removing_the_active_version_activates_the_newest() {
+        let mut fixture = Fixture::new();
+        fixture.install("9", &["/usr/bin/gcc"]).unwrap();
+        fixture.install("12", &["/usr/bin/gcc"]).unwrap();
+        fixture.install("10", &["/usr/bin/gcc"]).unwrap();
+
+        fixture.store.remove("gcc", "9").unwrap();
+        assert_eq!(fixture.store.get("gcc").unwrap().active.as_deref(), Some("12"));
+        assert_eq!(fixture.read("bin/gcc").as_deref(), Some("12"));
+        assert!(!fixture.dir.path().join("packages/gcc/9").exists());
+
+        fixture.store.remove("gcc", "10").unwrap();
+        assert_eq!(fixture.read("bin/gcc").as_deref(), Some("12"));
+
+        fixture.store.remove("gcc", "12").unwrap();
+        assert!(fixture.store.get("gcc").is_none());
+        assert!(fs::symlink_metadata(fixture.prefix("bin/gcc")).is_err());
+        assert!(!fixture.dir.path().join("packages/gcc").exists());
+        assert!(fixture.store.remove("gcc", "12").is_err());
+    }
+
+    #[test]
+    fn links_files_in_linked_dirs() {
+        let files: Vec<PathBuf> = ["/usr/bin/gcc", "/usr/local/lib/libgcc.so", "/sbin/tool", "/usr/share/man/man1/gcc.1", "/usr/share/doc/gcc", "/etc/gcc"]
+            .iter().map(PathBuf::from).collect();
+        let links = prefix_links(&files);
+
+        assert_eq!(links.keys().collect::<Vec<_>>(), [
+            Path::new("bin/gcc"),
+            Path::new("lib/libgcc.so"),
+            Path::new("sbin/tool"),
+            Path::new("share/man/man1/gcc.1"),
+        ]);
+        assert_eq!(links[Path::new("lib/libgcc.so")], Path::new("/usr/local/lib/libgcc.so"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path(Path::new("/a/b/c"), Path::new("/a/d")), Path::new("../b/c"));
+        assert_eq!(relative_path(Path::new("/a/b"), Path::new("/a")), Path::new("b"));
+        assert_eq!(relative_path(Path::new("/a"), Path::new("/a/b/c")), Path::new("../.."));
+    }
+}
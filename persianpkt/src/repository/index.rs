@@ -0,0 +1,110 @@
+use anyhow::{Result, anyhow};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use url::Url;
+
+use crate::package::{compare_versions, Package, PackageDependency, PackageInfo};
+use crate::repository::{Repository, RepositorySource};
+
+/// A package available from a repository.
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub repository: String,
+    pub priority: i32,
+    pub base_url: Url,
+    pub info: PackageInfo,
+}
+
+impl IndexEntry {
+    pub fn download_url(&self) -> Result<Url> {
+        self.base_url.join(&self.info.filename)
+            .map_err(|e| anyhow!("Invalid file name {} for {}: {}", self.info.filename, self.info.name, e))
+    }
+
+    pub fn to_package(&self) -> Package {
+        let mut package = Package::new(self.info.clone(), Default::default());
+        package.origin = self.repository.clone();
+        package
+    }
+}
+
+/// The packages of every enabled repository, as stored by `pkt update`.
+#[derive(Debug, Default)]
+pub struct PackageIndex {
+    entries: HashMap<String, Vec<IndexEntry>>,
+    /// Entries by the virtual packages they provide
+    providers: HashMap<String, Vec<IndexEntry>>,
+}
+
+impl PackageIndex {
+    /// Loads the stored package lists of the enabled repositories. Lists
+    /// that have not been downloaded yet are skipped.
+    pub fn load(lists_dir: &Path, repositories: &[Repository]) -> Result<Self> {
+        let mut index = Self::default();
+
+        for repo in repositories.iter().filter(|r| r.enabled) {
+            for component in &repo.components {
+                for arch in &repo.architectures {
+                    let list_path = lists_dir.join(repo.list_file_name(component, arch));
+                    if !list_path.exists() {
+                        continue;
+                    }
+
+                    let content = fs::read_to_string(&list_path)
+                        .map_err(|e| anyhow!("Failed to read {}: {}", list_path.display(), e))?;
+
+                    for info in RepositorySource::parse_packages_file(&content)? {
+                        index.insert(IndexEntry {
+                            repository: repo.name.clone(),
+                            priority: repo.priority,
+                            base_url: repo.url.clone(),
+                            info,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(index)
+    }
+
+    pub fn insert(&mut self, entry: IndexEntry) {
+        for provided in entry.info.provides.iter().filter_map(|p| PackageDependency::parse(p).ok()) {
+            if provided.name != entry.info.name {
+                self.providers.entry(provided.name).or_default().push(entry.clone());
+            }
+        }
+        self.entries.entry(entry.info.name.clone()).or_default().push(entry);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns every available version of a package.
+    pub fn candidates(&self, package_name: &str) -> &[IndexEntry] {
+        self.entries.get(package_name).map(|e| e.as_slice()).unwrap_or(&[])
+    }
+
+    /// Returns every available package version that provides `virtual_name`.
+    pub fn providers(&self, virtual_name: &str) -> &[IndexEntry] {
+        self.providers.get(virtual_name).map(|e| e.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn find(&self, package_name: &str, version: &str) -> Option<&IndexEntry> {
+        self.candidates(package_name).iter().find(|e| e.info.version == version)
+    }
+
+    /// Returns the version that would be installed: the newest version from
+    /// the repositories with the highest priority.
+    pub fn best_candidate(&self, package_name: &str) -> Option<&IndexEntry> {
+        self.candidates(package_name).iter().max_by(|a, b| compare_entries(a, b))
+    }
+}
+
+fn compare_entries(a: &IndexEntry, b: &IndexEntry) -> Ordering {
+    a.priority.cmp(&b.priority)
+        .then_with(|| compare_versions(&a.info.version, &b.info.version))
+}
@@ -3,5 +3,7 @@ pub mod repository;
 pub use repository::{Repository, RepositoryManager};
 
 mod source;
+mod index;
  
-pub use source::*; 
\ No newline at end of file
+pub use source::*;
+pub use index::*; 
\ No newline at end of file
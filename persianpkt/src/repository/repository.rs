@@ -1,7 +1,7 @@
 use anyhow::{Result, anyhow};
 use serde::{Serialize, Deserialize};
 use url::Url;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs;
 use reqwest::Client;
 
@@ -85,7 +85,15 @@ impl Repository {
             .map_err(|e| anyhow!("Failed to create release URL: {}", e))
     }
 
-    pub async fn update(&self) -> Result<()> {
+    /// File name under which the package list of a component and
+    /// architecture is stored.
+    pub fn list_file_name(&self, component: &str, arch: &str) -> String {
+        format!("{}_{}_{}_{}_Packages", self.name, self.distribution, component, arch)
+    }
+    
+    /// Downloads the package lists of every component and architecture into
+    /// `lists_dir`. A list that cannot be fetched keeps its previous copy.
+    pub async fn update(&self, lists_dir: &Path) -> Result<()> {
         let client = Client::new();
         
         println!("Fetching repository information from {}", self.url);
@@ -121,6 +129,15 @@ impl Repository {
                     continue;
                 }
                 
+                let content = response.text()
+                    .await
+                    .map_err(|e| anyhow!("Failed to read package list: {}", e))?;
+                    
+                let list_path = lists_dir.join(self.list_file_name(component, arch));
+                let tmp_path = lists_dir.join(format!("{}.tmp", self.list_file_name(component, arch)));
+                fs::write(&tmp_path, content)?;
+                fs::rename(&tmp_path, &list_path)?;
+                
                 println!("Package list for {}/{} updated", component, arch);
             }
         }
@@ -152,11 +169,21 @@ impl Repository {
                 architecture: "x86_64".to_string(),
                 description: "A test package for development".to_string(),
                 dependencies: Vec::new(),
+                pre_depends: Vec::new(),
+                recommends: Vec::new(),
+                suggests: Vec::new(),
                 conflicts: Vec::new(),
+                breaks: Vec::new(),
                 provides: Vec::new(),
                 replaces: Vec::new(),
                 install_path: std::path::PathBuf::new(),
                 files: Vec::new(),
+                conffiles: Vec::new(),
+                state: crate::package::PackageState::Installed,
+                auto_installed: false,
+                origin: self.name.clone(),
+                sha256: String::new(),
+                trigger_interests: Vec::new(),
                 install_date: std::time::SystemTime::now(),
                 size: 1024,
                 installed_size: 2048,
@@ -28,7 +28,7 @@ impl RepositorySource {
         let response = self.client.get(url).send()?;
         let content = response.text()?;
         
-        let packages = self.parse_packages_file(&content)?;
+        let packages = Self::parse_packages_file(&content)?;
         Ok(packages)
     }
 
@@ -47,14 +47,15 @@ impl RepositorySource {
         Ok(content)
     }
 
-    fn parse_packages_file(&self, content: &str) -> Result<Vec<PackageInfo>> {
+    /// Parses a repository `Packages` file into its package stanzas.
+    pub fn parse_packages_file(content: &str) -> Result<Vec<PackageInfo>> {
         let mut packages = Vec::new();
         let mut current_package = HashMap::new();
         
         for line in content.lines() {
             if line.is_empty() {
                 if !current_package.is_empty() {
-                    if let Some(package_info) = self.create_package_info(&current_package) {
+                    if let Some(package_info) = Self::create_package_info(&current_package) {
                         packages.push(package_info);
                     }
                     current_package = HashMap::new();
@@ -74,7 +75,7 @@ impl RepositorySource {
         }
         
         if !current_package.is_empty() {
-            if let Some(package_info) = self.create_package_info(&current_package) {
+            if let Some(package_info) = Self::create_package_info(&current_package) {
                 packages.push(package_info);
             }
         }
@@ -82,7 +83,7 @@ impl RepositorySource {
         Ok(packages)
     }
 
-    fn create_package_info(&self, fields: &HashMap<String, String>) -> Option<PackageInfo> {
+    fn create_package_info(fields: &HashMap<String, String>) -> Option<PackageInfo> {
         let name = fields.get("Package")?.to_string();
         let version = fields.get("Version")?.to_string();
         let architecture = fields.get("Architecture")?.to_string();
@@ -94,6 +95,13 @@ impl RepositorySource {
             architecture,
             description,
             dependencies: Vec::new(),
+            pre_depends: Vec::new(),
+            recommends: Vec::new(),
+            suggests: Vec::new(),
+            conflicts: Vec::new(),
+            breaks: Vec::new(),
+            provides: Vec::new(),
+            replaces: Vec::new(),
             size: 0,
             installed_size: 0,
             maintainer: String::new(),
@@ -152,6 +160,20 @@ impl RepositorySource {
             info.dependencies = deps;
         }
         
+        for (field, list) in [
+            ("Pre-Depends", &mut info.pre_depends),
+            ("Recommends", &mut info.recommends),
+            ("Suggests", &mut info.suggests),
+            ("Conflicts", &mut info.conflicts),
+            ("Breaks", &mut info.breaks),
+            ("Provides", &mut info.provides),
+            ("Replaces", &mut info.replaces),
+        ] {
+            if let Some(value) = fields.get(field) {
+                *list = value.split(',').map(|s| s.trim().to_string()).collect();
+            }
+        }
+        
         Some(info)
     }
 
@@ -107,4 +107,51 @@ pub fn find_files_by_name(dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
     }
 
     Ok(files)
-} 
\ No newline at end of file
+} 
+/// Appends `suffix` to the file name of `path`, e.g. `foo.conf` to `foo.conf.pkt-new`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Moves a file, symlink or directory, falling back to copy and delete when
+/// `src` and `dst` are on different filesystems.
+pub fn move_path(src: &Path, dst: &Path) -> Result<()> {
+    if fs::rename(src, dst).is_ok() {
+        return Ok(());
+    }
+
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.file_type().is_symlink() {
+        if fs::symlink_metadata(dst).is_ok() {
+            fs::remove_file(dst)?;
+        }
+        std::os::unix::fs::symlink(fs::read_link(src)?, dst)?;
+        fs::remove_file(src)?;
+    } else if metadata.is_dir() {
+        copy_dir_contents(src, dst)?;
+        fs::remove_dir_all(src)?;
+    } else {
+        // Copy next to the destination first so the final rename is atomic
+        let tmp = with_suffix(dst, ".pkt-tmp");
+        fs::copy(src, &tmp)?;
+        fs::rename(&tmp, dst)?;
+        fs::remove_file(src)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the parent directories of `path` that are left empty, stopping
+/// at `stop_at` or the first directory that still has entries.
+pub fn remove_empty_parents(path: &Path, stop_at: &Path) {
+    let mut current = path.parent();
+
+    while let Some(dir) = current {
+        if dir == stop_at || !dir.starts_with(stop_at) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        current = dir.parent();
+    }
+}
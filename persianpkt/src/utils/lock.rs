@@ -0,0 +1,226 @@
+use anyhow::{Result, anyhow};
+use fs2::FileExt;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::utils::with_suffix;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Set for maintainer scripts, telling pkt commands they run from inside a
+/// locked operation.
+pub const LOCKED_ENV: &str = "PERSIANPKT_LOCKED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Held by read-only commands, any number may run at once
+    Shared,
+    /// Held by commands that change state, excludes every other command
+    Exclusive,
+}
+
+impl fmt::Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Shared => write!(f, "shared"),
+            Self::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+/// A process holding the lock, as recorded in its holder file.
+#[derive(Debug, Clone)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub mode: String,
+    pub command: String,
+}
+
+/// An advisory lock serializing pkt runs that share a data directory.
+///
+/// The lock itself is an `flock` on the lock file, so the kernel releases it
+/// when the holder exits. Each holder also leaves a record in `<lock>.holders`
+/// naming its PID and command line, which is used to tell waiting processes
+/// who they are waiting for. Records of processes that are no longer running
+/// are stale and get removed.
+pub struct OperationLock {
+    file: File,
+    record: PathBuf,
+}
+
+impl OperationLock {
+    /// Takes the lock, waiting up to `timeout` for other holders to finish.
+    pub fn acquire(path: &Path, mode: LockMode, timeout: Duration) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow!("Failed to open lock file {}: {}", path.display(), e))?;
+
+        let started = Instant::now();
+        let mut announced = false;
+
+        loop {
+            let result = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+
+            match result {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {}
+                Err(e) => return Err(anyhow!("Failed to lock {}: {}", path.display(), e)),
+            }
+
+            let holders = live_holders(path);
+
+            if started.elapsed() >= timeout {
+                return Err(anyhow!("Could not get {} lock on {}, it is held by {}",
+                    mode, path.display(), describe_holders(&holders)));
+            }
+
+            if !announced {
+                println!("Waiting for {} to release the lock (timeout {}s)...",
+                    describe_holders(&holders), timeout.as_secs());
+                announced = true;
+            }
+
+            thread::sleep(RETRY_INTERVAL);
+        }
+
+        // Drop records left behind by holders that exited without cleaning up
+        live_holders(path);
+
+        let record = holders_dir(path).join(std::process::id().to_string());
+        fs::create_dir_all(holders_dir(path))?;
+        fs::write(&record, format!("{}\n{}\n", mode, current_command()))?;
+
+        Ok(Self { file, record })
+    }
+}
+
+impl Drop for OperationLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.record);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+fn holders_dir(path: &Path) -> PathBuf {
+    with_suffix(path, ".holders")
+}
+
+/// Reads the holder records next to the lock, dropping those whose process
+/// is no longer running.
+fn live_holders(path: &Path) -> Vec<LockHolder> {
+    let mut holders = Vec::new();
+    let entries = match fs::read_dir(holders_dir(path)) {
+        Ok(entries) => entries,
+        Err(_) => return holders,
+    };
+
+    for entry in entries.flatten() {
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+
+        if !process_exists(pid) {
+            log::info!("Removing stale lock record of process {}", pid);
+            let _ = fs::remove_file(entry.path());
+            continue;
+        }
+
+        let content = fs::read_to_string(entry.path()).unwrap_or_default();
+        let mut lines = content.lines();
+        holders.push(LockHolder {
+            pid,
+            mode: lines.next().unwrap_or("unknown").to_string(),
+            command: lines.next().unwrap_or("unknown").to_string(),
+        });
+    }
+
+    holders
+}
+
+fn describe_holders(holders: &[LockHolder]) -> String {
+    if holders.is_empty() {
+        return "another process".to_string();
+    }
+
+    let names: Vec<String> = holders.iter()
+        .map(|h| format!("process {} ({}, {} lock)", h.pid, h.command, h.mode))
+        .collect();
+
+    names.join(", ")
+}
+
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+fn current_command() -> String {
+    std::env::args().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_the_lock_between_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock");
+
+        let first = OperationLock::acquire(&path, LockMode::Shared, Duration::ZERO).unwrap();
+        let second = OperationLock::acquire(&path, LockMode::Shared, Duration::ZERO).unwrap();
+        assert!(OperationLock::acquire(&path, LockMode::Exclusive, Duration::ZERO).is_err());
+
+        drop((first, second));
+        assert!(OperationLock::acquire(&path, LockMode::Exclusive, Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn names_the_holders_when_timing_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock");
+
+        let held = OperationLock::acquire(&path, LockMode::Exclusive, Duration::ZERO).unwrap();
+        let started = Instant::now();
+        let error = OperationLock::acquire(&path, LockMode::Shared, RETRY_INTERVAL).err().unwrap().to_string();
+
+        assert!(started.elapsed() >= RETRY_INTERVAL);
+        assert!(error.starts_with("Could not get shared lock"), "{}", error);
+        assert!(error.contains(&format!("process {} (", std::process::id())), "{}", error);
+        assert!(error.ends_with("exclusive lock)"), "{}", error);
+
+        drop(held);
+        assert!(!holders_dir(&path).join(std::process::id().to_string()).exists());
+    }
+
+    #[test]
+    fn drops_records_of_exited_holders() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lock");
+        let stale = holders_dir(&path).join(u32::MAX.to_string());
+        fs::create_dir_all(holders_dir(&path)).unwrap();
+        fs::write(&stale, "exclusive\npkt install vim\n").unwrap();
+        fs::write(holders_dir(&path).join("notes"), "").unwrap();
+
+        let _lock = OperationLock::acquire(&path, LockMode::Exclusive, Duration::ZERO).unwrap();
+
+        assert!(!stale.exists());
+        let holders = live_holders(&path);
+        assert_eq!(holders.len(), 1);
+        assert_eq!((holders[0].pid, holders[0].mode.as_str()), (std::process::id(), "exclusive"));
+    }
+}
@@ -1,7 +1,9 @@
 mod fs;
 mod compression;
 mod progress;
+mod lock;
  
 pub use fs::*;
 pub use compression::*;
-pub use progress::*; 
\ No newline at end of file
+pub use progress::*;
+pub use lock::*; 
\ No newline at end of file